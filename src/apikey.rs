@@ -0,0 +1,51 @@
+use crate::codegen::api_keys::{API_KEY_BYTES, API_KEY_PREFIX, API_KEY_SCOPES, SHOWN_PREFIX_LEN};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+/// A fresh API key, in the same format as the ones the generated admin routes hand out.
+pub fn generate_api_key() -> Result<String, String> {
+    let mut bytes = [0u8; API_KEY_BYTES];
+    getrandom::getrandom(&mut bytes).map_err(|e| format!("Couldn't generate a key: {e}"))?;
+
+    Ok(format!("{API_KEY_PREFIX}{}", URL_SAFE_NO_PAD.encode(bytes)))
+}
+
+/// What's stored in place of the key. Keys are random enough that a fast hash is all they need.
+pub fn hash_api_key(key: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Prints a new key, and the statement that adds it to the `api_keys` table. The generated app
+/// can only create keys for callers that already have an admin key, so this is how the first one
+/// gets made.
+pub fn create_api_key(name: &str, scopes: &[String]) -> Result<(), String> {
+    if let Some(scope) = scopes
+        .iter()
+        .find(|x| !API_KEY_SCOPES.contains(&x.as_str()))
+    {
+        return Err(format!(
+            "Unknown scope: {scope}. Expected one of {}",
+            API_KEY_SCOPES.join(", ")
+        ));
+    }
+
+    let key = generate_api_key()?;
+
+    let scopes = scopes
+        .iter()
+        .map(|x| format!("'{x}'"))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    println!("Your API key, which won't be shown again:\n\n  {key}\n");
+    println!("Add it to the database with:\n");
+    println!(
+        "  INSERT INTO api_keys (name, prefix, key_hash, scopes) VALUES ('{}', '{}', '{}', ARRAY[{scopes}]);",
+        name.replace('\'', "''"),
+        &key[..SHOWN_PREFIX_LEN],
+        hash_api_key(&key),
+    );
+
+    Ok(())
+}
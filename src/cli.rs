@@ -1,13 +1,20 @@
-use crate::codegen::axum_auth::{auth_middleware, auth_routes};
-use crate::codegen::main_fn::{axum_crud_fns, main_function};
-use crate::codegen::migration_file::write_migration_file;
-use crate::commands::{
-    cargo_init, make_dir, write_file, write_main_file, write_mod_file, write_secrets_file,
-};
-use crate::dependencies::add_required_dependencies;
+use crate::apikey::create_api_key;
+use crate::codegen::migration_file::{GENERATED_COLUMNS, GENERATED_TABLES};
+use crate::codegen::secrets::{parse_secret, validate_secrets};
+use crate::codegen::tenants::{ORGANIZATIONS, TENANT_COLUMN};
+use crate::manifest::read_manifest;
+use crate::migrate::migrate_diff;
+use crate::project::create_project;
+use crate::schema::{creation_order, foreign_keys, join_tables, Field, Relation};
+use crate::secret::gen_key;
+use crate::wizard::run_wizard;
 use inquire::{Confirm, Text};
+use std::fmt;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use syn::Ident;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -18,41 +25,241 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    /// does testing things
+    /// Creates a new web service project
     Create {
         /// Creates API routes for each name inputted (comma separated)
         #[arg(short, long)]
         crud: Option<Vec<String>>,
-        /// Creates auth routes using database-backed cookie session auth
+        /// Declares a relationship between two CRUD resources, e.g. "comment belongs_to post".
+        /// Supports belongs_to, has_many and many_to_many, with an optional on_delete=<cascade|set_null|restrict|no_action>.
         #[arg(short, long)]
-        auth: bool,
+        relation: Vec<String>,
+        /// Adds auth: database-backed cookie sessions with login routes, or API keys for other services calling in. On its own, --auth means cookie.
+        #[arg(short, long, value_enum, num_args = 0..=1, default_missing_value = "cookie")]
+        auth: Option<AuthMode>,
+        /// Sends an email with a verification link on registration, and only lets verified users log in (only used alongside --auth).
+        #[arg(long)]
+        email_verification: bool,
+        /// Adds routes for requesting a password reset email and resetting a password with its token (only used alongside --auth).
+        #[arg(long)]
+        password_reset: bool,
+        /// Rate limits the auth routes by IP address and username, and locks accounts for a while after repeated failed logins (only used alongside --auth).
+        #[arg(long)]
+        rate_limit: bool,
+        /// Where rate limits are counted: in memory, or in Postgres so they're shared between instances (only used alongside --rate-limit).
+        #[arg(long, value_enum, default_value_t = RateLimitStore::Memory)]
+        rate_limit_store: RateLimitStore,
+        /// Requires a CSRF token on every protected route that changes data, from a new `/auth/csrf` route (only used alongside --auth).
+        #[arg(long)]
+        csrf: bool,
+        /// Adds checkout, customer portal and webhook routes for subscriptions through a payment provider (only used alongside --auth).
+        #[arg(long, value_enum)]
+        payments: Option<PaymentProvider>,
+        /// Gives every resource's rows an organization, adding organizations and memberships, and scopes every query to the caller's organization (only used alongside --auth).
+        #[arg(long)]
+        multi_tenant: bool,
+        /// How passwords are hashed (only used alongside --auth).
+        #[arg(long, value_enum, default_value_t = PasswordHash::Argon2id)]
+        password_hash: PasswordHash,
+        /// How much memory argon2id uses to hash a password, in KiB.
+        #[arg(long, default_value_t = HashParams::default().argon2_memory_kib)]
+        argon2_memory_kib: u32,
+        /// How many passes argon2id makes over its memory.
+        #[arg(long, default_value_t = HashParams::default().argon2_iterations)]
+        argon2_iterations: u32,
+        /// How many lanes argon2id hashes with.
+        #[arg(long, default_value_t = HashParams::default().argon2_parallelism)]
+        argon2_parallelism: u32,
+        /// bcrypt's cost factor, between 4 and 31. Each step doubles the time hashing takes.
+        #[arg(long, default_value_t = HashParams::default().bcrypt_cost)]
+        bcrypt_cost: u32,
         /// Adds a secrets file that you can use to hold secrets as well as the shuttle-secrets crate.
         #[arg(short, long)]
         secrets: bool,
+        /// Declares a secret that's read into `AppConfig` on startup, as NAME[:TYPE], with a trailing `?` if it's optional (implies --secrets).
+        /// TYPE is one of text, integer, bigint, float or boolean, and defaults to text.
+        #[arg(long)]
+        secret: Vec<String>,
         /// Adds the name of your project.
         #[arg(short, long)]
         name: Option<String>,
+        /// Protects your CRUD routes with the auth middleware, or with API keys (only used alongside --auth and --crud).
+        #[arg(short, long, num_args = 0..=1, default_missing_value = "true")]
+        protected: Option<bool>,
+        /// Never prompts for input. Missing inputs will cause an error instead. Implied when stdin is not a terminal.
+        #[arg(short, long, alias = "non-interactive")]
+        yes: bool,
+        /// Where the project will be deployed.
+        #[arg(short, long, value_enum, default_value_t = Deployment::Shuttle)]
+        deploy: Deployment,
+        /// Embeds the migrations in the binary and runs them on startup.
+        #[arg(long)]
+        embed_migrations: bool,
+        /// Uses sqlx's compile-time checked query macros, along with offline query metadata in .sqlx.
+        #[arg(long)]
+        checked_queries: bool,
+        /// How the generated handlers talk to the database.
+        #[arg(long, value_enum, default_value_t = Orm::Sqlx)]
+        orm: Orm,
+        /// Puts each resource's queries behind a repository trait, with Postgres and in-memory implementations.
+        #[arg(long)]
+        repositories: bool,
+        /// Whether to generate a single crate or a workspace of crates (implies --repositories).
+        #[arg(long, value_enum, default_value_t = Layout::Single)]
+        layout: Layout,
+        /// Adds structured JSON logging with tracing, a request trace layer and request ids.
+        #[arg(long)]
+        tracing: bool,
+        /// Adds a Prometheus `/metrics` endpoint with per-route request metrics.
+        #[arg(long)]
+        metrics: bool,
+        /// Adds liveness and readiness endpoints in place of the hello world route.
+        #[arg(long)]
+        health: bool,
+        /// Where the liveness endpoint is served (only used alongside --health).
+        #[arg(long, default_value = DEFAULT_HEALTH_PATH)]
+        health_path: String,
+        /// Where the readiness endpoint is served (only used alongside --health).
+        #[arg(long, default_value = DEFAULT_READY_PATH)]
+        ready_path: String,
+    },
+    /// Creates a project from a manifest saved by `inlet new`
+    Generate {
+        /// The manifest to read.
+        #[arg(default_value = "inlet.toml")]
+        manifest: PathBuf,
+        /// Overrides the name of your project.
+        #[arg(short, long)]
+        name: Option<String>,
+    },
+    /// Walks you through every option before creating a new project
+    New,
+    /// Manages the migrations of a project created by Inlet
+    Migrate {
+        #[command(subcommand)]
+        cmds: MigrateCommands,
+    },
+    /// Manages the secrets of a project created by Inlet
+    Secret {
+        #[command(subcommand)]
+        cmds: SecretCommands,
+    },
+    /// Manages the API keys of a project created with `--auth apikey`
+    Apikey {
+        #[command(subcommand)]
+        cmds: ApiKeyCommands,
     },
     Test,
 }
 
+#[derive(Subcommand)]
+enum MigrateCommands {
+    /// Creates a migration for any changes made to inlet.toml since the last one was generated
+    Diff {
+        /// The directory of the project.
+        #[arg(short, long, default_value = ".")]
+        path: PathBuf,
+        /// Describes the migration. Used in the migration's filename.
+        #[arg(short, long, default_value = "schema_change")]
+        name: String,
+        /// Allows changes that could lose data (dropping tables or columns, changing types) without asking.
+        #[arg(long)]
+        allow_destructive: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum SecretCommands {
+    /// Writes a fresh cookie key into Secrets.toml (and Secrets.dev.toml, if there is one)
+    GenKey {
+        /// The directory of the project.
+        #[arg(short, long, default_value = ".")]
+        path: PathBuf,
+        /// Prints the key instead, e.g. for the COOKIE_KEY environment variable of a standalone project.
+        #[arg(long)]
+        print: bool,
+        /// Replaces a key that's already set, which logs everyone out.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ApiKeyCommands {
+    /// Generates an API key, printing it along with the SQL that adds it to the `api_keys` table
+    Create {
+        /// Who or what the key is for.
+        #[arg(short, long)]
+        name: String,
+        /// What the key is allowed to do: read, write or admin (can be used multiple times).
+        #[arg(short, long, default_value = "read")]
+        scope: Vec<String>,
+    },
+}
+
 pub fn process_commands() -> Result<(), String> {
     let cli = Cli::parse();
 
-    let mut cfg = Config {
-        crud: false,
-        auth: false,
-        routes: None,
-        secrets: false,
-    };
-
     match cli.cmds {
         Some(Commands::Create {
             crud,
+            relation,
             auth,
+            email_verification,
+            password_reset,
+            rate_limit,
+            rate_limit_store,
+            csrf,
+            payments,
+            multi_tenant,
+            password_hash,
+            argon2_memory_kib,
+            argon2_iterations,
+            argon2_parallelism,
+            bcrypt_cost,
             secrets,
+            secret,
             name,
+            protected,
+            yes,
+            deploy,
+            embed_migrations,
+            checked_queries,
+            orm,
+            repositories,
+            layout,
+            tracing,
+            metrics,
+            health,
+            health_path,
+            ready_path,
         }) => {
+            let interactive = !yes && std::io::stdin().is_terminal();
+            let asks_protected = auth.is_some() && crud.is_some();
+            let (auth, api_keys) = (
+                auth == Some(AuthMode::Cookie),
+                auth == Some(AuthMode::ApiKey),
+            );
+
+            if !interactive {
+                let mut missing = Vec::new();
+
+                if name.is_none() {
+                    missing.push("--name <NAME>");
+                }
+
+                if asks_protected && protected.is_none() {
+                    missing.push("--protected <true|false>");
+                }
+
+                if !missing.is_empty() {
+                    return Err(format!(
+                        "Running non-interactively, but the following inputs are missing:\n  {}",
+                        missing.join("\n  ")
+                    ));
+                }
+            }
+
             let project_name = match name {
                 Some(res) => res,
                 None => {
@@ -66,84 +273,128 @@ pub fn process_commands() -> Result<(), String> {
                 }
             };
 
-            let protected = match auth && crud.is_some() {
-               true => { 
-                let prompt = Confirm::new("Do you want to protect your CRUD routes?").prompt();
+            let protected = match protected {
+                Some(res) => res,
+                None if asks_protected => {
+                    let prompt = Confirm::new("Do you want to protect your CRUD routes?").prompt();
 
-                match prompt {
-                    Ok(true) => true,
-                    Ok(false) => false,
-                    Err(_) => return Err("Couldn't find your choice :(".to_string())
+                    match prompt {
+                        Ok(res) => res,
+                        Err(_) => return Err("Couldn't find your choice :(".to_string()),
+                    }
                 }
-                    },
-                false => false
+                None => false,
             };
 
-            let project_path = cargo_init(&project_name);
-            let routes_dir = make_dir(project_path.clone(), "routes");
+            let mut routes = crud
+                .unwrap_or_default()
+                .into_iter()
+                .map(|name| Route {
+                    name,
+                    auth_required: protected,
+                    fields: Vec::new(),
+                    relations: Vec::new(),
+                })
+                .collect::<Vec<Route>>();
 
-            let mut routes: Vec<Route> = Vec::new();
+            for declaration in relation {
+                let (owner, relation) = Relation::parse(&declaration)?;
 
-            cfg.secrets = secrets;
+                let Some(route) = routes.iter_mut().find(|x| x.name == owner) else {
+                    return Err(format!(
+                        "`{owner}` in `{declaration}` isn't one of your CRUD resources"
+                    ));
+                };
 
-            if auth {
-                cfg.auth = auth;
-                let middleware_dir = make_dir(project_path.clone(), "middleware");
-                write_file(auth_middleware(), middleware_dir.join("auth.rs")).unwrap();
-                write_mod_file(middleware_dir).unwrap();
-                write_file(auth_routes(), routes_dir.clone().join("auth.rs")).unwrap();
+                route.relations.push(relation);
             }
 
-            if let Some(res) = crud {
-                cfg.crud = true;
-                for name in res {
-                    routes.push(Route {
-                        name,
-                        auth_required: protected,
-                    });
-                }
+            let secret_keys = secret
+                .iter()
+                .map(|x| parse_secret(x))
+                .collect::<Result<Vec<Field>, String>>()?;
 
-                for route in &routes {
-                    let tablename_as_filename = format!("{}.rs", route.name);
-
-                    let (crud_routes, extra_deps) = if route.auth_required {
-                        axum_crud_fns(route.clone(), true)
-                    } else {
-                        axum_crud_fns(route.clone(), false)
-                    };
-
-                    write_main_file(
-                        crud_routes,
-                        extra_deps,
-                        routes_dir.clone().join(tablename_as_filename),
-                    )
-                    .unwrap();
-                }
-            }
+            let cfg = Config {
+                crud: !routes.is_empty(),
+                auth,
+                api_keys,
+                email_verification,
+                password_reset,
+                rate_limit,
+                rate_limit_store,
+                csrf,
+                payments,
+                multi_tenant,
+                password_hash,
+                hash_params: HashParams {
+                    argon2_memory_kib,
+                    argon2_iterations,
+                    argon2_parallelism,
+                    bcrypt_cost,
+                },
+                routes,
+                // Shuttle apps read their cookie key from Secrets.toml
+                secrets: secrets
+                    || !secret_keys.is_empty()
+                    || (auth && deploy == Deployment::Shuttle),
+                secret_keys,
+                deployment: deploy,
+                embed_migrations,
+                checked_queries,
+                orm,
+                // The workspace's db crate is made up of repositories
+                repositories: repositories || layout == Layout::Workspace,
+                layout,
+                tracing,
+                metrics,
+                health,
+                health_path,
+                ready_path,
+            };
 
-            if cfg.crud | cfg.auth {
-                let migrations_dir = make_dir(project_path.clone(), "migrations");
-                write_migration_file(migrations_dir, cfg.clone());
-            }
+            cfg.validate()?;
 
-            if cfg.secrets {
-                write_secrets_file(project_path.clone());
-            }
+            create_project(&project_name, cfg)?;
+        }
+        Some(Commands::Generate { manifest, name }) => {
+            let (manifest_name, cfg) = read_manifest(&manifest)?;
 
-            write_mod_file(routes_dir).unwrap();
+            cfg.validate()?;
 
-            let (main_fn_file, router_useitems) = main_function(cfg.clone(), routes);
-            write_main_file(
-                main_fn_file,
-                router_useitems,
-                project_path.join("src/main.rs"),
-            )
-            .unwrap();
+            create_project(&name.unwrap_or(manifest_name), cfg)?;
+        }
+        Some(Commands::New) => {
+            if !std::io::stdin().is_terminal() {
+                return Err(
+                    "The wizard needs an interactive terminal. Use `inlet create --yes` instead."
+                        .to_string(),
+                );
+            }
 
-            if let Err(e) = add_required_dependencies(project_path, cfg) {
-                panic!("Error while adding dependencies: {e}");
+            if let Some((project_name, cfg)) = run_wizard()? {
+                create_project(&project_name, cfg)?;
             }
         }
+        Some(Commands::Migrate {
+            cmds:
+                MigrateCommands::Diff {
+                    path,
+                    name,
+                    allow_destructive,
+                },
+        }) => {
+            migrate_diff(&path, &name, allow_destructive)?;
+        }
+        Some(Commands::Secret {
+            cmds: SecretCommands::GenKey { path, print, force },
+        }) => {
+            gen_key(&path, print, force)?;
+        }
+        Some(Commands::Apikey {
+            cmds: ApiKeyCommands::Create { name, scope },
+        }) => {
+            create_api_key(&name, &scope)?;
+        }
         Some(Commands::Test) => {}
         None => {}
     }
@@ -151,16 +402,789 @@ pub fn process_commands() -> Result<(), String> {
     Ok(())
 }
 
-#[derive(Clone)]
+/// Where the liveness endpoint is served unless another path is given.
+pub const DEFAULT_HEALTH_PATH: &str = "/healthz";
+/// Where the readiness endpoint is served unless another path is given.
+pub const DEFAULT_READY_PATH: &str = "/readyz";
+
+#[derive(Clone, Default)]
 pub struct Config {
     pub crud: bool,
     pub auth: bool,
-    pub routes: Option<Vec<String>>,
+    /// Protects routes with API keys instead of cookie sessions.
+    pub api_keys: bool,
+    pub email_verification: bool,
+    pub password_reset: bool,
+    pub rate_limit: bool,
+    pub rate_limit_store: RateLimitStore,
+    pub csrf: bool,
+    /// Where payments are taken, if anywhere.
+    pub payments: Option<PaymentProvider>,
+    /// Scopes every resource's rows to the organization of whoever's asking.
+    pub multi_tenant: bool,
+    pub password_hash: PasswordHash,
+    pub hash_params: HashParams,
+    pub routes: Vec<Route>,
     pub secrets: bool,
+    /// The secrets declared for `AppConfig`, as fields whose nullability marks them as optional.
+    pub secret_keys: Vec<Field>,
+    pub deployment: Deployment,
+    pub embed_migrations: bool,
+    pub checked_queries: bool,
+    pub orm: Orm,
+    pub repositories: bool,
+    pub layout: Layout,
+    pub tracing: bool,
+    pub metrics: bool,
+    pub health: bool,
+    pub health_path: String,
+    pub ready_path: String,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.secrets && self.deployment != Deployment::Shuttle {
+            return Err(
+                "A Secrets.toml file can only be used when deploying to Shuttle".to_string(),
+            );
+        }
+
+        if !self.secret_keys.is_empty() && !self.secrets {
+            return Err("Declared secrets need a Secrets.toml file".to_string());
+        }
+
+        validate_secrets(self)?;
+
+        if self.embed_migrations && !(self.crud | self.auth) {
+            return Err("There are no migrations to embed without CRUD routes or auth".to_string());
+        }
+
+        if (self.email_verification || self.password_reset) && !self.auth {
+            return Err(
+                "Email verification and password resets need auth to be enabled".to_string(),
+            );
+        }
+
+        if self.rate_limit && !self.auth {
+            return Err(
+                "Rate limiting protects the auth routes, so it needs auth to be enabled"
+                    .to_string(),
+            );
+        }
+
+        if self.csrf && !self.auth {
+            return Err(
+                "CSRF tokens protect the routes behind auth, so they need auth to be enabled"
+                    .to_string(),
+            );
+        }
+
+        if self.payments.is_some() && !self.auth {
+            return Err(
+                "Payments are linked to the users who make them, so they need cookie auth to be enabled"
+                    .to_string(),
+            );
+        }
+
+        if self.auth {
+            self.hash_params.validate(self.password_hash)?;
+        }
+
+        if self.auth && self.api_keys {
+            return Err(
+                "Routes can be protected by cookie sessions or by API keys, but not both"
+                    .to_string(),
+            );
+        }
+
+        if self.api_keys && !self.crud {
+            return Err(
+                "API keys protect CRUD routes, so they need at least one CRUD resource".to_string(),
+            );
+        }
+
+        if self.routes.iter().any(|x| x.auth_required) && !(self.auth || self.api_keys) {
+            return Err("Protected routes need auth to be enabled".to_string());
+        }
+
+        self.validate_names()?;
+
+        for route in &self.routes {
+            for relation in &route.relations {
+                if !self.routes.iter().any(|x| x.name == relation.target) {
+                    return Err(format!(
+                        "`{}` is related to `{}`, which isn't one of your CRUD resources",
+                        route.name, relation.target
+                    ));
+                }
+            }
+        }
+
+        let keys = foreign_keys(&self.routes);
+        for (idx, key) in keys.iter().enumerate() {
+            let duplicate = keys[..idx]
+                .iter()
+                .any(|x| x.table == key.table && x.references == key.references);
+
+            let clashes = self
+                .routes
+                .iter()
+                .filter(|x| x.name == key.table)
+                .flat_map(|x| x.fields.iter())
+                .any(|x| x.name == key.column());
+
+            if duplicate || clashes {
+                return Err(format!(
+                    "`{}` ends up with more than one `{}` column",
+                    key.table,
+                    key.column()
+                ));
+            }
+        }
+
+        let tables = join_tables(&self.routes);
+        for (idx, table) in tables.iter().enumerate() {
+            if table.left == table.right {
+                return Err(format!(
+                    "`{}` can't have a many-to-many relation with itself",
+                    table.left
+                ));
+            }
+
+            // `a many_to_many b` already links both ways, so `b many_to_many a` would be a second
+            // join table for the same relationship
+            let declared = tables[..idx].iter().any(|x| {
+                (x.left == table.left && x.right == table.right)
+                    || (x.left == table.right && x.right == table.left)
+            });
+
+            if declared {
+                return Err(format!(
+                    "The many-to-many relation between `{}` and `{}` is declared more than once. Declare it on only one of them",
+                    table.left, table.right
+                ));
+            }
+
+            // A foreign key nests `GET /:id/<other>` under the parent's router, which is where the
+            // join table's route would go as well
+            let keyed = keys.iter().any(|x| {
+                (x.table == table.left && x.references == table.right)
+                    || (x.table == table.right && x.references == table.left)
+            });
+
+            if keyed {
+                return Err(format!(
+                    "`{}` and `{}` are already related by a foreign key, so they can't have a many-to-many relation too",
+                    table.left, table.right
+                ));
+            }
+        }
+
+        if self.checked_queries && self.orm != Orm::Sqlx {
+            return Err("Compile-time checked queries can only be used with sqlx".to_string());
+        }
+
+        if self.repositories && !self.crud {
+            return Err("There are no repositories to generate without CRUD routes".to_string());
+        }
+
+        if self.repositories && self.orm != Orm::Sqlx {
+            return Err("Repositories can only be generated with sqlx".to_string());
+        }
+
+        if self.multi_tenant {
+            self.validate_multi_tenant()?;
+        }
+
+        if self.layout == Layout::Workspace && !self.repositories {
+            return Err(
+                "The workspace layout keeps queries in the db crate, so it needs repositories"
+                    .to_string(),
+            );
+        }
+
+        if self.health {
+            self.validate_health_paths()?;
+        }
+
+        if self.orm == Orm::SeaOrm {
+            let mut related: Vec<(&str, &str)> = Vec::new();
+
+            for route in &self.routes {
+                for relation in &route.relations {
+                    let (a, b) = (route.name.as_str(), relation.target.as_str());
+
+                    if a == b {
+                        return Err(format!(
+                            "`{a}` can't be related to itself when generating SeaORM entities"
+                        ));
+                    }
+
+                    if related.contains(&(a, b)) || related.contains(&(b, a)) {
+                        return Err(format!(
+                            "SeaORM entities can only have one relation between `{a}` and `{b}`"
+                        ));
+                    }
+
+                    related.push((a, b));
+                }
+            }
+        }
+
+        creation_order(&self.routes)?;
+
+        Ok(())
+    }
+
+    /// Resources and fields become tables, columns and Rust identifiers, so their names can't
+    /// clash with each other or with anything the generator adds.
+    fn validate_names(&self) -> Result<(), String> {
+        for (idx, route) in self.routes.iter().enumerate() {
+            validate_resource_name(&route.name)?;
+
+            if self.routes[..idx].iter().any(|x| x.name == route.name) {
+                return Err(format!("`{}` is declared more than once", route.name));
+            }
+
+            for (idx, field) in route.fields.iter().enumerate() {
+                validate_field_name(&field.name)?;
+
+                if self.multi_tenant && field.name == TENANT_COLUMN {
+                    return Err(format!(
+                        "`{}.{TENANT_COLUMN}` clashes with the column multi-tenancy adds",
+                        route.name
+                    ));
+                }
+
+                if route.fields[..idx].iter().any(|x| x.name == field.name) {
+                    return Err(format!(
+                        "`{}.{}` is declared more than once",
+                        route.name, field.name
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The health endpoints sit next to the resources' routers, so they can't share a path with
+    /// any of them or with each other.
+    fn validate_health_paths(&self) -> Result<(), String> {
+        let mut taken: Vec<String> = self.routes.iter().map(|x| format!("/{}", x.name)).collect();
+
+        if self.auth {
+            taken.push("/auth".to_string());
+        }
+
+        if self.metrics {
+            taken.push("/metrics".to_string());
+        }
+
+        if self.multi_tenant {
+            taken.push(format!("/{ORGANIZATIONS}"));
+        }
+
+        for path in [&self.health_path, &self.ready_path] {
+            if !path.starts_with('/') || path == "/" {
+                return Err(format!(
+                    "`{path}` should be a path starting with `/`, like `/healthz`"
+                ));
+            }
+
+            let clashes = taken
+                .iter()
+                .any(|x| path == x || path.starts_with(&format!("{x}/")));
+
+            if clashes {
+                return Err(format!("`{path}` is already used by another route"));
+            }
+
+            taken.push(path.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Every row belongs to the organization of the user who made it, which is looked up from
+    /// their session in the scoped sqlx queries.
+    fn validate_multi_tenant(&self) -> Result<(), String> {
+        if !self.auth {
+            return Err(
+                "Rows belong to the organizations users are members of, so multi-tenancy needs cookie auth to be enabled"
+                    .to_string(),
+            );
+        }
+
+        if !self.crud {
+            return Err(
+                "Multi-tenancy scopes CRUD resources, so it needs at least one CRUD resource"
+                    .to_string(),
+            );
+        }
+
+        if self.orm != Orm::Sqlx || self.repositories {
+            return Err(
+                "Multi-tenancy scopes the generated sqlx queries, so it can't be used with other ORMs or with repositories"
+                    .to_string(),
+            );
+        }
+
+        if let Some(route) = self.routes.iter().find(|x| !x.auth_required) {
+            return Err(format!(
+                "`{}` isn't protected, but every multi-tenant resource needs a session to find its organization",
+                route.name
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Resource and field names end up as Rust identifiers and as unquoted SQL names, so they're kept
+/// to lowercase letters, digits and underscores, and can't be Rust keywords.
+fn validate_identifier(name: &str) -> Result<(), String> {
+    let valid = name.starts_with(|x: char| x.is_ascii_lowercase() || x == '_')
+        && name
+            .chars()
+            .all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || x == '_');
+
+    if !valid {
+        return Err(format!(
+            "`{name}` can't be used as a name. Use lowercase letters, digits and underscores, like `blog_post`"
+        ));
+    }
+
+    if syn::parse_str::<Ident>(name).is_err() {
+        return Err(format!(
+            "`{name}` can't be used as a name, since it's a Rust keyword"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks the name of a resource, which is also the name of its table.
+pub fn validate_resource_name(name: &str) -> Result<(), String> {
+    validate_identifier(name)?;
+
+    if GENERATED_TABLES.contains(&name) {
+        return Err(format!(
+            "`{name}` is a table inlet generates, so it can't be a CRUD resource"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks the name of a field, which is also the name of its column.
+pub fn validate_field_name(name: &str) -> Result<(), String> {
+    validate_identifier(name)?;
+
+    if GENERATED_COLUMNS.contains(&name) {
+        return Err(format!(
+            "`{name}` is a column every resource already has, so it can't be a field"
+        ));
+    }
+
+    Ok(())
+}
+
+/// The cost of hashing a password. Only the chosen algorithm's parameters are used.
+#[derive(Clone, Copy, PartialEq)]
+pub struct HashParams {
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    pub bcrypt_cost: u32,
+}
+
+impl Default for HashParams {
+    /// OWASP's recommendations: 19MiB of memory, 2 iterations and 1 lane for argon2id, and a
+    /// cost of 12 for bcrypt.
+    fn default() -> Self {
+        Self {
+            argon2_memory_kib: 19456,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+            bcrypt_cost: 12,
+        }
+    }
+}
+
+impl HashParams {
+    /// Checks the parameters against what the hashing crates accept, so the generated app doesn't
+    /// fail on its first registration instead.
+    pub fn validate(&self, hash: PasswordHash) -> Result<(), String> {
+        match hash {
+            PasswordHash::Argon2id => {
+                if self.argon2_iterations == 0 {
+                    return Err("argon2id needs at least 1 iteration".to_string());
+                }
+
+                if !(1..=0xFF_FFFF).contains(&self.argon2_parallelism) {
+                    return Err(
+                        "argon2id's parallelism should be between 1 and 16777215".to_string()
+                    );
+                }
+
+                if self.argon2_memory_kib < 8 * self.argon2_parallelism {
+                    return Err(format!(
+                        "argon2id needs at least {}KiB of memory with a parallelism of {}",
+                        8 * self.argon2_parallelism,
+                        self.argon2_parallelism
+                    ));
+                }
+            }
+            PasswordHash::Bcrypt => {
+                if !(4..=31).contains(&self.bcrypt_cost) {
+                    return Err(format!(
+                        "bcrypt's cost should be between 4 and 31, not {}",
+                        self.bcrypt_cost
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
 pub struct Route {
     pub name: String,
     pub auth_required: bool,
+    pub fields: Vec<Field>,
+    pub relations: Vec<Relation>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum Deployment {
+    /// Deploys to Shuttle using shuttle-runtime
+    #[default]
+    Shuttle,
+    /// A plain Tokio binary that you can run anywhere
+    Standalone,
+}
+
+impl Deployment {
+    pub const ALL: [Deployment; 2] = [Deployment::Shuttle, Deployment::Standalone];
+
+    /// The name used for this deployment target in manifests.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Deployment::Shuttle => "shuttle",
+            Deployment::Standalone => "standalone",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Deployment::ALL.into_iter().find(|x| x.name() == name)
+    }
+}
+
+impl fmt::Display for Deployment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum Layout {
+    /// One crate holding everything
+    #[default]
+    Single,
+    /// A workspace with api, domain, db and migrations crates
+    Workspace,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 2] = [Layout::Single, Layout::Workspace];
+
+    /// The name used for this layout in manifests.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layout::Single => "single",
+            Layout::Workspace => "workspace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Layout::ALL.into_iter().find(|x| x.name() == name)
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// `SeaOrm` is the crate's name, not a repeat of the enum's
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum Orm {
+    /// Plain sqlx queries
+    #[default]
+    Sqlx,
+    /// SeaORM entities, with a SeaORM migration crate
+    #[value(name = "seaorm")]
+    SeaOrm,
+    /// Diesel, with a deadpool-diesel connection pool
+    Diesel,
+}
+
+impl Orm {
+    pub const ALL: [Orm; 3] = [Orm::Sqlx, Orm::SeaOrm, Orm::Diesel];
+
+    /// The name used for this ORM in manifests.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Orm::Sqlx => "sqlx",
+            Orm::SeaOrm => "seaorm",
+            Orm::Diesel => "diesel",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Orm::ALL.into_iter().find(|x| x.name() == name)
+    }
+}
+
+impl fmt::Display for Orm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum PasswordHash {
+    /// argon2id, which is memory-hard
+    #[default]
+    Argon2id,
+    /// bcrypt
+    Bcrypt,
+}
+
+impl PasswordHash {
+    pub const ALL: [PasswordHash; 2] = [PasswordHash::Argon2id, PasswordHash::Bcrypt];
+
+    /// The name used for this algorithm in manifests.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PasswordHash::Argon2id => "argon2id",
+            PasswordHash::Bcrypt => "bcrypt",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        PasswordHash::ALL.into_iter().find(|x| x.name() == name)
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum RateLimitStore {
+    /// Counted by each instance on its own
+    #[default]
+    Memory,
+    /// Counted in a Postgres table shared by every instance
+    Postgres,
+}
+
+impl RateLimitStore {
+    pub const ALL: [RateLimitStore; 2] = [RateLimitStore::Memory, RateLimitStore::Postgres];
+
+    /// The name used for this store in manifests.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RateLimitStore::Memory => "memory",
+            RateLimitStore::Postgres => "postgres",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        RateLimitStore::ALL.into_iter().find(|x| x.name() == name)
+    }
+}
+
+impl fmt::Display for RateLimitStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum AuthMode {
+    /// Users log in and get a session cookie
+    #[default]
+    Cookie,
+    /// Callers send a key in the X-API-Key or Authorization header
+    #[value(name = "apikey")]
+    ApiKey,
+}
+
+impl AuthMode {
+    pub const ALL: [AuthMode; 2] = [AuthMode::Cookie, AuthMode::ApiKey];
+
+    /// The name used for this mode on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthMode::Cookie => "cookie",
+            AuthMode::ApiKey => "apikey",
+        }
+    }
+}
+
+impl fmt::Display for AuthMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum PaymentProvider {
+    /// Stripe Checkout, the customer portal and signed webhooks
+    Stripe,
+}
+
+impl PaymentProvider {
+    pub const ALL: [PaymentProvider; 1] = [PaymentProvider::Stripe];
+
+    /// The name used for this provider in manifests.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PaymentProvider::Stripe => "stripe",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        PaymentProvider::ALL.into_iter().find(|x| x.name() == name)
+    }
+}
+
+impl fmt::Display for PaymentProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manifest::parse_manifest;
+
+    fn validate(manifest: &str) -> Result<(), String> {
+        parse_manifest(&format!("name = \"test\"\n{manifest}"))?
+            .1
+            .validate()
+    }
+
+    fn resource(name: &str, field: &str) -> String {
+        format!("[[resources]]\nname = \"{name}\"\n\n[[resources.fields]]\nname = \"{field}\"\n")
+    }
+
+    #[test]
+    fn accepts_plain_names() {
+        assert!(validate(&resource("blog_post", "title2")).is_ok());
+    }
+
+    #[test]
+    fn rejects_names_that_arent_identifiers() {
+        for (name, field) in [
+            ("my-post", "title"),
+            ("Post", "title"),
+            ("2posts", "title"),
+            ("post", "type"),
+            ("self", "title"),
+        ] {
+            assert!(validate(&resource(name, field)).is_err(), "{name}.{field}");
+        }
+    }
+
+    #[test]
+    fn rejects_generated_tables_and_columns() {
+        for name in [
+            "users",
+            "usersessions",
+            "organizations",
+            "api_keys",
+            "payments",
+        ] {
+            assert!(validate(&resource(name, "title")).is_err(), "{name}");
+        }
+
+        for field in ["id", "created_at", "last_updated"] {
+            assert!(validate(&resource("post", field)).is_err(), "{field}");
+        }
+
+        // The tenant column is only added to multi-tenant projects
+        assert!(validate(&resource("post", "tenant_id")).is_ok());
+        let tenant = format!(
+            "auth = true\nmulti_tenant = true\n{}",
+            resource("post", "tenant_id").replace("\n\n", "\nprotected = true\n\n")
+        );
+        assert!(validate(&tenant.replace("tenant_id", "title")).is_ok());
+        assert!(validate(&tenant).unwrap_err().contains("multi-tenancy"));
+    }
+
+    fn related(relations: &[(&str, &str, &str)]) -> String {
+        let mut manifest = String::new();
+
+        for name in ["post", "tag"] {
+            manifest.push_str(&format!("[[resources]]\nname = \"{name}\"\n"));
+
+            for (owner, kind, target) in relations {
+                if *owner == name {
+                    manifest.push_str(&format!(
+                        "[[resources.relations]]\nkind = \"{kind}\"\ntarget = \"{target}\"\n"
+                    ));
+                }
+            }
+        }
+
+        manifest
+    }
+
+    #[test]
+    fn rejects_a_many_to_many_relation_declared_from_both_sides() {
+        assert!(validate(&related(&[("post", "many_to_many", "tag")])).is_ok());
+
+        let err = validate(&related(&[
+            ("post", "many_to_many", "tag"),
+            ("tag", "many_to_many", "post"),
+        ]))
+        .unwrap_err();
+        assert!(err.contains("declared more than once"), "{err}");
+    }
+
+    #[test]
+    fn rejects_a_many_to_many_relation_alongside_a_foreign_key() {
+        for (owner, kind, target) in [
+            ("tag", "belongs_to", "post"),
+            ("post", "belongs_to", "tag"),
+            ("post", "has_many", "tag"),
+        ] {
+            let err = validate(&related(&[
+                ("post", "many_to_many", "tag"),
+                (owner, kind, target),
+            ]))
+            .unwrap_err();
+            assert!(
+                err.contains("foreign key"),
+                "{owner} {kind} {target}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let post = resource("post", "title");
+        assert!(validate(&format!("{post}\n{post}")).is_err());
+        assert!(validate(&format!("{post}\n[[resources.fields]]\nname = \"title\"\n")).is_err());
+    }
 }
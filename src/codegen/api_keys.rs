@@ -0,0 +1,255 @@
+use crate::cli::Config;
+use crate::codegen::axum_auth::instrument_secrets;
+use proc_macro2::{Literal, TokenStream};
+use quote::quote;
+use syn::File;
+
+/// The header keys are sent in, unless they're sent as a bearer token.
+pub const API_KEY_HEADER: &str = "x-api-key";
+/// What every key starts with, so they're easy to spot in config files and logs.
+pub const API_KEY_PREFIX: &str = "ik_";
+/// How many random bytes a key is made of.
+pub const API_KEY_BYTES: usize = 32;
+/// How much of a key is stored as-is, so keys can be told apart without being usable.
+pub const SHOWN_PREFIX_LEN: usize = 11;
+/// `read` allows GET requests to protected routes, `write` allows everything else, and `admin`
+/// allows anything, including managing keys.
+pub const API_KEY_SCOPES: [&str; 3] = ["read", "write", "admin"];
+
+/// A middleware that checks the key in a request's `X-API-Key` header (or `Authorization:
+/// Bearer`) against the hashes in `api_keys`, along with the scope the request needs.
+pub fn api_key_middleware(cfg: &Config) -> File {
+    let instrument = instrument_secrets(cfg);
+
+    let bytes = Literal::usize_unsuffixed(API_KEY_BYTES);
+    let shown_prefix_len = Literal::usize_unsuffixed(SHOWN_PREFIX_LEN);
+    let scope_count = Literal::usize_unsuffixed(API_KEY_SCOPES.len());
+    let scopes = API_KEY_SCOPES;
+
+    let code = quote! {
+        use crate::AppState;
+        use axum::extract::State;
+        use axum::http::{header, HeaderMap, Method, Request, StatusCode};
+        use axum::middleware::Next;
+        use axum::response::Response;
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use base64::Engine;
+        use sha2::{Digest, Sha256};
+
+        /// The header keys are sent in, unless they're sent as a bearer token.
+        pub const API_KEY_HEADER: &str = #API_KEY_HEADER;
+        pub const API_KEY_PREFIX: &str = #API_KEY_PREFIX;
+        /// How much of a key is stored as-is, so keys can be told apart in the admin routes.
+        pub const SHOWN_PREFIX_LEN: usize = #shown_prefix_len;
+        /// `read` allows GET requests, `write` allows everything else, and `admin` allows
+        /// anything, including managing keys.
+        pub const SCOPES: [&str; #scope_count] = [#(#scopes),*];
+
+        /// The key a request was made with. Handlers behind `check_api_key` can get it as an
+        /// `Extension<ApiKeyInfo>`.
+        #[derive(Clone, sqlx::FromRow)]
+        pub struct ApiKeyInfo {
+            pub id: i32,
+            pub name: String,
+            pub scopes: Vec<String>,
+        }
+
+        impl ApiKeyInfo {
+            /// Admin keys are allowed to do anything.
+            pub fn allows(&self, scope: &str) -> bool {
+                self.scopes.iter().any(|x| x == scope || x == "admin")
+            }
+        }
+
+        /// A fresh key. Only its hash is stored, so it can only be shown once.
+        pub fn generate_api_key() -> String {
+            format!("{API_KEY_PREFIX}{}", URL_SAFE_NO_PAD.encode(rand::random::<[u8; #bytes]>()))
+        }
+
+        /// Keys are random enough that a fast hash is all they need.
+        pub fn hash_api_key(key: &str) -> String {
+            URL_SAFE_NO_PAD.encode(Sha256::digest(key.as_bytes()))
+        }
+
+        fn presented_key(headers: &HeaderMap) -> Option<&str> {
+            if let Some(key) = headers.get(API_KEY_HEADER) {
+                return key.to_str().ok();
+            }
+
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.strip_prefix("Bearer "))
+        }
+
+        async fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<ApiKeyInfo, (StatusCode, String)> {
+            let Some(key) = presented_key(headers) else {
+                return Err((StatusCode::UNAUTHORIZED, "Missing API key".to_string()));
+            };
+
+            sqlx::query_as::<_, ApiKeyInfo>("SELECT id, name, scopes FROM api_keys WHERE key_hash = $1 AND revoked_at IS NULL")
+                .bind(hash_api_key(key))
+                .fetch_optional(&state.db)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Something went wrong checking your API key: {e}")))?
+                .ok_or_else(|| (StatusCode::UNAUTHORIZED, "Invalid API key".to_string()))
+        }
+
+        /// Lets a request through if its key has the scope its method needs: `read` for GET and
+        /// HEAD, and `write` for everything else.
+        #instrument
+        pub async fn check_api_key<B>(
+            State(state): State<AppState>,
+            mut req: Request<B>,
+            next: Next<B>,
+        ) -> Result<Response, (StatusCode, String)> {
+            let key = authenticate(&state, req.headers()).await?;
+
+            let scope = if matches!(*req.method(), Method::GET | Method::HEAD) {
+                "read"
+            } else {
+                "write"
+            };
+
+            if !key.allows(scope) {
+                return Err((StatusCode::FORBIDDEN, format!("This API key needs the `{scope}` scope")));
+            }
+
+            req.extensions_mut().insert(key);
+            Ok(next.run(req).await)
+        }
+
+        /// Only lets admin keys through, for the routes that manage keys.
+        #instrument
+        pub async fn check_admin_key<B>(
+            State(state): State<AppState>,
+            mut req: Request<B>,
+            next: Next<B>,
+        ) -> Result<Response, (StatusCode, String)> {
+            let key = authenticate(&state, req.headers()).await?;
+
+            if !key.allows("admin") {
+                return Err((StatusCode::FORBIDDEN, "This API key needs the `admin` scope".to_string()));
+            }
+
+            req.extensions_mut().insert(key);
+            Ok(next.run(req).await)
+        }
+    };
+
+    syn::parse_file(&code.to_string()).unwrap()
+}
+
+/// The admin routes for creating, listing and revoking keys.
+pub fn api_key_routes(cfg: &Config) -> File {
+    let instrument = instrument_secrets(cfg);
+
+    let code = quote! {
+        use crate::middleware::api_key::{generate_api_key, hash_api_key, SCOPES, SHOWN_PREFIX_LEN};
+        use crate::AppState;
+        use axum::extract::{Path, State};
+        use axum::http::StatusCode;
+        use axum::Json;
+        use chrono::{DateTime, Utc};
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Deserialize)]
+        pub struct NewApiKey {
+            pub name: String,
+            pub scopes: Vec<String>,
+        }
+
+        /// The only time a key is shown in full.
+        #[derive(Serialize)]
+        pub struct CreatedApiKey {
+            pub id: i32,
+            pub name: String,
+            pub key: String,
+            pub scopes: Vec<String>,
+        }
+
+        #[derive(Serialize, sqlx::FromRow)]
+        pub struct ApiKeySummary {
+            pub id: i32,
+            pub name: String,
+            pub prefix: String,
+            pub scopes: Vec<String>,
+            pub created_at: DateTime<Utc>,
+            pub revoked_at: Option<DateTime<Utc>>,
+        }
+
+        #instrument
+        pub async fn create_api_key(
+            State(state): State<AppState>,
+            Json(req): Json<NewApiKey>,
+        ) -> Result<(StatusCode, Json<CreatedApiKey>), (StatusCode, String)> {
+            if let Some(scope) = req.scopes.iter().find(|x| !SCOPES.contains(&x.as_str())) {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Unknown scope: {scope}. Expected one of {}", SCOPES.join(", ")),
+                ));
+            }
+
+            let key = generate_api_key();
+
+            let (id,): (i32,) = sqlx::query_as("INSERT INTO api_keys (name, prefix, key_hash, scopes) VALUES ($1, $2, $3, $4) RETURNING id")
+                .bind(&req.name)
+                .bind(&key[..SHOWN_PREFIX_LEN])
+                .bind(hash_api_key(&key))
+                .bind(&req.scopes)
+                .fetch_one(&state.db)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Error while creating an API key: {e}")))?;
+
+            Ok((
+                StatusCode::CREATED,
+                Json(CreatedApiKey {
+                    id,
+                    name: req.name,
+                    key,
+                    scopes: req.scopes,
+                }),
+            ))
+        }
+
+        pub async fn list_api_keys(
+            State(state): State<AppState>,
+        ) -> Result<Json<Vec<ApiKeySummary>>, (StatusCode, String)> {
+            sqlx::query_as::<_, ApiKeySummary>("SELECT id, name, prefix, scopes, created_at, revoked_at FROM api_keys ORDER BY id")
+                .fetch_all(&state.db)
+                .await
+                .map(Json)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Error while listing API keys: {e}")))
+        }
+
+        /// Stops a key from working. It's kept, so it still shows up in the list.
+        pub async fn revoke_api_key(
+            State(state): State<AppState>,
+            Path(id): Path<i32>,
+        ) -> Result<StatusCode, (StatusCode, String)> {
+            let res = sqlx::query("UPDATE api_keys SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL")
+                .bind(id)
+                .execute(&state.db)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Error while revoking an API key: {e}")))?;
+
+            if res.rows_affected() == 0 {
+                return Err((StatusCode::NOT_FOUND, "There's no active API key with that id".to_string()));
+            }
+
+            Ok(StatusCode::OK)
+        }
+    };
+
+    syn::parse_file(&code.to_string()).unwrap()
+}
+
+/// The router for the admin routes, which only admin keys can use.
+pub fn admin_router() -> TokenStream {
+    quote! {
+        let admin_router = Router::new()
+            .route("/api-keys", get(list_api_keys).post(create_api_key))
+            .route("/api-keys/:id", delete(revoke_api_key))
+            .layer(from_fn_with_state(state.clone(), check_admin_key));
+    }
+}
@@ -1,19 +1,57 @@
+use crate::cli::Config;
+use crate::codegen::csrf::{csrf_protected, csrf_route};
+use crate::codegen::mailer::sends_email;
 use crate::codegen::main_fn::axum_endpoint;
+use crate::codegen::rate_limit::{rate_limited, LOCKOUT_MINUTES, MAX_FAILED_LOGINS};
+use crate::codegen::tenants::insert_user;
+use proc_macro2::Literal;
 use proc_macro2::TokenStream;
 use quote::quote;
 use syn::File;
 
-fn register_route() -> TokenStream {
+/// Records the username on the handler's span, but never the password.
+fn instrument_credentials(cfg: &Config) -> Option<TokenStream> {
+    cfg.tracing
+        .then(|| quote! {#[tracing::instrument(skip_all, fields(username = %user.username))]})
+}
+
+/// Logs an error that doesn't fail the request. The message can refer to `e`.
+fn log_error(cfg: &Config, message: &str) -> TokenStream {
+    if cfg.tracing {
+        quote! {tracing::error!(#message);}
+    } else {
+        quote! {eprintln!(#message);}
+    }
+}
+
+/// Handlers and middleware that never record their arguments, since they carry passwords, keys,
+/// session cookies or tokens.
+pub(crate) fn instrument_secrets(cfg: &Config) -> Option<TokenStream> {
+    cfg.tracing
+        .then(|| quote! {#[tracing::instrument(skip_all)]})
+}
+
+fn register_route(cfg: &Config) -> TokenStream {
+    if sends_email(cfg) {
+        return register_with_email_route(cfg);
+    }
+
     let endpoint = axum_endpoint();
+    let instrument = instrument_credentials(cfg);
+    let insert = insert_user(cfg, &["username", "password"], false);
 
     quote! {
+        #instrument
         pub async fn register(
             State(state): State<AppState>,
             Json(user): Json<LoginDetails>
         ) -> #endpoint {
-            let hashed_password = hash(user.password, 10u32).unwrap();
+            let hashed_password = match hash_password(user.password).await {
+                Ok(res) => res,
+                Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error while registering: {e}")))
+            };
 
-            if let Err(e) = sqlx::query("INSERT INTO users (username, password) VALUES ($1, $2)")
+            if let Err(e) = sqlx::query(#insert)
                 .bind(user.username)
                 .bind(hashed_password)
                 .execute(&state.db)
@@ -26,18 +64,413 @@ fn register_route() -> TokenStream {
     }
 }
 
-pub fn auth_router() -> TokenStream {
+/// Registering takes an email address once the auth routes send emails. With verification, the
+/// account is only created if its verification email could be sent.
+fn register_with_email_route(cfg: &Config) -> TokenStream {
+    let instrument = instrument_credentials(cfg);
+    let insert = insert_user(
+        cfg,
+        &["username", "email", "password"],
+        cfg.email_verification,
+    );
+
+    let create_user = if cfg.email_verification {
+        quote! {
+            let mut tx = state.db.begin().await.map_err(internal_error)?;
+
+            let (user_id,): (i32,) = sqlx::query_as(#insert)
+            .bind(user.username)
+            .bind(&user.email)
+            .bind(hashed_password)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Error while registering: {e}")))?;
+
+            let token = issue_token(
+                &mut tx,
+                EMAIL_VERIFICATION_TOKENS,
+                user_id,
+                ChronoDuration::hours(VERIFICATION_TOKEN_HOURS),
+            )
+            .await?;
+
+            state
+                .mailer
+                .send(verification_email(&user.email, &token))
+                .await
+                .map_err(internal_error)?;
+
+            tx.commit().await.map_err(internal_error)?;
+        }
+    } else {
+        quote! {
+            sqlx::query(#insert)
+                .bind(user.username)
+                .bind(user.email)
+                .bind(hashed_password)
+                .execute(&state.db)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Error while registering: {e}")))?;
+        }
+    };
+
+    quote! {
+        #instrument
+        pub async fn register(
+            State(state): State<AppState>,
+            Json(user): Json<RegisterDetails>
+        ) -> Result<StatusCode, (StatusCode, String)> {
+            if !user.email.contains('@') {
+                return Err((StatusCode::BAD_REQUEST, "That isn't a valid email address".to_string()));
+            }
+
+            let hashed_password = hash_password(user.password).await.map_err(internal_error)?;
+
+            #create_user
+
+            Ok(StatusCode::CREATED)
+        }
+    }
+}
+
+fn verify_email_route(cfg: &Config) -> TokenStream {
+    let instrument = instrument_secrets(cfg);
+
+    quote! {
+        #[derive(Deserialize)]
+        pub struct VerifyEmail {
+            pub token: String
+        }
+
+        /// Where the link in the verification email leads.
+        #instrument
+        pub async fn verify_email(
+            State(state): State<AppState>,
+            Query(query): Query<VerifyEmail>
+        ) -> Result<StatusCode, (StatusCode, String)> {
+            let mut tx = state.db.begin().await.map_err(internal_error)?;
+
+            let user_id = redeem_token(&mut tx, EMAIL_VERIFICATION_TOKENS, &query.token).await?;
+
+            sqlx::query("UPDATE users SET email_verified_at = now() WHERE id = $1")
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(internal_error)?;
+
+            tx.commit().await.map_err(internal_error)?;
+
+            Ok(StatusCode::OK)
+        }
+    }
+}
+
+fn password_reset_routes(cfg: &Config) -> TokenStream {
+    let instrument = instrument_secrets(cfg);
+
+    // Following the link in a reset email proves the address is theirs as much as verifying it
+    let verified = if cfg.email_verification {
+        ", email_verified_at = COALESCE(email_verified_at, now())"
+    } else {
+        ""
+    };
+    // A new password lifts any lockout the old one's guesses caused
+    let unlocked = if rate_limited(cfg) {
+        ", failed_logins = 0, locked_until = NULL"
+    } else {
+        ""
+    };
+    let update_password = format!(
+        "UPDATE users SET password = $1, last_updated = now(){verified}{unlocked} WHERE id = $2"
+    );
+
+    let log_error = log_error(cfg, "Couldn't send a password reset email: {e}");
+
+    quote! {
+        #[derive(Deserialize)]
+        pub struct ForgotPassword {
+            pub email: String
+        }
+
+        /// Emails a password reset token to the address, if it belongs to anyone. The response is
+        /// the same either way, so it can't be used to find out who has an account.
+        #instrument
+        pub async fn forgot_password(
+            State(state): State<AppState>,
+            Json(req): Json<ForgotPassword>
+        ) -> Result<StatusCode, (StatusCode, String)> {
+            let user: Option<(i32,)> = sqlx::query_as("SELECT id FROM users WHERE email = $1")
+                .bind(&req.email)
+                .fetch_optional(&state.db)
+                .await
+                .map_err(internal_error)?;
+
+            if let Some((user_id,)) = user {
+                let mut conn = state.db.acquire().await.map_err(internal_error)?;
+
+                let token = issue_token(
+                    &mut conn,
+                    PASSWORD_RESET_TOKENS,
+                    user_id,
+                    ChronoDuration::minutes(RESET_TOKEN_MINUTES),
+                )
+                .await?;
+
+                if let Err(e) = state.mailer.send(reset_email(&req.email, &token)).await {
+                    #log_error
+                }
+            }
+
+            Ok(StatusCode::ACCEPTED)
+        }
+
+        #[derive(Deserialize)]
+        pub struct ResetPassword {
+            pub token: String,
+            pub password: String
+        }
+
+        /// Sets a new password with the token from a reset email, logging out every session the
+        /// old password started.
+        #instrument
+        pub async fn reset_password(
+            State(state): State<AppState>,
+            Json(req): Json<ResetPassword>
+        ) -> Result<StatusCode, (StatusCode, String)> {
+            let hashed_password = hash_password(req.password).await.map_err(internal_error)?;
+
+            let mut tx = state.db.begin().await.map_err(internal_error)?;
+
+            let user_id = redeem_token(&mut tx, PASSWORD_RESET_TOKENS, &req.token).await?;
+
+            sqlx::query(#update_password)
+                .bind(hashed_password)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(internal_error)?;
+
+            sqlx::query("DELETE FROM usersessions WHERE user_id = $1")
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(internal_error)?;
+
+            tx.commit().await.map_err(internal_error)?;
+
+            Ok(StatusCode::OK)
+        }
+    }
+}
+
+/// The tokens emailed out for verification and password resets. They're single use and only
+/// their hashes are stored, so a copy of the database can't be used to take over accounts.
+fn email_token_fns(cfg: &Config) -> TokenStream {
+    let verification = cfg.email_verification.then(|| {
+        quote! {
+            const EMAIL_VERIFICATION_TOKENS: &str = "email_verification_tokens";
+            /// How long the link in a verification email works for.
+            const VERIFICATION_TOKEN_HOURS: i64 = 24;
+
+            fn verification_email(to: &str, token: &str) -> Email {
+                Email {
+                    to: to.to_string(),
+                    subject: "Verify your email address".to_string(),
+                    body: format!(
+                        "Thanks for signing up! To verify your email address, open /auth/verify?token={token}\n\nThe link works for {VERIFICATION_TOKEN_HOURS} hours."
+                    ),
+                }
+            }
+        }
+    });
+
+    let reset = cfg.password_reset.then(|| {
+        quote! {
+            const PASSWORD_RESET_TOKENS: &str = "password_reset_tokens";
+            /// How long the token in a password reset email works for.
+            const RESET_TOKEN_MINUTES: i64 = 60;
+
+            fn reset_email(to: &str, token: &str) -> Email {
+                Email {
+                    to: to.to_string(),
+                    subject: "Reset your password".to_string(),
+                    body: format!(
+                        "To choose a new password, send this token to /auth/reset along with it:\n\n{token}\n\nIt works for {RESET_TOKEN_MINUTES} minutes. If you didn't ask to reset your password, you can ignore this email."
+                    ),
+                }
+            }
+        }
+    });
+
+    quote! {
+        #verification
+        #reset
+
+        fn hash_token(token: &str) -> String {
+            URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+        }
+
+        /// Stores a new token for the user in `table`, returning it so it can be emailed out.
+        async fn issue_token(
+            conn: &mut PgConnection,
+            table: &str,
+            user_id: i32,
+            ttl: ChronoDuration,
+        ) -> Result<String, (StatusCode, String)> {
+            let token = new_token();
+
+            sqlx::query(&format!(
+                "INSERT INTO {table} (user_id, token_hash, expires_at) VALUES ($1, $2, $3)"
+            ))
+            .bind(user_id)
+            .bind(hash_token(&token))
+            .bind(Utc::now() + ttl)
+            .execute(conn)
+            .await
+            .map_err(internal_error)?;
+
+            Ok(token)
+        }
+
+        /// Uses up a token from `table`, returning the user it was issued to.
+        async fn redeem_token(
+            conn: &mut PgConnection,
+            table: &str,
+            token: &str,
+        ) -> Result<i32, (StatusCode, String)> {
+            let user: Option<(i32,)> = sqlx::query_as(&format!(
+                "DELETE FROM {table} WHERE token_hash = $1 AND expires_at > now() RETURNING user_id"
+            ))
+            .bind(hash_token(token))
+            .fetch_optional(conn)
+            .await
+            .map_err(internal_error)?;
+
+            user.map(|(user_id,)| user_id)
+                .ok_or_else(|| (StatusCode::BAD_REQUEST, "The token is invalid or has expired".to_string()))
+        }
+
+        fn internal_error(e: impl Display) -> (StatusCode, String) {
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Something went wrong: {e}"))
+        }
+    }
+}
+
+/// The names of the handlers in the auth routes module.
+pub fn auth_handlers(cfg: &Config) -> Vec<&'static str> {
+    let mut handlers = vec!["login", "register"];
+
+    if cfg.email_verification {
+        handlers.push("verify_email");
+    }
+
+    if cfg.password_reset {
+        handlers.extend(["forgot_password", "reset_password"]);
+    }
+
+    if csrf_protected(cfg) {
+        handlers.push("csrf_token");
+    }
+
+    handlers
+}
+
+pub fn auth_router(cfg: &Config) -> TokenStream {
+    let verify = cfg
+        .email_verification
+        .then(|| quote! {.route("/verify", get(verify_email))});
+    let reset = cfg.password_reset.then(|| {
+        quote! {
+            .route("/forgot", post(forgot_password))
+            .route("/reset", post(reset_password))
+        }
+    });
+
+    let csrf = csrf_protected(cfg).then(|| quote! {.route("/csrf", get(csrf_token))});
+    let rate_limit = rate_limited(cfg)
+        .then(|| quote! {.route_layer(from_fn_with_state(state.clone(), rate_limit))});
+
     quote! {
     let auth_router = Router::new()
         .route("/login", post(login))
-        .route("/register", post(register));
+        .route("/register", post(register))
+        #verify
+        #reset
+        #csrf
+        #rate_limit;
     }
 }
 
-fn login_route() -> TokenStream {
+fn login_route(cfg: &Config) -> TokenStream {
     let endpoint = axum_endpoint();
+    let instrument = instrument_credentials(cfg);
+
+    let log_rehash_error = log_error(cfg, "Couldn't rehash a password: {e}");
+
+    let check_verified = cfg.email_verification.then(|| {
+        quote! {
+            match sqlx::query_as::<_, (bool,)>("SELECT email_verified_at IS NOT NULL FROM users WHERE username = $1")
+                .bind(user.username.clone())
+                .fetch_one(&state.db)
+                .await {
+                Ok((true,)) => {},
+                Ok((false,)) => {return Err((StatusCode::FORBIDDEN, "Verify your email address before logging in".to_string()))},
+                Err(e) => {return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Something went wrong trying to log you in: {e}")))}
+            }
+        }
+    });
+
+    let (check_locked, record_failure, clear_failures, lockout_consts) = if rate_limited(cfg) {
+        let max_failed_logins = Literal::i32_unsuffixed(MAX_FAILED_LOGINS);
+        let lockout_minutes = Literal::i32_unsuffixed(LOCKOUT_MINUTES);
+
+        (
+            Some(quote! {
+                match sqlx::query_as::<_, (bool,)>("SELECT locked_until IS NOT NULL AND locked_until > now() FROM users WHERE username = $1")
+                    .bind(user.username.clone())
+                    .fetch_one(&state.db)
+                    .await {
+                    Ok((false,)) => {},
+                    Ok((true,)) => {return Err((StatusCode::LOCKED, format!("Too many failed logins. Try again in {LOCKOUT_MINUTES} minutes")))},
+                    Err(e) => {return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Something went wrong trying to log you in: {e}")))}
+                }
+            }),
+            Some(quote! {
+                // The failure that reaches the limit locks the account and starts the count over
+                if let Err(e) = sqlx::query("UPDATE users SET
+                    failed_logins = CASE WHEN failed_logins + 1 >= $2 THEN 0 ELSE failed_logins + 1 END,
+                    locked_until = CASE WHEN failed_logins + 1 >= $2 THEN now() + make_interval(mins => $3) ELSE locked_until END
+                    WHERE username = $1")
+                    .bind(user.username.clone())
+                    .bind(MAX_FAILED_LOGINS)
+                    .bind(LOCKOUT_MINUTES)
+                    .execute(&state.db)
+                    .await {
+                    return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Something went wrong trying to log you in: {e}")));
+                }
+            }),
+            Some(quote! {
+                if let Err(e) = sqlx::query("UPDATE users SET failed_logins = 0, locked_until = NULL WHERE username = $1 AND failed_logins > 0")
+                    .bind(user.username.clone())
+                    .execute(&state.db)
+                    .await {
+                    return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Something went wrong trying to log you in: {e}")));
+                }
+            }),
+            Some(quote! {
+                /// How many wrong passwords in a row lock an account, and for how long.
+                const MAX_FAILED_LOGINS: i32 = #max_failed_logins;
+                const LOCKOUT_MINUTES: i32 = #lockout_minutes;
+            }),
+        )
+    } else {
+        (None, None, None, None)
+    };
 
     quote! {
+        #lockout_consts
+
+        #instrument
         pub async fn login(
             State(state): State<AppState>,
             jar: PrivateCookieJar,
@@ -51,13 +484,40 @@ fn login_route() -> TokenStream {
                 Err(_) => return Err((StatusCode::BAD_REQUEST, "Incorrect credentials".to_string()))
             };
 
-            match verify(user.password, &res.password) {
+            #check_locked
+
+            match verify_password(user.password.clone(), res.password.clone()).await {
                 Ok(true) => {},
-                Ok(false) => {return Err((StatusCode::BAD_REQUEST, "Incorrect credentials".to_string()))},
+                Ok(false) => {
+                    #record_failure
+                    return Err((StatusCode::BAD_REQUEST, "Incorrect credentials".to_string()))
+                },
                 Err(e) => {return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Something went wrong trying to verify your password: {e}")))}
             }
 
-            let session_id = "hello world!";
+            #check_verified
+            #clear_failures
+
+            // Hashes made with older parameters are replaced while the password is at hand
+            if needs_rehash(&res.password) {
+                let rehashed = match hash_password(user.password).await {
+                    Ok(hash) => sqlx::query("UPDATE users SET password = $1 WHERE username = $2")
+                        .bind(hash)
+                        .bind(user.username.clone())
+                        .execute(&state.db)
+                        .await
+                        .map(|_| ())
+                        .map_err(|e| e.to_string()),
+                    Err(e) => Err(e),
+                };
+
+                if let Err(e) = rehashed {
+                    #log_rehash_error
+                }
+            }
+
+            // Each login gets a new random id, which replaces the user's previous session
+            let session_id = new_token();
 
             let expires_at = Utc::now().naive_local() + ChronoDuration::seconds(3600);
 
@@ -71,7 +531,7 @@ fn login_route() -> TokenStream {
                 session_id = excluded.session_id,
                 expires_at = excluded.expires_at")
                 .bind(user.username)
-                .bind(session_id)
+                .bind(&session_id)
                 .bind(expires_at)
                 .execute(&state.db)
                 .await {
@@ -81,10 +541,11 @@ fn login_route() -> TokenStream {
                 ));
             }
 
-            let cookie = Cookie::build("foo", session_id)
+            let cookie = Cookie::build("session", session_id)
                 .domain(".app.localhost")
                 .secure(true)
                 .http_only(true)
+                .same_site(SameSite::Lax)
                 .max_age(TimeDuration::seconds(3600))
                 .finish();
 
@@ -96,15 +557,62 @@ fn login_route() -> TokenStream {
     }
 }
 
-pub fn auth_routes() -> File {
-    let register = register_route();
-    let login = login_route();
+pub fn auth_routes(cfg: &Config) -> File {
+    let register = register_route(cfg);
+    let login = login_route(cfg);
+    let verify_email = cfg.email_verification.then(|| verify_email_route(cfg));
+    let password_reset = cfg.password_reset.then(|| password_reset_routes(cfg));
+
+    let email_items = sends_email(cfg).then(|| {
+        let tokens = email_token_fns(cfg);
+        let query = cfg
+            .email_verification
+            .then(|| quote! {use axum::extract::Query;});
+
+        quote! {
+            #query
+            use sha2::{Digest, Sha256};
+            use sqlx::PgConnection;
+            use std::fmt::Display;
+            use crate::mailer::Email;
+
+            #[derive(Deserialize)]
+            pub struct RegisterDetails {
+                pub username: String,
+                pub email: String,
+                pub password: String
+            }
+
+            #tokens
+        }
+    });
+
+    let csrf_items = csrf_protected(cfg).then(|| {
+        let route = csrf_route();
+
+        quote! {
+            use crate::middleware::csrf::CSRF_COOKIE;
+            use serde::Serialize;
+
+            #route
+        }
+    });
+
+    // Session ids, and email and CSRF tokens, are all made the same way
+    let new_token = quote! {
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use base64::Engine;
+
+        fn new_token() -> String {
+            URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+        }
+    };
 
     let code = quote! {
 
             use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
-    use axum_extra::extract::cookie::{Cookie, PrivateCookieJar};
-    use bcrypt::{verify, hash};
+    use axum_extra::extract::cookie::{Cookie, PrivateCookieJar, SameSite};
+    use crate::password::{hash_password, needs_rehash, verify_password};
     use serde::Deserialize;
     use chrono::{Duration as ChronoDuration, Utc};
     use time::Duration as TimeDuration;
@@ -116,15 +624,27 @@ pub fn auth_routes() -> File {
                 pub password: String
             }
 
+            #new_token
+
+            #email_items
+
+            #csrf_items
+
             #register
 
             #login
+
+            #verify_email
+
+            #password_reset
         };
 
     syn::parse_file(&code.to_string()).unwrap()
 }
 
-pub fn auth_middleware() -> File {
+pub fn auth_middleware(cfg: &Config) -> File {
+    let instrument = instrument_secrets(cfg);
+
     let code = quote! {
         use serde::{Deserialize, Serialize};
         use crate::AppState;
@@ -133,9 +653,10 @@ pub fn auth_middleware() -> File {
 
         #[derive(Clone, Deserialize, Serialize, sqlx::FromRow)]
         pub struct UserInfo {
-            user_id: i32,
+            pub user_id: i32,
         }
 
+        #instrument
         pub async fn check_authed_cookies<B>(
             State(state): State<AppState>,
             jar: PrivateCookieJar,
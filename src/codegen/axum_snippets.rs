@@ -1,15 +1,16 @@
 use crate::cli::Config;
-use crate::cli::Route;
+use crate::cli::{Orm, RateLimitStore, Route};
+use crate::codegen::csrf::csrf_protected;
+use crate::codegen::mailer::sends_email;
+use crate::codegen::payments::takes_payments;
+use crate::codegen::queries::{repository_ident, struct_ident};
+use crate::codegen::rate_limit::rate_limited;
+use crate::schema::{foreign_keys, join_tables};
 use indoc::{formatdoc, indoc};
 use proc_macro2::{Ident, Span, TokenStream};
 use quote::quote;
 
-type Snippets = (
-    Option<TokenStream>,
-    TokenStream,
-    Option<TokenStream>,
-    Option<TokenStream>,
-);
+type Snippets = (Option<TokenStream>, TokenStream, Option<TokenStream>);
 
 pub fn state_snippets(cfg: Config) -> Snippets {
     let key = if cfg.auth {
@@ -17,6 +18,8 @@ pub fn state_snippets(cfg: Config) -> Snippets {
     } else {
         None
     };
+    // A throwaway key for tests. `main` swaps it for the configured one, so sessions outlive
+    // restarts
     let keygen = if cfg.auth {
         Some(quote! {key: Key::generate()})
     } else {
@@ -34,14 +37,142 @@ pub fn state_snippets(cfg: Config) -> Snippets {
         None
     };
 
+    // Emails are only logged until `main` swaps in the configured mailer
+    let (mailer, mailer_declare) = if sends_email(&cfg) {
+        (
+            Some(quote! {pub mailer: Arc<dyn Mailer>,}),
+            Some(quote! {mailer: Arc::new(LogMailer::default()),}),
+        )
+    } else {
+        (None, None)
+    };
+
+    let (rate_limiter, rate_limiter_declare) = if rate_limited(&cfg) {
+        let limiter = match cfg.rate_limit_store {
+            RateLimitStore::Memory => quote! {MemoryRateLimiter::default()},
+            RateLimitStore::Postgres => quote! {PostgresRateLimiter { db: db.clone() }},
+        };
+
+        (
+            Some(quote! {pub rate_limiter: Arc<dyn RateLimiter>,}),
+            Some(quote! {rate_limiter: Arc::new(#limiter),}),
+        )
+    } else {
+        (None, None)
+    };
+
+    // Requests to Stripe fail until `main` swaps in a client with the configured keys
+    let (stripe, stripe_declare) = if takes_payments(&cfg) {
+        (
+            Some(quote! {pub stripe: StripeClient,}),
+            Some(quote! {stripe: StripeClient::default(),}),
+        )
+    } else {
+        (None, None)
+    };
+
+    let (orm, orm_declare) = match cfg.orm {
+        Orm::Sqlx => (None, None),
+        Orm::SeaOrm => (
+            Some(quote! {pub orm: DatabaseConnection,}),
+            Some(quote! {orm,}),
+        ),
+        Orm::Diesel => (Some(quote! {pub pool: Pool,}), Some(quote! {pool,})),
+    };
+
+    let (repositories, repositories_declare): (Vec<TokenStream>, Vec<TokenStream>) = cfg
+        .routes
+        .iter()
+        .filter(|_| cfg.repositories)
+        .map(|x| {
+            let field = repository_ident(&x.name);
+            let model = struct_ident(&x.name);
+            let trait_name = Ident::new(&format!("{model}Repository"), Span::call_site());
+            let postgres = Ident::new(&format!("Postgres{model}Repository"), Span::call_site());
+
+            (
+                quote! {pub #field: Arc<dyn #trait_name>,},
+                quote! {#field: Arc::new(#postgres { db: db.clone() }),},
+            )
+        })
+        .unzip();
+
+    let interact = (cfg.orm == Orm::Diesel).then(|| {
+        quote! {
+            impl AppState {
+                /// Runs a blocking Diesel query on a pooled connection.
+                pub async fn interact<F, T, E>(&self, f: F) -> Result<T, String>
+                where
+                    F: FnOnce(&mut PgConnection) -> Result<T, E> + Send + 'static,
+                    T: Send + 'static,
+                    E: ToString + Send + 'static,
+                {
+                    let conn = self.pool.get().await.map_err(|e| e.to_string())?;
+
+                    conn.interact(f)
+                        .await
+                        .map_err(|e| e.to_string())?
+                        .map_err(|e| e.to_string())
+                }
+            }
+        }
+    });
+
+    // SeaORM's connection wraps the sqlx pool, while Diesel has a pool of its own
+    let (new_params, new_args, orm_connect, pool_connect) = match cfg.orm {
+        Orm::Sqlx => (quote! {db: PgPool}, quote! {db}, None, None),
+        Orm::SeaOrm => (
+            quote! {db: PgPool},
+            quote! {db},
+            Some(quote! {
+                let orm = SqlxPostgresConnector::from_sqlx_postgres_pool(db.clone());
+            }),
+            None,
+        ),
+        Orm::Diesel => (
+            quote! {db: PgPool, pool: Pool},
+            quote! {db, pool},
+            None,
+            Some(quote! {
+                let pool = Pool::builder(Manager::new(db_url, Runtime::Tokio1))
+                    .build()
+                    .map_err(|e| e.to_string())?;
+            }),
+        ),
+    };
+
     let appstate = if cfg.auth | cfg.crud {
         Some(quote! {
             #[derive(Clone)]
             pub struct AppState {
                 pub db: PgPool,
+                #orm
+                #(#repositories)*
+                #mailer
+                #rate_limiter
+                #stripe
                 #key
             }
+
+            impl AppState {
+                /// Sets up the app's state around an existing connection pool.
+                pub fn new(#new_params) -> Self {
+                    #orm_connect
+
+                    Self { #(#repositories_declare)* #rate_limiter_declare db, #orm_declare #mailer_declare #stripe_declare #keygen }
+                }
+
+                /// Connects to the database at `db_url` and sets up the app's state.
+                pub async fn connect(db_url: &str) -> Result<Self, String> {
+                    let db = PgPool::connect(db_url).await.map_err(|e| e.to_string())?;
+                    #pool_connect
+
+                    Ok(Self::new(#new_args))
+                }
+            }
+
             #keyimpl
+            #interact
         })
     } else {
         None
@@ -49,38 +180,43 @@ pub fn state_snippets(cfg: Config) -> Snippets {
 
     let with_state = if cfg.auth | cfg.crud {
         quote! {
-            .with_state(state);
-        }
-    } else {
-        quote! {
-            ;
+            .with_state(state)
         }
-    };
-
-    let state_declare = if cfg.auth | cfg.crud {
-        Some(quote! {
-            let state = AppState { db, #keygen };
-        })
     } else {
-        None
+        quote! {}
     };
 
-    let db_macro = if cfg.auth | cfg.crud {
-        Some(quote! {
+    // Diesel needs a connection string for its own pool, so the sqlx pool is created from it later
+    let db_macro = match (cfg.auth | cfg.crud, cfg.orm) {
+        (false, _) => None,
+        (true, Orm::Diesel) => Some(quote! {
+            #[shuttle_shared_db::Postgres] db_url: String,
+        }),
+        (true, _) => Some(quote! {
             #[shuttle_shared_db::Postgres] db: PgPool,
-        })
-    } else {
-        None
+        }),
     };
 
-    (appstate, with_state, state_declare, db_macro)
+    (appstate, with_state, db_macro)
 }
 
-pub fn axum_crud_routes(routes: Vec<Route>) -> (TokenStream, TokenStream, String) {
+/// The routers for each resource. Protected resources are layered with the session or API key
+/// check, and with CSRF protection, the routes that change data also need a CSRF token.
+pub fn axum_crud_routes(routes: Vec<Route>, cfg: &Config) -> (TokenStream, TokenStream, String) {
+    let csrf = csrf_protected(cfg);
+    let auth_check = if cfg.api_keys {
+        quote! {check_api_key}
+    } else {
+        quote! {check_authed_cookies}
+    };
+
     let mut routers: Vec<TokenStream> = Vec::new();
     let mut nest: Vec<TokenStream> = Vec::new();
     let mut useitems: String = String::new();
 
+    let keys = foreign_keys(&routes);
+    let joins = join_tables(&routes);
+
     if !routes.is_empty() {
         for route in routes {
             let route_name = route.name;
@@ -92,16 +228,77 @@ pub fn axum_crud_routes(routes: Vec<Route>) -> (TokenStream, TokenStream, String
             let delete_route = Ident::new(&format!("delete_{route_name}_by_id"), Span::call_site());
             let route_location = format!("/{route_name}");
 
+            // Only layers the methods added before it, so the GET routes are left out
+            let csrf_layer = (csrf && route.auth_required)
+                .then(|| quote! {.route_layer(from_fn_with_state(state.clone(), check_csrf))});
+
+            let mut nested_routes: Vec<TokenStream> = Vec::new();
+
+            for key in keys.iter().filter(|x| x.references == route_name) {
+                let child = &key.table;
+                let handler = format!("get_{child}_by_{route_name}");
+                let handler_ident = Ident::new(&handler, Span::call_site());
+                let location = format!("/:id/{child}");
+
+                nested_routes.push(quote! {.route(#location, get(#handler_ident))});
+                useitems.push_str(&format!("use crate::routes::{child}::{handler};\n"));
+            }
+
+            for join in &joins {
+                let other = if join.left == route_name {
+                    &join.right
+                } else if join.right == route_name {
+                    &join.left
+                } else {
+                    continue;
+                };
+
+                let handler = format!("get_{other}_by_{route_name}");
+                let handler_ident = Ident::new(&handler, Span::call_site());
+                let location = format!("/:id/{other}");
+
+                nested_routes.push(quote! {.route(#location, get(#handler_ident))});
+                useitems.push_str(&format!("use crate::routes::{other}::{handler};\n"));
+
+                if join.left == route_name {
+                    let link = format!("add_{other}_to_{route_name}");
+                    let unlink = format!("remove_{other}_from_{route_name}");
+                    let link_ident = Ident::new(&link, Span::call_site());
+                    let unlink_ident = Ident::new(&unlink, Span::call_site());
+                    let location = format!("/:id/{other}/:{other}_id");
+
+                    nested_routes.push(
+                        quote! {.route(#location, post(#link_ident).delete(#unlink_ident)#csrf_layer)},
+                    );
+                    useitems.push_str(&format!(
+                        "use crate::routes::{route_name}::{{{link}, {unlink}}};\n"
+                    ));
+                }
+            }
+
             let auth_middleware = if route.auth_required {
-                quote! {.layer(from_fn_with_state(state.clone(), check_authed_cookies));}
+                quote! {.layer(from_fn_with_state(state.clone(), #auth_check));}
             } else {
                 quote! {;}
             };
-            routers.push(quote! {
-                let #router_name = Router::new()
+            let crud = if let Some(csrf_layer) = &csrf_layer {
+                quote! {
+                    .route("/", post(#create_route)#csrf_layer.get(#get_all_route))
+                    .route("/:id", patch(#update_route).delete(#delete_route)#csrf_layer
+                                .get(#get_one_route))
+                }
+            } else {
+                quote! {
                     .route("/", get(#get_all_route).post(#create_route))
                     .route("/:id", get(#get_one_route).patch(#update_route)
                                 .delete(#delete_route))
+                }
+            };
+
+            routers.push(quote! {
+                let #router_name = Router::new()
+                    #crud
+                    #(#nested_routes)*
                                 #auth_middleware
             });
             nest.push(quote! {
@@ -0,0 +1,84 @@
+use crate::cli::Config;
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::File;
+
+/// The header requests send the CSRF token back in.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// The cookie holding the token a request's header has to match.
+pub const CSRF_COOKIE: &str = "csrf";
+
+/// Whether the protected routes that change data need a CSRF token.
+pub fn csrf_protected(cfg: &Config) -> bool {
+    cfg.auth && cfg.csrf
+}
+
+/// A middleware that checks the token in a request's `X-CSRF-Token` header against the one in
+/// its encrypted `csrf` cookie. Only the non-GET routes of protected resources are layered with
+/// it.
+pub fn csrf_middleware() -> File {
+    let code = quote! {
+        use axum::http::{Request, StatusCode};
+        use axum::middleware::Next;
+        use axum::response::IntoResponse;
+        use axum_extra::extract::cookie::PrivateCookieJar;
+
+        /// The header the token from `/auth/csrf` is sent back in.
+        pub const CSRF_HEADER: &str = #CSRF_HEADER;
+        /// The cookie `/auth/csrf` keeps the token in. It's encrypted, so another site can't set
+        /// one of its own.
+        pub const CSRF_COOKIE: &str = #CSRF_COOKIE;
+
+        /// Turns away requests whose `X-CSRF-Token` header doesn't match their `csrf` cookie.
+        /// Another site can make a browser send the cookie, but it can't read the token to put
+        /// in the header.
+        pub async fn check_csrf<B>(
+            jar: PrivateCookieJar,
+            req: Request<B>,
+            next: Next<B>,
+        ) -> Result<impl IntoResponse, impl IntoResponse> {
+            let expected = jar.get(CSRF_COOKIE).map(|cookie| cookie.value().to_owned());
+            let submitted = req.headers().get(CSRF_HEADER).and_then(|x| x.to_str().ok());
+
+            match (expected, submitted) {
+                (Some(expected), Some(submitted)) if tokens_match(&expected, submitted) => {
+                    Ok(next.run(req).await)
+                }
+                _ => Err((StatusCode::FORBIDDEN, "Missing or invalid CSRF token".to_string())),
+            }
+        }
+
+        /// Compares the whole of both tokens, so how long it takes doesn't give away how much
+        /// of a guess was right.
+        fn tokens_match(a: &str, b: &str) -> bool {
+            a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+        }
+    };
+
+    syn::parse_file(&code.to_string()).unwrap()
+}
+
+/// The `/auth/csrf` handler, which hands out a token and sets the cookie it's checked against.
+pub fn csrf_route() -> TokenStream {
+    quote! {
+        #[derive(Serialize)]
+        pub struct CsrfToken {
+            pub token: String
+        }
+
+        /// Issues a CSRF token to send in the `X-CSRF-Token` header of every request that
+        /// changes data.
+        pub async fn csrf_token(jar: PrivateCookieJar) -> (PrivateCookieJar, Json<CsrfToken>) {
+            let token = new_token();
+
+            let cookie = Cookie::build(CSRF_COOKIE, token.clone())
+                .path("/")
+                .secure(true)
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .finish();
+
+            (jar.add(cookie), Json(CsrfToken { token }))
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use crate::cli::Config;
+use crate::schema::{foreign_keys, join_tables, table_fields};
+use indoc::formatdoc;
+
+/// The `src/schema.rs` that `diesel print-schema` would generate once the migrations have run.
+/// Written out as text, since prettyplease leaves the insides of macros unformatted.
+pub fn schema_file(cfg: &Config) -> String {
+    let mut tables: Vec<String> = Vec::new();
+    let mut joinables: Vec<String> = Vec::new();
+    let mut names: Vec<String> = Vec::new();
+
+    for route in &cfg.routes {
+        let name = &route.name;
+
+        let columns: String = table_fields(route, &cfg.routes)
+            .iter()
+            .map(|x| format!("        {} -> {},\n", x.name, x.diesel_type()))
+            .collect();
+
+        tables.push(formatdoc! {"
+            diesel::table! {{
+                {name} (id) {{
+                    id -> Int4,
+            {columns}        created_at -> Timestamptz,
+                    last_updated -> Timestamptz,
+                }}
+            }}
+        "});
+
+        names.push(name.clone());
+    }
+
+    for join in join_tables(&cfg.routes) {
+        let name = join.name();
+        let (left, right) = (&join.left, &join.right);
+
+        tables.push(formatdoc! {"
+            diesel::table! {{
+                {name} ({left}_id, {right}_id) {{
+                    {left}_id -> Int4,
+                    {right}_id -> Int4,
+                }}
+            }}
+        "});
+
+        joinables.push(format!(
+            "diesel::joinable!({name} -> {left} ({left}_id));\n"
+        ));
+        joinables.push(format!(
+            "diesel::joinable!({name} -> {right} ({right}_id));\n"
+        ));
+
+        names.push(name);
+    }
+
+    // Diesel can't join a table to itself, so self-referencing keys are only usable as plain columns
+    for key in foreign_keys(&cfg.routes) {
+        if key.table != key.references {
+            joinables.push(format!(
+                "diesel::joinable!({} -> {} ({}));\n",
+                key.table,
+                key.references,
+                key.column()
+            ));
+        }
+    }
+
+    let mut file = String::from("// @generated automatically by Inlet.\n\n");
+    file.push_str(&tables.join("\n"));
+
+    if !joinables.is_empty() {
+        file.push('\n');
+        file.push_str(&joinables.concat());
+    }
+
+    if names.len() > 1 {
+        let names: String = names.iter().map(|x| format!("    {x},\n")).collect();
+        file.push_str(&format!(
+            "\ndiesel::allow_tables_to_appear_in_same_query!(\n{names});\n"
+        ));
+    }
+
+    file
+}
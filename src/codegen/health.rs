@@ -0,0 +1,191 @@
+use crate::cli::{Config, Layout, Orm};
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::File;
+
+/// The liveness and readiness handlers. Readiness covers the database, whether every migration
+/// the app knows about has been applied, and whatever else the app is made to depend on.
+pub fn health_file(cfg: &Config) -> File {
+    let uses_db = cfg.crud | cfg.auth;
+
+    let (state_param, state_arg, state_uses) = if uses_db {
+        (
+            Some(quote! {State(state): State<AppState>}),
+            Some(quote! {&state}),
+            Some(quote! {
+                use crate::AppState;
+                use axum::extract::State;
+            }),
+        )
+    } else {
+        (None, None, None)
+    };
+
+    let dependency_param = if uses_db {
+        quote! {_state: &AppState}
+    } else {
+        quote! {}
+    };
+
+    let (db_checks, db_fns) = if uses_db {
+        let (migrations_check, migrations_fn) = migrations_check(cfg);
+
+        (
+            Some(quote! {
+                checks.insert("database", outcome(check_database(&state).await));
+                #migrations_check
+            }),
+            Some(quote! {
+                async fn check_database(state: &AppState) -> Result<(), String> {
+                    sqlx::query("SELECT 1")
+                        .execute(&state.db)
+                        .await
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                }
+
+                #migrations_fn
+            }),
+        )
+    } else {
+        (None, None)
+    };
+
+    let code = quote! {
+        #state_uses
+        use axum::{http::StatusCode, response::IntoResponse, Json};
+        use serde::Serialize;
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize)]
+        pub struct Health {
+            pub status: &'static str,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            pub checks: BTreeMap<&'static str, String>,
+        }
+
+        /// Liveness: the process is up and handling requests.
+        pub async fn healthz() -> impl IntoResponse {
+            Json(Health {
+                status: "ok",
+                checks: BTreeMap::new(),
+            })
+        }
+
+        /// Readiness: everything the app needs is reachable, so it can be sent traffic. Responds
+        /// with a 503 and the checks that failed otherwise.
+        pub async fn readyz(#state_param) -> impl IntoResponse {
+            let mut checks = BTreeMap::new();
+            #db_checks
+
+            for (name, result) in dependency_checks(#state_arg).await {
+                checks.insert(name, outcome(result));
+            }
+
+            if checks.values().all(|x| x == "ok") {
+                (StatusCode::OK, Json(Health { status: "ok", checks }))
+            } else {
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Json(Health {
+                        status: "unavailable",
+                        checks,
+                    }),
+                )
+            }
+        }
+
+        /// Checks on anything else the app needs before it can serve traffic, like a cache or
+        /// another service. Each one shows up by name in the readiness response.
+        async fn dependency_checks(#dependency_param) -> Vec<(&'static str, Result<(), String>)> {
+            Vec::new()
+        }
+
+        fn outcome(result: Result<(), String>) -> String {
+            match result {
+                Ok(()) => "ok".to_string(),
+                Err(e) => e,
+            }
+        }
+
+        #db_fns
+    };
+
+    syn::parse_file(&code.to_string()).unwrap()
+}
+
+/// Only sqlx projects always have their migrations at hand. SeaORM and Diesel projects only embed
+/// them when they're run on startup, so they're only checked then.
+fn migrations_check(cfg: &Config) -> (Option<TokenStream>, Option<TokenStream>) {
+    let check = quote! {
+        checks.insert("migrations", outcome(check_migrations(&state).await));
+    };
+
+    let pending = quote! {
+        if pending == 0 {
+            Ok(())
+        } else {
+            Err(format!("{pending} pending migrations"))
+        }
+    };
+
+    let migrator = match (cfg.orm, cfg.layout) {
+        (Orm::Sqlx, Layout::Workspace) => quote! {use migrations::MIGRATOR;},
+        (Orm::Sqlx, Layout::Single) => quote! {
+            static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!();
+        },
+        _ => quote! {},
+    };
+
+    let body = match cfg.orm {
+        Orm::Sqlx => quote! {
+            #migrator
+
+            let applied: Vec<i64> =
+                sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+                    .fetch_all(&state.db)
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+            // Reversible migrations show up twice, once for each direction
+            let pending = MIGRATOR
+                .iter()
+                .filter(|x| !x.migration_type.is_down_migration())
+                .filter(|x| !applied.contains(&x.version))
+                .count();
+
+            #pending
+        },
+        Orm::SeaOrm if cfg.embed_migrations => quote! {
+            use migration::{Migrator, MigratorTrait};
+
+            let pending = Migrator::get_pending_migrations(&state.orm)
+                .await
+                .map_err(|e| e.to_string())?
+                .len();
+
+            #pending
+        },
+        Orm::Diesel if cfg.embed_migrations => quote! {
+            use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+
+            const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+
+            let pending = state
+                .interact(|conn| conn.pending_migrations(MIGRATIONS).map(|x| x.len()))
+                .await?;
+
+            #pending
+        },
+        _ => return (None, None),
+    };
+
+    (
+        Some(check),
+        Some(quote! {
+            async fn check_migrations(state: &AppState) -> Result<(), String> {
+                #body
+            }
+        }),
+    )
+}
@@ -0,0 +1,1277 @@
+use crate::cli::{Config, Deployment, Layout, Orm, PasswordHash, Route};
+use crate::codegen::api_keys::API_KEY_HEADER;
+use crate::codegen::csrf::{csrf_protected, CSRF_HEADER};
+use crate::codegen::mailer::sends_email;
+use crate::codegen::payments::{takes_payments, STRIPE_SIGNATURE_HEADER};
+use crate::codegen::queries::struct_ident;
+use crate::codegen::rate_limit::{rate_limited, MAX_FAILED_LOGINS};
+use crate::codegen::tenants::{ORGANIZATIONS, TENANT_HEADER};
+use crate::codegen::workspace::DOMAIN_CRATE;
+use crate::schema::{foreign_keys, join_tables, table_fields, FieldType};
+use proc_macro2::{Ident, Literal, Span, TokenStream};
+use quote::quote;
+use syn::File;
+
+/// Where the generated tests live, relative to the project root.
+pub const TESTS_FILE: &str = "tests/api.rs";
+
+/// The tests rely on `#sqlx_test` migrating each test's database from `migrations/`, which
+/// only sqlx projects have.
+pub fn has_integration_tests(cfg: &Config) -> bool {
+    cfg.orm == Orm::Sqlx || !(cfg.crud | cfg.auth)
+}
+
+/// With repositories, many-to-many links are also checked without a database, through the
+/// in-memory repositories on either side of the relation.
+pub fn tests_in_memory_links(cfg: &Config) -> bool {
+    cfg.repositories && !join_tables(&cfg.routes).is_empty()
+}
+
+/// Tests that run every generated route against the router from `app`, using a fresh database
+/// from `#sqlx_test` for each test.
+pub fn integration_tests(cfg: &Config, crate_name: &str) -> (File, String) {
+    let uses_db = cfg.crud | cfg.auth;
+    let sqlx_test = sqlx_test(cfg);
+
+    let mut useitems = if uses_db {
+        format!("use {crate_name}::{{app, AppState}};\nuse sqlx::PgPool;\n")
+    } else {
+        format!("use {crate_name}::app;\n")
+    };
+
+    if cfg.deployment == Deployment::Standalone {
+        useitems.push_str(&format!("use {crate_name}::config::Settings;\n"));
+    }
+
+    // Every test logs in first if any of the routes need it. With CSRF protection, logging in
+    // gets a token along with the cookies, and with API keys, there's a key instead
+    let protected = cfg.routes.iter().any(|x| x.auth_required);
+    let csrf = csrf_protected(cfg);
+    let session = if csrf {
+        quote! {session}
+    } else if cfg.api_keys {
+        quote! {key}
+    } else {
+        quote! {cookie}
+    };
+    let cookie = if protected {
+        quote! {Some(&#session)}
+    } else {
+        quote! {None}
+    };
+    // With email verification, logging in needs the database to mark the user as verified
+    let start = match (protected, cfg.email_verification) {
+        (false, _) => quote! {let app = test_app(db);},
+        (true, _) if cfg.api_keys => quote! {
+            let app = test_app(db.clone());
+            let key = api_key(&db, &["read", "write"]).await;
+        },
+        (true, false) => quote! {
+            let app = test_app(db);
+            let #session = log_in(&app).await;
+        },
+        (true, true) => quote! {
+            let app = test_app(db.clone());
+            let #session = log_in(&app, &db).await;
+        },
+    };
+
+    // Standalone apps are tested with the default server settings
+    let settings =
+        (cfg.deployment == Deployment::Standalone).then(|| quote! {&Settings::default()});
+
+    let test_app = if uses_db {
+        quote! {
+            fn test_app(db: PgPool) -> Router {
+                app(AppState::new(db), #settings)
+            }
+        }
+    } else {
+        quote! {
+            fn test_app() -> Router {
+                app(#settings)
+            }
+        }
+    };
+
+    // The routes that aren't part of any resource, which should all respond with a 200
+    let mut endpoints = if cfg.health {
+        vec![
+            ("liveness", cfg.health_path.as_str()),
+            ("readiness", cfg.ready_path.as_str()),
+        ]
+    } else {
+        vec![("hello_world", "/")]
+    };
+
+    if cfg.metrics {
+        endpoints.push(("metrics", "/metrics"));
+    }
+
+    let endpoints = endpoints.into_iter().map(|(name, uri)| {
+        let name = Ident::new(name, Span::call_site());
+
+        let (attr, signature, test_app) = if uses_db {
+            (
+                sqlx_test.clone(),
+                quote! {#name(db: PgPool)},
+                quote! {test_app(db)},
+            )
+        } else {
+            (
+                quote! {#[tokio::test]},
+                quote! {#name()},
+                quote! {test_app()},
+            )
+        };
+
+        quote! {
+            #attr
+            async fn #signature {
+                let app = #test_app;
+
+                let res = send(&app, "GET", #uri, None, None).await;
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+        }
+    });
+
+    let credentials = if sends_email(cfg) {
+        r#"{"username": "test", "email": "test@example.com", "password": "password"}"#
+    } else {
+        r#"{"username": "test", "password": "password"}"#
+    };
+
+    let (log_in_params, verify_user, register_and_log_in) = if cfg.email_verification {
+        (
+            quote! {app: &Router, db: &PgPool},
+            Some(quote! {
+                // The verification email is tested on its own, so the user is verified directly
+                sqlx::query("UPDATE users SET email_verified_at = now()")
+                    .execute(db)
+                    .await
+                    .unwrap();
+            }),
+            quote! {
+                let app = test_app(db.clone());
+
+                log_in(&app, &db).await;
+            },
+        )
+    } else {
+        (
+            quote! {app: &Router},
+            None,
+            quote! {
+                let app = test_app(db);
+
+                log_in(&app).await;
+            },
+        )
+    };
+
+    let (session_type, log_in_result) = if csrf {
+        (
+            quote! {Session},
+            quote! {
+                let cookie = cookie.split(';').next().unwrap().to_string();
+
+                let res = send(app, "GET", "/auth/csrf", None, None).await;
+                assert_eq!(res.status(), StatusCode::OK);
+
+                let csrf_cookie = res
+                    .headers()
+                    .get(header::SET_COOKIE)
+                    .expect("getting a CSRF token should set a cookie")
+                    .to_str()
+                    .unwrap()
+                    .split(';')
+                    .next()
+                    .unwrap()
+                    .to_string();
+
+                // The body is `{"token":"..."}`, and tokens never contain quotes
+                let body = read_body(res).await;
+                let csrf_token = body.split('"').nth(3).unwrap().to_string();
+
+                Session {
+                    cookie: format!("{cookie}; {csrf_cookie}"),
+                    csrf_token,
+                }
+            },
+        )
+    } else {
+        (
+            quote! {String},
+            quote! {cookie.split(';').next().unwrap().to_string()},
+        )
+    };
+
+    // With multi-tenancy, the tests also log in as a second user with an organization of their
+    // own
+    let credentials_value = if cfg.multi_tenant {
+        quote! {credentials}
+    } else {
+        quote! {CREDENTIALS}
+    };
+
+    let log_in_body = quote! {
+        let res = send(app, "POST", "/auth/register", Some(#credentials_value), None).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        #verify_user
+
+        let res = send(app, "POST", "/auth/login", Some(#credentials_value), None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let cookie = res
+            .headers()
+            .get(header::SET_COOKIE)
+            .expect("logging in should set a session cookie")
+            .to_str()
+            .unwrap();
+
+        #log_in_result
+    };
+
+    let log_in = if cfg.multi_tenant {
+        let args = if cfg.email_verification {
+            quote! {app, db}
+        } else {
+            quote! {app}
+        };
+        let other_credentials = credentials.replace("test", "other");
+
+        quote! {
+            const OTHER_CREDENTIALS: &str = #other_credentials;
+
+            /// Registers and logs in a user, returning their session cookie.
+            async fn log_in(#log_in_params) -> #session_type {
+                log_in_as(#args, CREDENTIALS).await
+            }
+
+            /// Registers and logs in the user with `credentials`, returning their session cookie.
+            async fn log_in_as(#log_in_params, credentials: &str) -> #session_type {
+                #log_in_body
+            }
+        }
+    } else {
+        quote! {
+            /// Registers and logs in a user, returning their session cookie.
+            async fn log_in(#log_in_params) -> #session_type {
+                #log_in_body
+            }
+        }
+    };
+
+    // Projects from before argon2id stored bcrypt hashes, which logging in moves onto argon2id
+    let bcrypt_rehash = (cfg.password_hash == PasswordHash::Argon2id).then(|| {
+        let update = if cfg.email_verification {
+            "UPDATE users SET password = $1, email_verified_at = now() WHERE username = 'test'"
+        } else {
+            "UPDATE users SET password = $1 WHERE username = 'test'"
+        };
+
+        quote! {
+            #sqlx_test
+            async fn log_in_with_bcrypt_hash(db: PgPool) {
+                let app = test_app(db.clone());
+
+                let res = send(&app, "POST", "/auth/register", Some(CREDENTIALS), None).await;
+                assert_eq!(res.status(), StatusCode::CREATED);
+
+                let hash = bcrypt::hash("password", 10).unwrap();
+                sqlx::query(#update)
+                    .bind(hash)
+                    .execute(&db)
+                    .await
+                    .unwrap();
+
+                let res = send(&app, "POST", "/auth/login", Some(CREDENTIALS), None).await;
+                assert_eq!(res.status(), StatusCode::OK);
+
+                let (password,): (String,) =
+                    sqlx::query_as("SELECT password FROM users WHERE username = 'test'")
+                        .fetch_one(&db)
+                        .await
+                        .unwrap();
+                assert!(password.starts_with("$argon2id$"));
+            }
+        }
+    });
+
+    let auth = cfg.auth.then(|| {
+        quote! {
+            const CREDENTIALS: &str = #credentials;
+
+            #log_in
+
+            #sqlx_test
+            async fn register_and_log_in(db: PgPool) {
+                #register_and_log_in
+            }
+
+            #sqlx_test
+            async fn log_in_with_wrong_password(db: PgPool) {
+                let app = test_app(db);
+
+                let res = send(&app, "POST", "/auth/register", Some(CREDENTIALS), None).await;
+                assert_eq!(res.status(), StatusCode::CREATED);
+
+                let wrong = r#"{"username": "test", "password": "wrong"}"#;
+                let res = send(&app, "POST", "/auth/login", Some(wrong), None).await;
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            }
+
+            #bcrypt_rehash
+        }
+    });
+
+    let emails = sends_email(cfg).then(|| email_tests(cfg, &settings));
+    let rate_limits = rate_limited(cfg).then(|| rate_limit_tests(cfg));
+    let api_keys = cfg.api_keys.then(|| api_key_tests(cfg));
+    let payments = takes_payments(cfg).then(|| payment_tests(cfg, &settings));
+
+    if takes_payments(cfg) {
+        useitems.push_str(&format!("use {crate_name}::stripe::StripeClient;\n"));
+        useitems.push_str("use axum::http::Uri;\n");
+    }
+
+    if takes_payments(cfg) || tests_in_memory_links(cfg) {
+        useitems.push_str("use std::sync::Mutex;\n");
+    }
+
+    let in_memory_links = tests_in_memory_links(cfg).then(|| {
+        let (tests, repositories) = in_memory_link_tests(cfg, crate_name);
+        useitems.push_str(&repositories);
+        tests
+    });
+
+    if cfg.api_keys {
+        useitems.push_str(&format!(
+            "use {crate_name}::middleware::api_key::{{generate_api_key, hash_api_key, SHOWN_PREFIX_LEN}};\n"
+        ));
+    }
+    let csrf_test = cfg
+        .routes
+        .iter()
+        .find(|x| csrf && x.auth_required)
+        .map(|route| csrf_test(route, cfg, &start));
+
+    if sends_email(cfg) {
+        useitems.push_str(&format!("use {crate_name}::mailer::LogMailer;\n"));
+    }
+
+    if sends_email(cfg) || takes_payments(cfg) || tests_in_memory_links(cfg) {
+        useitems.push_str("use std::sync::Arc;\n");
+    }
+
+    let mut tests: Vec<TokenStream> = Vec::new();
+
+    for route in &cfg.routes {
+        tests.push(crud_test(route, cfg, &start, &cookie));
+
+        if route.auth_required {
+            let name = Ident::new(&format!("{}_requires_auth", route.name), Span::call_site());
+            let uri = format!("/{}", route.name);
+            // A missing key is a 401, while the session middleware turns everyone away the same
+            let status = if cfg.api_keys {
+                quote! {StatusCode::UNAUTHORIZED}
+            } else {
+                quote! {StatusCode::FORBIDDEN}
+            };
+
+            tests.push(quote! {
+                #sqlx_test
+                async fn #name(db: PgPool) {
+                    let app = test_app(db);
+
+                    let res = send(&app, "GET", #uri, None, None).await;
+                    assert_eq!(res.status(), #status);
+                }
+            });
+        }
+    }
+
+    for join in join_tables(&cfg.routes) {
+        let (left, right) = (&join.left, &join.right);
+        let name = Ident::new(&format!("link_{right}_to_{left}"), Span::call_site());
+
+        let mut setup = create_with_parents(left, cfg, &cookie);
+        for step in create_with_parents(right, cfg, &cookie) {
+            if !setup.iter().any(|x| x.0 == step.0) {
+                setup.push(step);
+            }
+        }
+        let setup = setup.into_iter().map(|x| x.1);
+
+        let link = format!("/{left}/1/{right}/1");
+        let by_left = format!("/{left}/1/{right}");
+        let by_right = format!("/{right}/1/{left}");
+
+        tests.push(quote! {
+            #sqlx_test
+            async fn #name(db: PgPool) {
+                #start
+
+                #(#setup)*
+
+                let res = send(&app, "POST", #link, None, #cookie).await;
+                assert_eq!(res.status(), StatusCode::CREATED);
+
+                let res = send(&app, "GET", #by_left, None, #cookie).await;
+                assert_eq!(res.status(), StatusCode::OK);
+
+                let res = send(&app, "GET", #by_right, None, #cookie).await;
+                assert_eq!(res.status(), StatusCode::OK);
+
+                let res = send(&app, "DELETE", #link, None, #cookie).await;
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+        });
+    }
+
+    let (session_struct, send_session, send_headers) = if csrf {
+        (
+            Some(quote! {
+                /// The cookies from logging in and getting a CSRF token, and the token itself.
+                #[derive(Clone)]
+                struct Session {
+                    cookie: String,
+                    csrf_token: String,
+                }
+            }),
+            quote! {session: Option<&Session>,},
+            quote! {
+                if let Some(session) = session {
+                    req = req
+                        .header(header::COOKIE, &session.cookie)
+                        .header(#CSRF_HEADER, &session.csrf_token);
+                }
+            },
+        )
+    } else if cfg.api_keys {
+        (
+            None,
+            quote! {key: Option<&str>,},
+            quote! {
+                if let Some(key) = key {
+                    req = req.header(#API_KEY_HEADER, key);
+                }
+            },
+        )
+    } else {
+        (
+            None,
+            quote! {cookie: Option<&str>,},
+            quote! {
+                if let Some(cookie) = cookie {
+                    req = req.header(header::COOKIE, cookie);
+                }
+            },
+        )
+    };
+
+    let tenants = cfg
+        .multi_tenant
+        .then(|| tenant_tests(cfg, &start, &cookie, &send_session, &send_headers));
+
+    let read_body = (csrf || cfg.api_keys || takes_payments(cfg) || cfg.multi_tenant).then(|| {
+        quote! {
+            async fn read_body(res: Response) -> String {
+                let mut body = res.into_body();
+                let mut bytes = Vec::new();
+
+                while let Some(chunk) = body.data().await {
+                    bytes.extend(chunk.unwrap());
+                }
+
+                String::from_utf8(bytes).unwrap()
+            }
+        }
+    });
+    let body_data = read_body
+        .as_ref()
+        .map(|_| quote! {use axum::body::HttpBody;});
+
+    let file = quote! {
+        use axum::body::Body;
+        #body_data
+        use axum::http::{header, Request, StatusCode};
+        use axum::response::Response;
+        use axum::Router;
+        use tower::ServiceExt;
+
+        #test_app
+
+        #session_struct
+
+        /// Sends a request through the router, with an optional JSON body and session.
+        async fn send(
+            app: &Router,
+            method: &str,
+            uri: &str,
+            body: Option<&str>,
+            #send_session
+        ) -> Response {
+            let mut req = Request::builder().method(method).uri(uri);
+
+            #send_headers
+
+            let req = match body {
+                Some(body) => req
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string())),
+                None => req.body(Body::empty()),
+            };
+
+            app.clone().oneshot(req.unwrap()).await.unwrap()
+        }
+
+        #read_body
+
+        #(#endpoints)*
+
+        #auth
+
+        #emails
+
+        #rate_limits
+
+        #api_keys
+
+        #payments
+
+        #tenants
+
+        #csrf_test
+
+        #in_memory_links
+
+        #(#tests)*
+    };
+
+    (syn::parse_file(&file.to_string()).unwrap(), useitems)
+}
+
+/// Checks that a second user, who starts out in an organization of their own, can't see or
+/// change the first user's rows until they're added to the first user's organization.
+fn tenant_tests(
+    cfg: &Config,
+    start: &TokenStream,
+    cookie: &TokenStream,
+    send_session: &TokenStream,
+    send_headers: &TokenStream,
+) -> TokenStream {
+    let sqlx_test = sqlx_test(cfg);
+
+    let Some(route) = cfg.routes.iter().find(|x| !requires_itself(x, cfg)) else {
+        return quote! {};
+    };
+
+    let collection = format!("/{}", route.name);
+    let item = format!("/{}/1", route.name);
+    let body = sample_payload(route, cfg);
+    let setup = create_with_parents(&route.name, cfg, cookie)
+        .into_iter()
+        .map(|x| x.1);
+
+    let organizations = format!("/{ORGANIZATIONS}");
+    let add_member = format!("/{ORGANIZATIONS}/1/members");
+    let remove_owner = format!("/{ORGANIZATIONS}/1/members/1");
+
+    let log_in_other = if cfg.email_verification {
+        quote! {log_in_as(&app, &db, OTHER_CREDENTIALS).await}
+    } else {
+        quote! {log_in_as(&app, OTHER_CREDENTIALS).await}
+    };
+
+    quote! {
+        /// Like `send`, but acting on the organization `tenant` instead of the user's first one.
+        async fn send_to_tenant(
+            app: &Router,
+            method: &str,
+            uri: &str,
+            #send_session
+            tenant: i32,
+        ) -> Response {
+            let mut req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(#TENANT_HEADER, tenant.to_string());
+
+            #send_headers
+
+            app.clone().oneshot(req.body(Body::empty()).unwrap()).await.unwrap()
+        }
+
+        #sqlx_test
+        async fn tenant_isolation(db: PgPool) {
+            #start
+
+            #(#setup)*
+
+            let res = send(&app, "GET", #organizations, None, #cookie).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let other = #log_in_other;
+
+            let res = send(&app, "GET", #collection, None, Some(&other)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(read_body(res).await, "[]");
+
+            let res = send(&app, "PATCH", #item, #body, Some(&other)).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+            let res = send(&app, "DELETE", #item, None, Some(&other)).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+            // An organization can't be picked by someone who isn't one of its members
+            let res = send_to_tenant(&app, "GET", #item, Some(&other), 1).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+            let res = send(&app, "POST", #add_member, Some(r#"{"username": "other"}"#), #cookie).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+
+            let res = send_to_tenant(&app, "GET", #item, Some(&other), 1).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            // Only owners manage members
+            let res = send(&app, "DELETE", #remove_owner, None, Some(&other)).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        }
+    }
+}
+
+/// Tests for the emails the auth routes send, which read the tokens back from a `LogMailer`.
+fn email_tests(cfg: &Config, settings: &Option<TokenStream>) -> TokenStream {
+    let sqlx_test = sqlx_test(cfg);
+
+    let verification = cfg.email_verification.then(|| {
+        quote! {
+            #sqlx_test
+            async fn verify_email(db: PgPool) {
+                let (app, mailer) = app_with_mailer(db);
+
+                let res = send(&app, "POST", "/auth/register", Some(CREDENTIALS), None).await;
+                assert_eq!(res.status(), StatusCode::CREATED);
+
+                let res = send(&app, "POST", "/auth/login", Some(CREDENTIALS), None).await;
+                assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+                let email = mailer.sent().pop().expect("registering should send an email");
+                assert_eq!(email.to, "test@example.com");
+
+                let token = email.body.split("token=").nth(1).unwrap();
+                let token = token.split_whitespace().next().unwrap();
+                let uri = format!("/auth/verify?token={token}");
+
+                let res = send(&app, "GET", &uri, None, None).await;
+                assert_eq!(res.status(), StatusCode::OK);
+
+                // Tokens only work once
+                let res = send(&app, "GET", &uri, None, None).await;
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+                let res = send(&app, "POST", "/auth/login", Some(CREDENTIALS), None).await;
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+        }
+    });
+
+    let reset = cfg.password_reset.then(|| {
+        quote! {
+            #sqlx_test
+            async fn reset_password(db: PgPool) {
+                let (app, mailer) = app_with_mailer(db);
+
+                let res = send(&app, "POST", "/auth/register", Some(CREDENTIALS), None).await;
+                assert_eq!(res.status(), StatusCode::CREATED);
+
+                let res = send(&app, "POST", "/auth/forgot", Some(r#"{"email": "test@example.com"}"#), None).await;
+                assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+                let email = mailer.sent().pop().expect("asking for a reset should send an email");
+                let token = email.body.lines().nth(2).unwrap();
+                let reset = format!(r#"{{"token": "{token}", "password": "new password"}}"#);
+
+                let res = send(&app, "POST", "/auth/reset", Some(&reset), None).await;
+                assert_eq!(res.status(), StatusCode::OK);
+
+                // Tokens only work once
+                let res = send(&app, "POST", "/auth/reset", Some(&reset), None).await;
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+                let res = send(&app, "POST", "/auth/login", Some(CREDENTIALS), None).await;
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+                let new_credentials = r#"{"username": "test", "password": "new password"}"#;
+                let res = send(&app, "POST", "/auth/login", Some(new_credentials), None).await;
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+
+            #sqlx_test
+            async fn forgot_password_for_unknown_email(db: PgPool) {
+                let (app, mailer) = app_with_mailer(db);
+
+                let res = send(&app, "POST", "/auth/forgot", Some(r#"{"email": "nobody@example.com"}"#), None).await;
+                assert_eq!(res.status(), StatusCode::ACCEPTED);
+                assert!(mailer.sent().is_empty());
+            }
+        }
+    });
+
+    quote! {
+        /// An app whose emails are kept, so the tests can read them.
+        fn app_with_mailer(db: PgPool) -> (Router, Arc<LogMailer>) {
+            let mailer = Arc::new(LogMailer::default());
+            let state = AppState {
+                mailer: mailer.clone(),
+                ..AppState::new(db)
+            };
+
+            (app(state, #settings), mailer)
+        }
+
+        #verification
+
+        #reset
+    }
+}
+
+/// Tests for the lockout after too many wrong passwords, and the limit on requests from one
+/// address. Requests sent through the router directly all come from the same unknown address.
+fn rate_limit_tests(cfg: &Config) -> TokenStream {
+    let sqlx_test = sqlx_test(cfg);
+    let max_failed_logins = Literal::i32_unsuffixed(MAX_FAILED_LOGINS);
+
+    quote! {
+        #sqlx_test
+        async fn lock_out_after_failed_logins(db: PgPool) {
+            let app = test_app(db);
+
+            let res = send(&app, "POST", "/auth/register", Some(CREDENTIALS), None).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+
+            let wrong = r#"{"username": "test", "password": "wrong"}"#;
+
+            for _ in 0..#max_failed_logins {
+                let res = send(&app, "POST", "/auth/login", Some(wrong), None).await;
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            }
+
+            // Even the right password is turned away until the lockout ends
+            let res = send(&app, "POST", "/auth/login", Some(CREDENTIALS), None).await;
+            assert_eq!(res.status(), StatusCode::LOCKED);
+        }
+
+        #sqlx_test
+        async fn rate_limit_by_address(db: PgPool) {
+            let app = test_app(db);
+
+            for i in 0..20 {
+                let credentials = format!(r#"{{"username": "user{i}", "password": "password"}}"#);
+                let res = send(&app, "POST", "/auth/login", Some(&credentials), None).await;
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            }
+
+            let credentials = r#"{"username": "user20", "password": "password"}"#;
+            let res = send(&app, "POST", "/auth/login", Some(credentials), None).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+    }
+}
+
+/// Tests for the scopes keys are checked against, and for managing keys through the admin routes.
+fn api_key_tests(cfg: &Config) -> TokenStream {
+    let sqlx_test = sqlx_test(cfg);
+
+    let read_only = cfg.routes.iter().find(|x| x.auth_required).map(|route| {
+        let collection = format!("/{}", route.name);
+        let body = sample_payload(route, cfg);
+
+        quote! {
+            #sqlx_test
+            async fn read_only_key(db: PgPool) {
+                let app = test_app(db.clone());
+                let key = api_key(&db, &["read"]).await;
+
+                let res = send(&app, "GET", #collection, None, Some(&key)).await;
+                assert_eq!(res.status(), StatusCode::OK);
+
+                let res = send(&app, "POST", #collection, #body, Some(&key)).await;
+                assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            }
+        }
+    });
+
+    quote! {
+        /// Adds a key with the given scopes straight to the database, returning it.
+        async fn api_key(db: &PgPool, scopes: &[&str]) -> String {
+            let key = generate_api_key();
+
+            sqlx::query("INSERT INTO api_keys (name, prefix, key_hash, scopes) VALUES ('test', $1, $2, $3)")
+                .bind(&key[..SHOWN_PREFIX_LEN])
+                .bind(hash_api_key(&key))
+                .bind(scopes)
+                .execute(db)
+                .await
+                .unwrap();
+
+            key
+        }
+
+        #read_only
+
+        #sqlx_test
+        async fn manage_api_keys(db: PgPool) {
+            let app = test_app(db.clone());
+            let admin = api_key(&db, &["admin"]).await;
+
+            // Only admin keys can manage keys
+            let key = api_key(&db, &["read", "write"]).await;
+            let res = send(&app, "GET", "/admin/api-keys", None, Some(&key)).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+            let new_key = r#"{"name": "ci", "scopes": ["read"]}"#;
+            let res = send(&app, "POST", "/admin/api-keys", Some(new_key), Some(&admin)).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+
+            let created: serde_json::Value = serde_json::from_str(&read_body(res).await).unwrap();
+            let created_key = created["key"].as_str().unwrap();
+            let uri = format!("/admin/api-keys/{}", created["id"]);
+
+            let res = send(&app, "GET", "/admin/api-keys", None, Some(&admin)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            // The new key only has the `read` scope, so it's turned away until it's revoked, and
+            // then it's not recognised at all
+            let res = send(&app, "GET", "/admin/api-keys", None, Some(created_key)).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+            let res = send(&app, "DELETE", &uri, None, Some(&admin)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let res = send(&app, "GET", "/admin/api-keys", None, Some(created_key)).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+}
+
+/// Tests for checking out and the webhook, against a mock of Stripe's API on a local port.
+fn payment_tests(cfg: &Config, settings: &Option<TokenStream>) -> TokenStream {
+    let sqlx_test = sqlx_test(cfg);
+    let session = if csrf_protected(cfg) {
+        quote! {session}
+    } else {
+        quote! {cookie}
+    };
+    let log_in = if cfg.email_verification {
+        quote! {log_in(&app, &db).await}
+    } else {
+        quote! {log_in(&app).await}
+    };
+
+    quote! {
+        const WEBHOOK_SECRET: &str = "whsec_test";
+        const CHECKOUT: &str = r#"{"success_url": "https://example.com/done", "cancel_url": "https://example.com/cancelled"}"#;
+        const PORTAL: &str = r#"{"return_url": "https://example.com/account"}"#;
+        const SUBSCRIPTION_UPDATED: &str = r#"{
+            "id": "evt_test",
+            "type": "customer.subscription.updated",
+            "data": {"object": {
+                "id": "sub_test",
+                "customer": "cus_test",
+                "status": "active",
+                "current_period_end": 1893456000,
+                "items": {"data": [{"price": {"id": "price_test"}}]}
+            }}
+        }"#;
+
+        /// Serves a stand-in for the parts of Stripe's API the app uses, returning its address
+        /// and the paths of the requests it gets.
+        async fn mock_stripe() -> (String, Arc<Mutex<Vec<String>>>) {
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let seen = requests.clone();
+
+            let mock = Router::new().fallback(move |uri: Uri| async move {
+                seen.lock().unwrap().push(uri.path().to_string());
+
+                match uri.path() {
+                    "/v1/customers" => (StatusCode::OK, r#"{"id": "cus_test"}"#),
+                    "/v1/checkout/sessions" => (StatusCode::OK, r#"{"id": "cs_test", "url": "https://checkout.stripe.com/c/pay/cs_test"}"#),
+                    "/v1/billing_portal/sessions" => (StatusCode::OK, r#"{"id": "bps_test", "url": "https://billing.stripe.com/p/session/test"}"#),
+                    _ => (StatusCode::NOT_FOUND, r#"{"error": {"message": "Unrecognized request URL"}}"#),
+                }
+            });
+
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(mock.into_make_service()));
+
+            (format!("http://{address}"), requests)
+        }
+
+        /// An app whose Stripe client sends its requests to `api_base`.
+        fn app_with_stripe(db: PgPool, api_base: &str) -> Router {
+            let mut stripe = StripeClient::new("sk_test", WEBHOOK_SECRET, "price_test", "https://example.com");
+            stripe.api_base = api_base.to_string();
+
+            let state = AppState {
+                stripe,
+                ..AppState::new(db)
+            };
+
+            app(state, #settings)
+        }
+
+        /// Sends an event to the webhook, signed with `secret`.
+        async fn send_webhook(app: &Router, event: &str, secret: &str) -> Response {
+            let signature = StripeClient::new("", secret, "", "")
+                .sign_webhook(event.as_bytes(), chrono::Utc::now().timestamp());
+
+            let req = Request::builder()
+                .method("POST")
+                .uri("/payments/webhook")
+                .header(#STRIPE_SIGNATURE_HEADER, signature)
+                .body(Body::from(event.to_string()));
+
+            app.clone().oneshot(req.unwrap()).await.unwrap()
+        }
+
+        #sqlx_test
+        async fn checkout_and_portal(db: PgPool) {
+            let (api_base, requests) = mock_stripe().await;
+            let app = app_with_stripe(db.clone(), &api_base);
+            let #session = #log_in;
+
+            // There's no customer to manage until the first checkout
+            let res = send(&app, "POST", "/payments/portal", Some(PORTAL), Some(&#session)).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+            // Users are only ever sent back to the app's own origin
+            let elsewhere = CHECKOUT.replace("https://example.com/done", "https://example.com.evil.test/done");
+            let res = send(&app, "POST", "/payments/checkout", Some(&elsewhere), Some(&#session)).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+            let elsewhere = r#"{"return_url": "http://example.com/account"}"#;
+            let res = send(&app, "POST", "/payments/portal", Some(elsewhere), Some(&#session)).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+            for _ in 0..2 {
+                let res = send(&app, "POST", "/payments/checkout", Some(CHECKOUT), Some(&#session)).await;
+                assert_eq!(res.status(), StatusCode::OK);
+                assert!(read_body(res).await.contains("https://checkout.stripe.com/c/pay/cs_test"));
+            }
+
+            let res = send(&app, "POST", "/payments/portal", Some(PORTAL), Some(&#session)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(read_body(res).await.contains("https://billing.stripe.com/p/session/test"));
+
+            // The customer from the first checkout is reused
+            let requests = requests.lock().unwrap().clone();
+            assert_eq!(
+                requests,
+                ["/v1/customers", "/v1/checkout/sessions", "/v1/checkout/sessions", "/v1/billing_portal/sessions"]
+            );
+        }
+
+        #sqlx_test
+        async fn checkout_requires_auth(db: PgPool) {
+            let app = test_app(db);
+
+            let res = send(&app, "POST", "/payments/checkout", Some(CHECKOUT), None).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        }
+
+        #sqlx_test
+        async fn webhook_records_events_once(db: PgPool) {
+            let (api_base, _) = mock_stripe().await;
+            let app = app_with_stripe(db.clone(), &api_base);
+            #log_in;
+
+            sqlx::query("UPDATE users SET stripe_customer_id = 'cus_test'")
+                .execute(&db)
+                .await
+                .unwrap();
+
+            let res = send_webhook(&app, SUBSCRIPTION_UPDATED, "whsec_wrong").await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+            // Stripe can deliver an event more than once
+            for _ in 0..2 {
+                let res = send_webhook(&app, SUBSCRIPTION_UPDATED, WEBHOOK_SECRET).await;
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+
+            let (events,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM payments")
+                .fetch_one(&db)
+                .await
+                .unwrap();
+            assert_eq!(events, 1);
+
+            let (status, price_id): (String, Option<String>) =
+                sqlx::query_as("SELECT status, price_id FROM subscriptions WHERE stripe_subscription_id = 'sub_test'")
+                    .fetch_one(&db)
+                    .await
+                    .unwrap();
+            assert_eq!(status, "active");
+            assert_eq!(price_id.as_deref(), Some("price_test"));
+        }
+    }
+}
+
+/// Checks that a protected route turns away changes with the wrong CSRF token, but not reads.
+fn csrf_test(route: &Route, cfg: &Config, start: &TokenStream) -> TokenStream {
+    let sqlx_test = sqlx_test(cfg);
+    let collection = format!("/{}", route.name);
+    let body = sample_payload(route, cfg);
+
+    quote! {
+        #sqlx_test
+        async fn reject_wrong_csrf_token(db: PgPool) {
+            #start
+
+            let forged = Session {
+                csrf_token: "forged".to_string(),
+                ..session.clone()
+            };
+
+            let res = send(&app, "POST", #collection, #body, Some(&forged)).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+            let res = send(&app, "GET", #collection, None, Some(&forged)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+    }
+}
+
+/// In a workspace, the migrations belong to another crate, so the tests use its migrator.
+fn sqlx_test(cfg: &Config) -> TokenStream {
+    match cfg.layout {
+        Layout::Single => quote! {#[sqlx::test]},
+        Layout::Workspace => quote! {#[sqlx::test(migrator = "migrations::MIGRATOR")]},
+    }
+}
+
+/// Creates a resource, then reads, updates and deletes it.
+fn crud_test(
+    route: &Route,
+    cfg: &Config,
+    start: &TokenStream,
+    cookie: &TokenStream,
+) -> TokenStream {
+    let name = Ident::new(&format!("{}_crud", route.name), Span::call_site());
+    let sqlx_test = sqlx_test(cfg);
+    let collection = format!("/{}", route.name);
+    let item = format!("/{}/1", route.name);
+
+    let body = sample_payload(route, cfg);
+
+    let mut setup = create_with_parents(&route.name, cfg, cookie);
+    // The resource itself is the last thing created
+    setup.pop();
+    let setup = setup.into_iter().map(|x| x.1);
+
+    if requires_itself(route, cfg) {
+        // A row that has to point at another row of the same table can never be the first one
+        return quote! {
+            #sqlx_test
+            async fn #name(db: PgPool) {
+                #start
+
+                let res = send(&app, "GET", #collection, None, #cookie).await;
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+        };
+    }
+
+    let by_parent: Vec<TokenStream> = foreign_keys(&cfg.routes)
+        .into_iter()
+        .filter(|x| x.table == route.name)
+        .map(|x| {
+            let uri = format!("/{}/1/{}", x.references, route.name);
+
+            quote! {
+                let res = send(&app, "GET", #uri, None, #cookie).await;
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+        })
+        .collect();
+
+    quote! {
+        #sqlx_test
+        async fn #name(db: PgPool) {
+            #start
+
+            #(#setup)*
+
+            let res = send(&app, "POST", #collection, #body, #cookie).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+
+            let res = send(&app, "GET", #collection, None, #cookie).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let res = send(&app, "GET", #item, None, #cookie).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            #(#by_parent)*
+
+            let res = send(&app, "PATCH", #item, #body, #cookie).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let res = send(&app, "DELETE", #item, None, #cookie).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+    }
+}
+
+/// The requests that create a row of `tablename`, preceded by the rows its required foreign
+/// keys point at. Each table is only created once, so every row ends up with an id of 1.
+fn create_with_parents(
+    tablename: &str,
+    cfg: &Config,
+    cookie: &TokenStream,
+) -> Vec<(String, TokenStream)> {
+    let mut steps: Vec<(String, TokenStream)> = Vec::new();
+
+    for key in foreign_keys(&cfg.routes) {
+        if key.table == tablename && key.references != tablename && !key.field().nullable {
+            for step in create_with_parents(&key.references, cfg, cookie) {
+                if !steps.iter().any(|x| x.0 == step.0) {
+                    steps.push(step);
+                }
+            }
+        }
+    }
+
+    let route = cfg.routes.iter().find(|x| x.name == tablename).unwrap();
+    let body = sample_payload(route, cfg);
+    let uri = format!("/{tablename}");
+
+    steps.push((
+        tablename.to_string(),
+        quote! {
+            let res = send(&app, "POST", #uri, #body, #cookie).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        },
+    ));
+
+    steps
+}
+
+/// Whether every row of a resource has to reference another row of the same resource.
+fn requires_itself(route: &Route, cfg: &Config) -> bool {
+    foreign_keys(&cfg.routes)
+        .iter()
+        .any(|x| x.table == route.name && x.references == route.name && !x.field().nullable)
+}
+
+/// Links a row through the in-memory repository on the left of each many-to-many relation and
+/// finds it through the one on the right, which only works when both are given the same links.
+/// Also returns the imports of the repositories involved.
+fn in_memory_link_tests(cfg: &Config, crate_name: &str) -> (TokenStream, String) {
+    let module = match cfg.layout {
+        Layout::Single => format!("{crate_name}::repositories"),
+        Layout::Workspace => DOMAIN_CRATE.to_string(),
+    };
+
+    let mut useitems = String::new();
+    let mut imported: Vec<String> = Vec::new();
+    let mut tests = Vec::new();
+
+    for join in join_tables(&cfg.routes) {
+        for name in [&join.left, &join.right] {
+            if !imported.contains(name) {
+                let model = struct_ident(name);
+                useitems.push_str(&format!(
+                    "use {module}::{name}::{{InMemory{model}Repository, {model}Repository}};\n"
+                ));
+                imported.push(name.clone());
+            }
+        }
+
+        let Some(right_route) = cfg.routes.iter().find(|x| x.name == join.right) else {
+            continue;
+        };
+
+        let links = Ident::new(&join.name(), Span::call_site());
+        let left = Ident::new(&join.left, Span::call_site());
+        let right = Ident::new(&join.right, Span::call_site());
+        let left_repository = Ident::new(
+            &format!("InMemory{}Repository", struct_ident(&join.left)),
+            Span::call_site(),
+        );
+        let right_repository = Ident::new(
+            &format!("InMemory{}Repository", struct_ident(&join.right)),
+            Span::call_site(),
+        );
+        let link = Ident::new(&format!("add_{}", join.right), Span::call_site());
+        let list = Ident::new(&format!("get_by_{}", join.left), Span::call_site());
+        let name = Ident::new(
+            &format!("in_memory_{}_links", join.name()),
+            Span::call_site(),
+        );
+        let payload =
+            sample_json(right_route, cfg).map(|json| quote! {serde_json::from_str(#json).unwrap()});
+
+        tests.push(quote! {
+            #[tokio::test]
+            async fn #name() {
+                let links = Arc::new(Mutex::new(Vec::new()));
+                let #left = #left_repository { #links: links.clone(), ..Default::default() };
+                let #right = #right_repository { #links: links, ..Default::default() };
+
+                #right.create(#payload).await.unwrap();
+                #left.#link(1, 1).await.unwrap();
+
+                let linked = #right.#list(1).await.unwrap();
+                assert_eq!(linked.len(), 1);
+                assert_eq!(linked[0].id, 1);
+            }
+        });
+    }
+
+    (quote! {#(#tests)*}, useitems)
+}
+
+/// A JSON payload with a value for every field, or `None` for resources without any fields.
+/// Foreign keys point at the row with an id of 1, or are left empty if they can be.
+fn sample_payload(route: &Route, cfg: &Config) -> TokenStream {
+    match sample_json(route, cfg) {
+        Some(payload) => quote! {Some(#payload)},
+        None => quote! {None},
+    }
+}
+
+fn sample_json(route: &Route, cfg: &Config) -> Option<String> {
+    let fields = table_fields(route, &cfg.routes);
+
+    if fields.is_empty() {
+        return None;
+    }
+
+    let optional_keys: Vec<String> = foreign_keys(&cfg.routes)
+        .into_iter()
+        .filter(|x| x.table == route.name && x.field().nullable)
+        .map(|x| x.column())
+        .collect();
+
+    let values: Vec<String> = fields
+        .iter()
+        .map(|x| {
+            let value = match x.ty {
+                _ if optional_keys.contains(&x.name) => "null",
+                FieldType::Text => "\"example\"",
+                FieldType::Integer | FieldType::BigInt => "1",
+                FieldType::Float => "1.5",
+                FieldType::Boolean => "true",
+                FieldType::Timestamp => "\"2024-01-01T00:00:00Z\"",
+            };
+
+            format!("\"{}\": {value}", x.name)
+        })
+        .collect();
+
+    Some(format!("{{{}}}", values.join(", ")))
+}
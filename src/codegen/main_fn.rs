@@ -1,29 +1,83 @@
-use crate::codegen::axum_auth::auth_router;
+use crate::codegen::api_keys::admin_router;
+use crate::codegen::axum_auth::{auth_handlers, auth_router};
 use proc_macro2::{Ident, Span, TokenStream};
 use quote::quote;
 use syn::File;
 
-use crate::cli::{Config, Route};
+use crate::cli::{Config, Deployment, Layout, Orm, RateLimitStore, Route};
 use crate::codegen::axum_snippets;
-use crate::codegen::queries::QueryGen;
+use crate::codegen::csrf::csrf_protected;
+use crate::codegen::mailer::sends_email;
+use crate::codegen::metrics::METRICS_MODULE;
+use crate::codegen::payments::{payments_router, takes_payments};
+use crate::codegen::queries::{payload_ident, struct_ident, QueryGen};
+use crate::codegen::rate_limit::rate_limited;
+use crate::codegen::tenants::{organizations_router, ORGANIZATIONS, TENANT_COLUMN};
+use crate::codegen::workspace::{DB_CRATE, DOMAIN_CRATE};
+use crate::schema::{join_tables, table_fields, FieldType, JoinTable};
+
+/// The library half of a generated project: `AppState`, the route and middleware modules, and
+/// `app`, which builds the router so that both the entrypoint and the tests can use it.
+pub fn lib_function(cfg: Config) -> (File, String) {
+    let routes = cfg.routes.clone();
+    let (appstate, with_state, _) = axum_snippets::state_snippets(cfg.clone());
+    let (routers, crud_nest, mut useitems) = axum_snippets::axum_crud_routes(routes.clone(), &cfg);
+
+    let payments = takes_payments(&cfg);
+
+    if routes.iter().any(|x| x.auth_required) || rate_limited(&cfg) || cfg.api_keys || payments {
+        useitems.push_str("use axum::middleware::from_fn_with_state;\n");
+    }
 
-pub fn main_function(cfg: Config, routes: Vec<Route>) -> (File, String) {
-    let (appstate, with_state, state_declare, dbmacro) = axum_snippets::state_snippets(cfg.clone());
-    let (routers, crud_nest, mut useitems) = axum_snippets::axum_crud_routes(routes.clone());
+    if cfg.api_keys {
+        useitems.push_str("use axum::routing::delete;\n");
+        useitems.push_str("use crate::middleware::api_key::{check_admin_key, check_api_key};\n");
+        useitems.push_str(
+            "use crate::routes::api_keys::{create_api_key, list_api_keys, revoke_api_key};\n",
+        );
+    } else if routes.iter().any(|x| x.auth_required) || payments {
+        useitems.push_str("use crate::middleware::auth::check_authed_cookies;\n");
 
-    let secretsmacro = if cfg.secrets {
-        Some(quote! {#[shuttle_secrets::Secrets] secrets: SecretStore,})
-    } else {
-        None
-    };
+        if csrf_protected(&cfg) {
+            useitems.push_str("use crate::middleware::csrf::check_csrf;\n");
+        }
 
-    if routes.iter().any(|x| x.auth_required == true) {
-        useitems.push_str("use axum::middleware::from_fn_with_state;\n");
-        useitems.push_str("use crate::middleware::auth::check_authed_cookies;\n");
-        
+        // The routes that need a token are registered by method, so the layer skips GETs
+        if csrf_protected(&cfg) && routes.iter().any(|x| x.auth_required) {
+            useitems.push_str("use axum::routing::patch;\n");
+        }
+    }
+
+    if cfg.multi_tenant {
+        useitems.push_str("use axum::routing::delete;\n");
+        useitems.push_str(
+            "use crate::routes::organizations::{add_member, create_organization, list_organizations, remove_member};\n",
+        );
     }
 
-    let auth_router = if cfg.auth { Some(auth_router()) } else { None };
+    if payments {
+        useitems.push_str("pub mod stripe;\n");
+        useitems.push_str("use crate::stripe::StripeClient;\n");
+        useitems.push_str("use crate::routes::payments::{checkout, portal, stripe_webhook};\n");
+    }
+
+    if rate_limited(&cfg) {
+        let limiter = match cfg.rate_limit_store {
+            RateLimitStore::Memory => "MemoryRateLimiter",
+            RateLimitStore::Postgres => "PostgresRateLimiter",
+        };
+
+        useitems.push_str("pub mod rate_limit;\n");
+        useitems.push_str(&format!(
+            "use crate::rate_limit::{{rate_limit, {limiter}, RateLimiter}};\n"
+        ));
+    }
+
+    let auth_router = if cfg.auth {
+        Some(auth_router(&cfg))
+    } else {
+        None
+    };
 
     let auth_nest = if cfg.auth {
         Some(quote! {.nest("/auth", auth_router)})
@@ -31,50 +85,569 @@ pub fn main_function(cfg: Config, routes: Vec<Route>) -> (File, String) {
         None
     };
 
-    if cfg.crud | cfg.auth {
+    let (payments_router, payments_nest) = if payments {
+        (
+            Some(payments_router(&cfg)),
+            Some(quote! {.nest("/payments", payments_router)}),
+        )
+    } else {
+        (None, None)
+    };
+
+    let (organizations_router, organizations_nest) = if cfg.multi_tenant {
+        let location = format!("/{ORGANIZATIONS}");
+
+        (
+            Some(organizations_router(&cfg)),
+            Some(quote! {.nest(#location, organizations_router)}),
+        )
+    } else {
+        (None, None)
+    };
+
+    let (admin_router, admin_nest) = if cfg.api_keys {
+        (
+            Some(admin_router()),
+            Some(quote! {.nest("/admin", admin_router)}),
+        )
+    } else {
+        (None, None)
+    };
+
+    let uses_db = cfg.crud | cfg.auth;
+
+    if uses_db {
         useitems.push_str("use sqlx::PgPool;\n");
+    }
+
+    // Only the auth routes, many-to-many links and CSRF protected routes are registered with
+    // `post` directly
+    if cfg.auth || !join_tables(&cfg.routes).is_empty() {
         useitems.push_str("use axum::routing::post;\n");
     }
 
     if cfg.auth {
-        useitems.push_str("use crate::routes::auth::{login, register};\n");
+        useitems.push_str(&format!(
+            "use crate::routes::auth::{{{}}};\n",
+            auth_handlers(&cfg).join(", ")
+        ));
         useitems.push_str("use axum_extra::extract::cookie::Key;\n");
         useitems.push_str("use axum::extract::FromRef;\n");
-        useitems.push_str("mod middleware;\n");
+        useitems.push_str("pub mod middleware;\n");
+        useitems.push_str("pub mod password;\n");
     }
 
-    if cfg.secrets {
-        useitems.push_str("use shuttle_secrets::SecretStore;\n");
+    if cfg.api_keys {
+        useitems.push_str("pub mod middleware;\n");
     }
 
-    let main = quote! {
+    if uses_db && cfg.orm == Orm::SeaOrm {
+        useitems.push_str("use sea_orm::{DatabaseConnection, SqlxPostgresConnector};\n");
+    }
+
+    if uses_db && cfg.orm == Orm::Diesel {
+        useitems.push_str("use deadpool_diesel::postgres::{Manager, Pool, Runtime};\n");
+        useitems.push_str("use diesel::PgConnection;\n");
+    }
+
+    if cfg.crud && cfg.orm == Orm::SeaOrm {
+        useitems.push_str("pub mod entities;\n");
+    }
+
+    if cfg.crud && cfg.orm == Orm::Diesel {
+        useitems.push_str("pub mod schema;\n");
+    }
+
+    if cfg.repositories || sends_email(&cfg) || rate_limited(&cfg) {
+        useitems.push_str("use std::sync::Arc;\n");
+    }
+
+    if sends_email(&cfg) {
+        useitems.push_str("pub mod mailer;\n");
+        useitems.push_str("use crate::mailer::{LogMailer, Mailer};\n");
+    }
+
+    if cfg.repositories {
+        // In a workspace, the traits and their Postgres implementations have crates of their own
+        let (domain, db) = match cfg.layout {
+            Layout::Single => {
+                useitems.push_str("pub mod repositories;\n");
+                ("crate::repositories", "crate::repositories")
+            }
+            Layout::Workspace => (DOMAIN_CRATE, DB_CRATE),
+        };
+
+        for route in &cfg.routes {
+            let model = struct_ident(&route.name);
+            useitems.push_str(&format!(
+                "use {domain}::{}::{model}Repository;\nuse {db}::{}::Postgres{model}Repository;\n",
+                route.name, route.name
+            ));
+        }
+    }
+
+    if cfg.tracing {
+        useitems.push_str("use axum::{body::Body, http::Request};\n");
+        useitems.push_str(
+            "use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};\n",
+        );
+        useitems.push_str("use tower_http::trace::TraceLayer;\n");
+    }
+
+    if cfg.metrics {
+        useitems.push_str(&format!("pub mod {METRICS_MODULE};\n"));
+        useitems.push_str(&format!(
+            "use crate::{METRICS_MODULE}::{{recorder_handle, render_metrics, track_metrics}};\n"
+        ));
+        useitems.push_str("use axum::middleware::from_fn;\n");
+    }
+
+    if cfg.health {
+        useitems.push_str("pub mod health;\n");
+        useitems.push_str("use crate::health::{healthz, readyz};\n");
+    }
+
+    // The health endpoints take the place of the placeholder route
+    let (hello_route, hello_world) = if cfg.health {
+        (None, None)
+    } else {
+        (
+            Some(quote! {.route("/", get(hello_world))}),
+            Some(quote! {
+                pub async fn hello_world() -> &'static str {
+                    "Hello world!"
+                }
+            }),
+        )
+    };
+
+    // These sit outside every resource's router, so they never need a session
+    let health_routes = cfg.health.then(|| {
+        let (health_path, ready_path) = (&cfg.health_path, &cfg.ready_path);
+
+        quote! {
+            .route(#health_path, get(healthz))
+            .route(#ready_path, get(readyz))
+        }
+    });
+
+    let install_recorder = cfg.metrics.then(|| {
+        quote! {
+            // Installed up front, so the first requests aren't lost
+            recorder_handle();
+        }
+    });
+
+    // `/metrics` is added after the route layer so scrapes aren't counted, and sits outside
+    // every resource's router so it never needs a session
+    let metrics = cfg.metrics.then(|| {
+        quote! {
+            .route_layer(from_fn(track_metrics))
+            .route("/metrics", get(render_metrics))
+        }
+    });
+
+    // Layers added later wrap the earlier ones, so each request gets an id before its span is
+    // created, and the id is copied onto the response on the way out
+    let trace_layers = cfg.tracing.then(|| {
+        quote! {
+            .layer(PropagateRequestIdLayer::x_request_id())
+            .layer(TraceLayer::new_for_http().make_span_with(|req: &Request<Body>| {
+                let request_id = req
+                    .headers()
+                    .get("x-request-id")
+                    .and_then(|x| x.to_str().ok())
+                    .unwrap_or_default();
+
+                tracing::info_span!("request", method = %req.method(), uri = %req.uri(), request_id)
+            }))
+            .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        }
+    });
+
+    let standalone = cfg.deployment == Deployment::Standalone;
+
+    // Standalone apps keep their server settings in the config module, and Shuttle apps their
+    // secrets
+    if standalone || cfg.secrets {
+        useitems.push_str("pub mod config;\n");
+    }
+
+    if standalone {
+        useitems.push_str("use crate::config::Settings;\n");
+        useitems.push_str("use axum::extract::DefaultBodyLimit;\n");
+        useitems.push_str("use tower_http::limit::RequestBodyLimitLayer;\n");
+        useitems.push_str("use tower_http::timeout::TimeoutLayer;\n");
+    }
+
+    // Shuttle runs the server itself, so only standalone builds are configured here. axum's own
+    // 2MB limit is lifted so the configured one is the only limit
+    let server_layers = standalone.then(|| {
+        quote! {
+            .layer(DefaultBodyLimit::disable())
+            .layer(RequestBodyLimitLayer::new(settings.body_limit_bytes))
+            .layer(TimeoutLayer::new(settings.request_timeout()))
+            .layer(settings.cors_layer())
+        }
+    });
+
+    let mut params = Vec::new();
+
+    if uses_db {
+        params.push(quote! {state: AppState});
+    }
+
+    if standalone {
+        params.push(quote! {settings: &Settings});
+    }
+
+    let lib = quote! {
         use axum::{routing::get, Router};
-        mod routes;
+        pub mod routes;
 
         #appstate
 
-        #[shuttle_runtime::main]
-        pub async fn main(
-            #dbmacro
-            #secretsmacro
-        ) -> shuttle_axum::ShuttleAxum {
-            #state_declare
-
+        /// Builds the app's router. Used by `main` as well as the tests.
+        pub fn app(#(#params),*) -> Router {
+            #install_recorder
             #routers
             #auth_router
+            #payments_router
+            #organizations_router
+            #admin_router
 
-            let router = Router::new()
+            Router::new()
                 #crud_nest
                 #auth_nest
-                .route("/", get(hello_world))#with_state
+                #payments_nest
+                #organizations_nest
+                #admin_nest
+                #hello_route
+                #health_routes
+                #server_layers
+                #metrics
+                #trace_layers
+                #with_state
+        }
 
-            Ok(router.into())
+        #hello_world
+    };
+
+    let file = syn::parse_file(&lib.to_string()).unwrap();
+
+    (file, useitems)
+}
+
+/// The binary's entrypoint for the chosen deployment target. It sets up `AppState` with the
+/// library's helpers and serves the router from `app`.
+pub fn main_function(cfg: Config, crate_name: &str) -> (File, String) {
+    let (_, _, dbmacro) = axum_snippets::state_snippets(cfg.clone());
+    let uses_db = cfg.crud | cfg.auth;
+
+    let mut useitems = if uses_db {
+        format!("use {crate_name}::{{app, AppState}};\n")
+    } else {
+        format!("use {crate_name}::app;\n")
+    };
+
+    let secretsmacro = if cfg.secrets {
+        Some(quote! {#[shuttle_secrets::Secrets] secrets: SecretStore,})
+    } else {
+        None
+    };
+
+    if cfg.secrets {
+        useitems.push_str("use shuttle_secrets::SecretStore;\n");
+        useitems.push_str(&format!("use {crate_name}::config::AppConfig;\n"));
+        useitems.push_str("use axum::Extension;\n");
+    }
+
+    if cfg.deployment == Deployment::Shuttle
+        && (cfg.secrets || cfg.embed_migrations || (uses_db && cfg.orm == Orm::Diesel))
+    {
+        useitems.push_str("use shuttle_runtime::CustomError;\n");
+    }
+
+    // The secrets are read before anything else, so a missing one stops the app straight away
+    let load_config = cfg.secrets.then(|| {
+        quote! {
+            let config = AppConfig::from_secrets(&secrets).map_err(CustomError::msg)?;
+        }
+    });
+
+    // Sessions are encrypted with the configured key rather than a new one on every start, which
+    // would log everyone out, emails go through the configured SMTP server and payments through
+    // the configured Stripe account
+    let mut overrides = Vec::new();
+
+    match cfg.deployment {
+        Deployment::Shuttle if cfg.secrets && cfg.auth => {
+            overrides.push(quote! {key: config.cookie_key().map_err(CustomError::msg)?,});
+        }
+        Deployment::Standalone if cfg.auth => {
+            useitems.push_str(&format!("use {crate_name}::config::cookie_key;\n"));
+            overrides.push(quote! {key: cookie_key()?,});
+        }
+        _ => {}
+    }
+
+    if sends_email(&cfg) {
+        useitems.push_str(&format!("use {crate_name}::mailer::mailer;\n"));
+
+        overrides.push(match cfg.deployment {
+            Deployment::Shuttle => quote! {
+                mailer: mailer(config.smtp_url.as_deref(), config.mail_from.as_deref())
+                    .map_err(CustomError::msg)?,
+            },
+            Deployment::Standalone => quote! {
+                mailer: mailer(
+                    std::env::var("SMTP_URL").ok().as_deref(),
+                    std::env::var("MAIL_FROM").ok().as_deref(),
+                )?,
+            },
+        });
+    }
+
+    if takes_payments(&cfg) {
+        overrides.push(match cfg.deployment {
+            Deployment::Shuttle => {
+                useitems.push_str(&format!("use {crate_name}::stripe::StripeClient;\n"));
+                quote! {
+                    stripe: StripeClient::new(
+                        &config.stripe_secret_key,
+                        &config.stripe_webhook_secret,
+                        &config.stripe_price_id,
+                        &config.stripe_redirect_origin,
+                    ),
+                }
+            }
+            Deployment::Standalone => {
+                useitems.push_str(&format!("use {crate_name}::config::stripe_client;\n"));
+                quote! {stripe: stripe_client()?,}
+            }
+        });
+    }
+
+    let with_overrides = |state: TokenStream| {
+        if overrides.is_empty() {
+            quote! {
+                let state = #state;
+            }
+        } else {
+            quote! {
+                let state = AppState {
+                    #(#overrides)*
+                    ..#state
+                };
+            }
         }
+    };
 
+    // Shuttle hands over a sqlx pool, except for Diesel, which needs the connection string
+    let state_declare = match (uses_db, cfg.deployment, cfg.orm) {
+        (false, _, _) => None,
+        (true, Deployment::Shuttle, Orm::Diesel) => Some(with_overrides(quote! {
+            AppState::connect(&db_url).await.map_err(CustomError::msg)?
+        })),
+        (true, Deployment::Shuttle, _) => {
+            useitems.push_str("use sqlx::PgPool;\n");
 
-        pub async fn hello_world() -> &'static str {
-            "Hello world!"
+            Some(with_overrides(quote! {AppState::new(db)}))
         }
+        (true, Deployment::Standalone, _) => Some(with_overrides(quote! {
+            AppState::connect(&std::env::var("DATABASE_URL")?).await?
+        })),
+    };
+
+    let mut embedded_migrations = None;
+
+    let migrate = if cfg.embed_migrations {
+        let (run_migrations, into_error) = match cfg.orm {
+            Orm::Sqlx if cfg.layout == Layout::Workspace => (
+                quote! {migrations::MIGRATOR.run(&state.db).await},
+                quote! {CustomError::new},
+            ),
+            Orm::Sqlx => (
+                quote! {sqlx::migrate!().run(&state.db).await},
+                quote! {CustomError::new},
+            ),
+            Orm::SeaOrm => {
+                useitems.push_str("use migration::{Migrator, MigratorTrait};\n");
+                (
+                    quote! {Migrator::up(&state.orm, None).await},
+                    quote! {CustomError::new},
+                )
+            }
+            Orm::Diesel => {
+                useitems.push_str(
+                    "use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};\n",
+                );
+                embedded_migrations = Some(quote! {
+                    const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+                });
+                (
+                    quote! {
+                        state
+                            .interact(|conn| conn.run_pending_migrations(MIGRATIONS).map(|_| ()))
+                            .await
+                    },
+                    quote! {CustomError::msg},
+                )
+            }
+        };
+
+        match cfg.deployment {
+            Deployment::Shuttle => Some(quote! {
+                #run_migrations.map_err(#into_error)?;
+            }),
+            Deployment::Standalone => Some(quote! {
+                #run_migrations?;
+            }),
+        }
+    } else {
+        None
+    };
+
+    let mut args = Vec::new();
+
+    if uses_db {
+        args.push(quote! {state});
+    }
+
+    if cfg.deployment == Deployment::Standalone {
+        useitems.push_str(&format!("use {crate_name}::config::Settings;\n"));
+        args.push(quote! {&settings});
+    }
+
+    // Handlers get at the secrets with `Extension<AppConfig>`
+    let config_layer = cfg.secrets.then(|| quote! {.layer(Extension(config))});
+
+    let body = quote! {
+        #load_config
+
+        #state_declare
+
+        #migrate
+
+        let router = app(#(#args),*)#config_layer;
+    };
+
+    let entrypoint = match cfg.deployment {
+        Deployment::Shuttle => quote! {
+            #[shuttle_runtime::main]
+            pub async fn main(
+                #dbmacro
+                #secretsmacro
+            ) -> shuttle_axum::ShuttleAxum {
+                #body
+
+                Ok(router.into())
+            }
+        },
+        Deployment::Standalone => {
+            // Shuttle sets up a subscriber of its own, so only standalone services need one
+            let subscriber = cfg.tracing.then(|| {
+                useitems.push_str("use tracing_subscriber::EnvFilter;\n");
+
+                quote! {
+                    tracing_subscriber::fmt()
+                        .json()
+                        .with_env_filter(
+                            EnvFilter::try_from_default_env()
+                                .unwrap_or_else(|_| EnvFilter::new("info,tower_http=debug")),
+                        )
+                        .init();
+                }
+            });
+            let (listening, timed_out) = if cfg.tracing {
+                (
+                    quote! {tracing::info!("Listening on {addr}");},
+                    quote! {tracing::warn!("Timed out waiting for connections to close");},
+                )
+            } else {
+                (
+                    quote! {println!("Listening on {addr}");},
+                    quote! {eprintln!("Timed out waiting for connections to close");},
+                )
+            };
+
+            useitems.push_str("use tokio::sync::watch;\n");
+
+            // The rate limiter keys requests by the address they came from
+            let make_service = if rate_limited(&cfg) {
+                useitems.push_str("use std::net::SocketAddr;\n");
+                quote! {router.into_make_service_with_connect_info::<SocketAddr>()}
+            } else {
+                quote! {router.into_make_service()}
+            };
+
+            quote! {
+                #[tokio::main]
+                async fn main() -> Result<(), Box<dyn std::error::Error>> {
+                    #subscriber
+
+                    let settings = Settings::load()?;
+
+                    #body
+
+                    let addr = settings.addr();
+                    #listening
+
+                    let (draining_tx, mut draining) = watch::channel(());
+
+                    let server = axum::Server::bind(&addr)
+                        .serve(#make_service)
+                        .with_graceful_shutdown(async move {
+                            shutdown_signal().await;
+                            let _ = draining_tx.send(());
+                        });
+
+                    // Stops waiting on requests that are still in flight once the drain timeout is up
+                    tokio::select! {
+                        res = server => res?,
+                        _ = async {
+                            let _ = draining.changed().await;
+                            tokio::time::sleep(settings.shutdown_timeout()).await;
+                        } => {
+                            #timed_out
+                        }
+                    }
+
+                    Ok(())
+                }
+
+                /// Resolves on Ctrl+C, or when the process is sent SIGTERM.
+                async fn shutdown_signal() {
+                    let ctrl_c = async {
+                        tokio::signal::ctrl_c()
+                            .await
+                            .expect("failed to listen for Ctrl+C");
+                    };
+
+                    #[cfg(unix)]
+                    let terminate = async {
+                        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                            .expect("failed to listen for SIGTERM")
+                            .recv()
+                            .await;
+                    };
+
+                    #[cfg(not(unix))]
+                    let terminate = std::future::pending::<()>();
+
+                    tokio::select! {
+                        _ = ctrl_c => {},
+                        _ = terminate => {},
+                    }
+                }
+            }
+        }
+    };
+
+    let main = quote! {
+        #embedded_migrations
+
+        #entrypoint
     };
 
     let file = syn::parse_file(&main.clone().to_string()).unwrap();
@@ -82,8 +655,11 @@ pub fn main_function(cfg: Config, routes: Vec<Route>) -> (File, String) {
     (file, useitems)
 }
 
-pub fn axum_crud_fns(route: Route, requires_auth: bool) -> (File, String) {
-    let query_data = QueryGen::create_query_data(&route.name);
+pub fn axum_crud_fns(route: Route, requires_auth: bool, cfg: &Config) -> (File, String) {
+    let fields = table_fields(&route, &cfg.routes);
+
+    let mut query_data = QueryGen::create_query_data(&route.name, &fields, cfg);
+    query_data.extend(QueryGen::relation_query_data(&route.name, cfg));
 
     let mut v: Vec<char> = route.name.chars().collect();
     v[0] = v[0].to_uppercase().next().unwrap();
@@ -95,15 +671,29 @@ pub fn axum_crud_fns(route: Route, requires_auth: bool) -> (File, String) {
 
     if requires_auth {
         extra_deps.push_str("use axum::Extension;\n");
-        extra_deps.push_str("use crate::middleware::auth::UserInfo;\n")
+
+        if cfg.api_keys {
+            extra_deps.push_str("use crate::middleware::api_key::ApiKeyInfo;\n")
+        } else {
+            extra_deps.push_str("use crate::middleware::auth::UserInfo;\n")
+        }
     }
 
+    if cfg.multi_tenant {
+        extra_deps.push_str("use crate::middleware::tenant::Tenant;\n");
+    }
+
+    // Whoever made the request, as the middleware found them
+    let (caller, caller_type) = if cfg.api_keys {
+        (quote! {_api_key}, quote! {ApiKeyInfo})
+    } else {
+        (quote! {_userinfo}, quote! {UserInfo})
+    };
+
     let query_fn_names: Vec<Ident> = query_data.iter().map(|x| x.query_fn_name.clone()).collect();
-    let queries: Vec<String> = query_data.iter().map(|x| x.query.clone()).collect();
+    let calls: Vec<TokenStream> = query_data.iter().map(|x| x.call(cfg)).collect();
     let paths: Vec<Option<TokenStream>> = query_data.iter().map(|x| x.path.clone()).collect();
-    let binds: Vec<Option<TokenStream>> = query_data.iter().map(|x| x.bind.clone()).collect();
-    let fetch_mode: Vec<TokenStream> = query_data.iter().map(|x| x.fetch_mode.clone()).collect();
-    let querytype: Vec<TokenStream> = query_data.iter().map(|x| x.querytype.clone()).collect();
+    let bodies: Vec<Option<TokenStream>> = query_data.iter().map(|x| x.body.clone()).collect();
     let response: Vec<TokenStream> = query_data.iter().map(|x| x.response.clone()).collect();
     let declarations: Vec<TokenStream> = query_data.iter().map(|x| x.declaration.clone()).collect();
     let error_handling: Vec<TokenStream> = query_data
@@ -114,34 +704,156 @@ pub fn axum_crud_fns(route: Route, requires_auth: bool) -> (File, String) {
     let endpoint = axum_endpoint();
 
     let userinfo_ext = if requires_auth {
-        Some(quote! {Extension(_userinfo): Extension<UserInfo>,})
+        Some(quote! {Extension(#caller): Extension<#caller_type>,})
     } else {
         None
     };
 
+    // The organization every query is scoped to
+    let tenant = cfg.multi_tenant.then(|| quote! {tenant: Tenant,});
+
+    // The ids end up on each handler's span, while the state, session and payload are skipped
+    let instruments: Vec<Option<TokenStream>> = query_data
+        .iter()
+        .map(|x| {
+            cfg.tracing.then(|| {
+                let session = requires_auth.then(|| quote! {, #caller});
+                let payload = x.body.is_some().then(|| quote! {, payload});
+
+                quote! {#[tracing::instrument(skip(state #session #payload))]}
+            })
+        })
+        .collect();
+
+    let field_names: Vec<Ident> = fields
+        .iter()
+        .map(|x| Ident::new(&x.name, Span::call_site()))
+        .collect();
+    let field_types: Vec<TokenStream> = fields.iter().map(|x| x.rust_type()).collect();
+
+    let table = Ident::new(&route.name, Span::call_site());
+
+    let joins: Vec<JoinTable> = join_tables(&cfg.routes)
+        .into_iter()
+        .filter(|x| x.left == route.name || x.right == route.name)
+        .collect();
+
+    // The SeaORM entities or Diesel tables these handlers use
+    let mut modules = vec![table.clone()];
+    modules.extend(
+        joins
+            .iter()
+            .map(|x| Ident::new(&x.name(), Span::call_site())),
+    );
+
+    let payload = if fields.is_empty() {
+        None
+    } else if cfg.repositories {
+        let payload_name = payload_ident(&route.name);
+
+        match cfg.layout {
+            Layout::Single => Some(quote! {use crate::repositories::#table::#payload_name;}),
+            Layout::Workspace => Some(quote! {use domain::#table::#payload_name;}),
+        }
+    } else {
+        let payload_name = payload_ident(&route.name);
+
+        let derives = match cfg.orm {
+            Orm::Diesel => quote! {
+                #[derive(Deserialize, Insertable, AsChangeset)]
+                #[diesel(table_name = #table, treat_none_as_null = true)]
+            },
+            _ => quote! {#[derive(Deserialize)]},
+        };
+
+        Some(quote! {
+            use serde::Deserialize;
+
+            #derives
+            pub struct #payload_name {
+                #(pub #field_names: #field_types,)*
+            }
+        })
+    };
+
+    // With multi-tenancy, rows also say which organization they belong to
+    let tenant_field = cfg.multi_tenant.then(|| {
+        let column = Ident::new(TENANT_COLUMN, Span::call_site());
+        quote! {, pub #column: i32}
+    });
+
+    // With repositories, the model lives alongside the queries instead
+    let model = match cfg.orm {
+        Orm::Sqlx if cfg.repositories => quote! {},
+        Orm::Sqlx => quote! {
+            use chrono::{DateTime, Utc};
+            use serde::Serialize;
+
+            #[derive(Serialize, sqlx::FromRow)]
+            pub struct #structname {
+                pub id: i32,
+                #(pub #field_names: #field_types,)*
+                pub created_at: DateTime<Utc>,
+                pub last_updated: DateTime<Utc>
+                #tenant_field
+            }
+        },
+        Orm::SeaOrm => {
+            let datetime = fields
+                .iter()
+                .any(|x| x.ty == FieldType::Timestamp)
+                .then(|| quote! {use chrono::DateTime;});
+
+            // Only the left side of a many-to-many relation gets the link handlers
+            let on_conflict = joins
+                .iter()
+                .any(|x| x.left == route.name)
+                .then(|| quote! {use sea_orm::sea_query::OnConflict;});
+
+            quote! {
+                use crate::entities::{#(#modules),*};
+                use chrono::Utc;
+                #datetime
+                use sea_orm::{entity::prelude::*, Set, Unchanged};
+                #on_conflict
+            }
+        }
+        Orm::Diesel => quote! {
+            use crate::schema::{#(#modules),*};
+            use chrono::{DateTime, Utc};
+            use diesel::prelude::*;
+            use serde::Serialize;
+
+            #[derive(Serialize, Queryable, Selectable)]
+            #[diesel(table_name = #table)]
+            pub struct #structname {
+                pub id: i32,
+                #(pub #field_names: #field_types,)*
+                pub created_at: DateTime<Utc>,
+                pub last_updated: DateTime<Utc>
+            }
+        },
+    };
+
     let routes = quote! {
         use crate::AppState;
         use axum::{response::IntoResponse, http::StatusCode, extract::{Path, State}, Json};
-        use chrono::{DateTime, Utc};
-        use serde::{Serialize};
 
-        #[derive(Serialize, sqlx::FromRow)]
-        pub struct #structname {
-            id: i32,
-            created_at: DateTime<Utc>,
-            last_updated: DateTime<Utc>
-        }
+        #model
+
+        #payload
 
         #(
+            #instruments
             pub async fn #query_fn_names(
             State(state): State<AppState>,
             #userinfo_ext
+            #tenant
             #paths
+            #bodies
         ) -> #endpoint {
 
-            #declarations #querytype(#queries)
-                    #binds
-                    #fetch_mode
+            #declarations #calls
                     .await {
                     #error_handling
             };
@@ -0,0 +1,100 @@
+use crate::cli::Config;
+use quote::quote;
+use syn::File;
+
+/// The generated module with the metrics middleware and the `/metrics` handler. It isn't called
+/// `metrics` so it doesn't shadow the crate of the same name.
+pub const METRICS_MODULE: &str = "prometheus";
+
+/// Records the count, latency and status of requests to every matched route, and serves them at
+/// `/metrics` alongside gauges for the connection pool in `AppState`.
+pub fn metrics_file(cfg: &Config) -> File {
+    let uses_db = cfg.crud | cfg.auth;
+
+    let state_uses = uses_db.then(|| {
+        quote! {
+            use crate::AppState;
+            use axum::extract::State;
+        }
+    });
+
+    let render = if uses_db {
+        quote! {
+            /// Serves every metric in Prometheus' text format, after checking on the pool.
+            pub async fn render_metrics(State(state): State<AppState>) -> String {
+                metrics::gauge!("db_pool_connections", state.db.size() as f64);
+                metrics::gauge!("db_pool_idle_connections", state.db.num_idle() as f64);
+
+                recorder_handle().render()
+            }
+        }
+    } else {
+        quote! {
+            /// Serves every metric in Prometheus' text format.
+            pub async fn render_metrics() -> String {
+                recorder_handle().render()
+            }
+        }
+    };
+
+    let code = quote! {
+        #state_uses
+        use axum::{extract::MatchedPath, http::Request, middleware::Next, response::IntoResponse};
+        use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+        use std::sync::OnceLock;
+        use std::time::Instant;
+
+        const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+        static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+        /// Installs the Prometheus recorder the first time it's called. The recorder is global, so
+        /// every router built by `app` shares it.
+        pub fn recorder_handle() -> &'static PrometheusHandle {
+            HANDLE.get_or_init(|| {
+                PrometheusBuilder::new()
+                    .set_buckets_for_metric(
+                        Matcher::Full("http_requests_duration_seconds".to_string()),
+                        LATENCY_BUCKETS,
+                    )
+                    .unwrap()
+                    .install_recorder()
+                    .unwrap()
+            })
+        }
+
+        /// Records every request, labelled with its method, status and the route it matched.
+        pub async fn track_metrics<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
+            let start = Instant::now();
+
+            // This only runs as a route layer, so there's always a matched path
+            let path = req
+                .extensions()
+                .get::<MatchedPath>()
+                .map(|x| x.as_str().to_owned())
+                .unwrap_or_default();
+            let method = req.method().to_string();
+
+            let res = next.run(req).await;
+
+            let labels = [
+                ("method", method),
+                ("path", path),
+                ("status", res.status().as_u16().to_string()),
+            ];
+
+            metrics::increment_counter!("http_requests_total", &labels);
+            metrics::histogram!(
+                "http_requests_duration_seconds",
+                start.elapsed().as_secs_f64(),
+                &labels
+            );
+
+            res
+        }
+
+        #render
+    };
+
+    syn::parse_file(&code.to_string()).unwrap()
+}
@@ -1,40 +1,51 @@
-use crate::cli::Config;
+use crate::cli::{Config, Orm, RateLimitStore, Route};
+use crate::codegen::mailer::sends_email;
+use crate::codegen::payments::{takes_payments, PAYMENTS, SUBSCRIPTIONS};
+use crate::codegen::tenants::{MEMBER, MEMBERSHIPS, ORGANIZATIONS, TENANT_COLUMN};
+use crate::schema::{creation_order, foreign_keys, join_tables, Field, ForeignKey, JoinTable};
 use chrono::Utc;
 use indoc::{formatdoc, indoc};
 use std::fs;
-use std::path::PathBuf;
+use std::path::Path;
 
-pub fn write_migration_file(migrations_dir: PathBuf, cfg: Config) {
-    let mut migrations_up = String::new();
-    let mut migrations_down = String::new();
+/// The tables holding the hashed tokens from verification and password reset emails.
+const EMAIL_VERIFICATION_TOKENS: &str = "email_verification_tokens";
+const PASSWORD_RESET_TOKENS: &str = "password_reset_tokens";
+/// The token buckets of the Postgres rate limiter.
+const RATE_LIMITS: &str = "rate_limits";
+/// The hashed API keys, when routes are protected by keys instead of sessions.
+const API_KEYS: &str = "api_keys";
 
-    if let Some(routes) = cfg.routes {
-        for route in routes {
-            migrations_up.push_str(&formatdoc! {"CREATE TABLE IF NOT EXISTS {route} (
-                    id SERIAL PRIMARY KEY,
-                    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
-                    last_updated TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP    
-                );\n\n"
-            });
-
-            migrations_down.push_str(&formatdoc! {"DROP TABLE {route};\n"});
-        }
-    }
+/// Every table inlet can create besides the resources' own, so no resource can be named after one.
+pub const GENERATED_TABLES: [&str; 10] = [
+    "users",
+    "usersessions",
+    ORGANIZATIONS,
+    MEMBERSHIPS,
+    API_KEYS,
+    EMAIL_VERIFICATION_TOKENS,
+    PASSWORD_RESET_TOKENS,
+    RATE_LIMITS,
+    SUBSCRIPTIONS,
+    PAYMENTS,
+];
+/// The columns every resource's table starts with.
+pub const GENERATED_COLUMNS: [&str; 3] = ["id", "created_at", "last_updated"];
 
-    let timestamp = Utc::now().naive_local().format("%Y%m%d%H%M%S");
+fn auth_tables_up(cfg: &Config) -> String {
+    let columns: String = users_columns(cfg)
+        .iter()
+        .map(|(name, definition)| format!("    {name} {definition},\n"))
+        .collect();
 
-    let filename_up = format!("{timestamp}_schema.up.sql");
-    let filename_down = format!("{timestamp}_schema.down.sql");
-
-    if cfg.auth {
-        migrations_up.push_str(indoc! {"CREATE TABLE IF NOT EXISTS users (
+    formatdoc! {"CREATE TABLE IF NOT EXISTS users (
             id SERIAL PRIMARY KEY,
             username VARCHAR(255) NOT NULL UNIQUE,
             password VARCHAR NOT NULL,
-            created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        {columns}    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
             last_updated TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
             );
-            
+
             CREATE TABLE IF NOT EXISTS usersessions (
             id SERIAL PRIMARY KEY,
             user_id INT NOT NULL UNIQUE,
@@ -42,13 +53,968 @@ pub fn write_migration_file(migrations_dir: PathBuf, cfg: Config) {
             expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
             FOREIGN KEY (user_id) REFERENCES users(id)
             );\n\n"
+    }
+}
+
+/// The columns that optional auth features add to `users`, as their name and definition.
+fn users_columns(cfg: &Config) -> Vec<(&'static str, &'static str)> {
+    let mut columns = Vec::new();
+
+    if sends_email(cfg) {
+        columns.push(("email", "VARCHAR(255) UNIQUE"));
+    }
+
+    if cfg.email_verification {
+        columns.push(("email_verified_at", "TIMESTAMP WITH TIME ZONE"));
+    }
+
+    if cfg.rate_limit {
+        columns.push(("failed_logins", "INT NOT NULL DEFAULT 0"));
+        columns.push(("locked_until", "TIMESTAMP WITH TIME ZONE"));
+    }
+
+    // Each user pays as one Stripe customer, created on their first checkout
+    if takes_payments(cfg) {
+        columns.push(("stripe_customer_id", "VARCHAR UNIQUE"));
+    }
+
+    columns
+}
+
+/// Adds and drops the columns on `users` that optional auth features need, when auth was already
+/// there before.
+fn users_column_changes(old: &Config, new: &Config) -> Vec<Change> {
+    let (old_columns, new_columns) = (users_columns(old), users_columns(new));
+    let mut changes = Vec::new();
+
+    for (name, definition) in &new_columns {
+        if old_columns.iter().any(|x| x.0 == *name) {
+            continue;
+        }
+
+        let mut up = format!("ALTER TABLE users ADD COLUMN {name} {definition};\n");
+
+        // Accounts from before verification was turned on can still log in
+        if *name == "email_verified_at" {
+            up.push_str("UPDATE users SET email_verified_at = CURRENT_TIMESTAMP;\n");
+        }
+
+        changes.push(Change {
+            up,
+            down: format!("ALTER TABLE users DROP COLUMN {name};\n"),
+            destructive: None,
+        });
+    }
+
+    for (name, definition) in old_columns.iter().rev() {
+        if new_columns.iter().any(|x| x.0 == *name) {
+            continue;
+        }
+
+        changes.push(Change {
+            up: format!("ALTER TABLE users DROP COLUMN {name};\n"),
+            down: format!("ALTER TABLE users ADD COLUMN {name} {definition};\n"),
+            destructive: Some(format!("Drops the `users.{name}` column")),
+        });
+    }
+
+    changes
+}
+
+fn create_token_table(name: &str) -> String {
+    formatdoc! {"CREATE TABLE IF NOT EXISTS {name} (
+            id SERIAL PRIMARY KEY,
+            user_id INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            token_hash VARCHAR NOT NULL UNIQUE,
+            expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+            created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );\n\n"
+    }
+}
+
+/// The tables that optional auth features need, as their name and the statement that creates
+/// them.
+fn feature_tables(cfg: &Config) -> Vec<(&'static str, String)> {
+    let mut tables = Vec::new();
+
+    // Keys are looked up by their hash, which the unique constraint indexes
+    if cfg.api_keys {
+        tables.push((
+            API_KEYS,
+            formatdoc! {"CREATE TABLE IF NOT EXISTS {API_KEYS} (
+                    id SERIAL PRIMARY KEY,
+                    name VARCHAR NOT NULL,
+                    prefix VARCHAR NOT NULL,
+                    key_hash VARCHAR NOT NULL UNIQUE,
+                    scopes TEXT[] NOT NULL,
+                    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+                    revoked_at TIMESTAMP WITH TIME ZONE
+                );\n\n"
+            },
+        ));
+    }
+
+    if !cfg.auth {
+        return tables;
+    }
+
+    // Users are looked up by their memberships far more often than organizations are
+    if cfg.multi_tenant {
+        tables.push((
+            MEMBERSHIPS,
+            formatdoc! {"CREATE TABLE IF NOT EXISTS {MEMBERSHIPS} (
+                    id SERIAL PRIMARY KEY,
+                    organization_id INT NOT NULL REFERENCES {ORGANIZATIONS}(id) ON DELETE CASCADE,
+                    user_id INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                    role VARCHAR NOT NULL DEFAULT '{MEMBER}',
+                    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+                    UNIQUE (organization_id, user_id)
+                );
+                CREATE INDEX {MEMBERSHIPS}_user_id_idx ON {MEMBERSHIPS} (user_id);\n\n"
+            },
+        ));
+    }
+
+    if cfg.email_verification {
+        tables.push((
+            EMAIL_VERIFICATION_TOKENS,
+            create_token_table(EMAIL_VERIFICATION_TOKENS),
+        ));
+    }
+
+    if cfg.password_reset {
+        tables.push((
+            PASSWORD_RESET_TOKENS,
+            create_token_table(PASSWORD_RESET_TOKENS),
+        ));
+    }
+
+    if cfg.rate_limit && cfg.rate_limit_store == RateLimitStore::Postgres {
+        tables.push((
+            RATE_LIMITS,
+            formatdoc! {"CREATE TABLE IF NOT EXISTS {RATE_LIMITS} (
+                    key VARCHAR PRIMARY KEY,
+                    tokens DOUBLE PRECISION NOT NULL,
+                    allowed BOOLEAN NOT NULL,
+                    updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+                );\n\n"
+            },
+        ));
+    }
+
+    // Events are recorded by their id, which is how a redelivered one is spotted. They're kept
+    // even when the user they were for is deleted
+    if takes_payments(cfg) {
+        tables.push((
+            SUBSCRIPTIONS,
+            formatdoc! {"CREATE TABLE IF NOT EXISTS {SUBSCRIPTIONS} (
+                    id SERIAL PRIMARY KEY,
+                    user_id INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                    stripe_subscription_id VARCHAR NOT NULL UNIQUE,
+                    status VARCHAR NOT NULL,
+                    price_id VARCHAR,
+                    current_period_end TIMESTAMP WITH TIME ZONE,
+                    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+                );\n\n"
+            },
+        ));
+        tables.push((
+            PAYMENTS,
+            formatdoc! {"CREATE TABLE IF NOT EXISTS {PAYMENTS} (
+                    id SERIAL PRIMARY KEY,
+                    stripe_event_id VARCHAR NOT NULL UNIQUE,
+                    event_type VARCHAR NOT NULL,
+                    user_id INT REFERENCES users(id) ON DELETE SET NULL,
+                    amount BIGINT,
+                    currency VARCHAR,
+                    received_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+                );\n\n"
+            },
+        ));
+    }
+
+    tables
+}
+
+/// Every resource's rows belong to one of these, so it's created before any of their tables.
+fn organizations_table() -> String {
+    formatdoc! {"CREATE TABLE IF NOT EXISTS {ORGANIZATIONS} (
+            id SERIAL PRIMARY KEY,
+            name VARCHAR NOT NULL,
+            created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+        );\n\n"
+    }
+}
+
+const AUTH_TABLES_DOWN: &str = indoc! {"DROP TABLE usersessions;
+    DROP TABLE users;\n"
+};
+
+/// Where migrations are kept, relative to the project root. This is where `sqlx::migrate!()` looks by default.
+pub const MIGRATIONS_DIR: &str = "migrations";
+
+/// A single step of a migration, alongside the step that undoes it.
+pub struct Change {
+    pub up: String,
+    pub down: String,
+    /// Describes what could be lost if this change is applied.
+    pub destructive: Option<String>,
+}
+
+pub fn write_migration_file(migrations_dir: &Path, cfg: Config) {
+    let changes = schema_changes(&Config::default(), &cfg).unwrap();
+
+    write_migration(migrations_dir, "schema", &changes, cfg.orm);
+}
+
+/// Writes `changes` as a new migration, laid out the way `orm`'s tooling expects: an up/down pair
+/// of files for sqlx, or a directory holding `up.sql` and `down.sql` for Diesel.
+pub fn write_migration(migrations_dir: &Path, description: &str, changes: &[Change], orm: Orm) {
+    let migrations_up: String = changes.iter().map(|x| x.up.as_str()).collect();
+    let migrations_down: String = changes.iter().rev().map(|x| x.down.as_str()).collect();
+
+    let now = Utc::now().naive_local();
+
+    let (path_up, path_down) = match orm {
+        Orm::Diesel => {
+            let timestamp = now.format("%Y-%m-%d-%H%M%S");
+            let dir = migrations_dir.join(format!("{timestamp}_{description}"));
+            fs::create_dir_all(&dir).unwrap();
+
+            (dir.join("up.sql"), dir.join("down.sql"))
+        }
+        _ => {
+            let timestamp = now.format("%Y%m%d%H%M%S");
+
+            (
+                migrations_dir.join(format!("{timestamp}_{description}.up.sql")),
+                migrations_dir.join(format!("{timestamp}_{description}.down.sql")),
+            )
+        }
+    };
+
+    fs::write(path_up, migrations_up).unwrap();
+    fs::write(path_down, migrations_down).unwrap();
+}
+
+/// Works out everything that needs to happen to get a database from the `old` schema to the `new` one.
+pub fn schema_changes(old: &Config, new: &Config) -> Result<Vec<Change>, String> {
+    let old_keys = foreign_keys(&old.routes);
+    let new_keys = foreign_keys(&new.routes);
+    let old_order = creation_order(&old.routes)?;
+    let new_order = creation_order(&new.routes)?;
+    let old_joins = join_tables(&old.routes);
+    let new_joins = join_tables(&new.routes);
+
+    let mut changes = Vec::new();
+
+    if new.multi_tenant && !old.multi_tenant {
+        changes.push(Change {
+            up: organizations_table(),
+            down: format!("DROP TABLE {ORGANIZATIONS};\n"),
+            destructive: None,
+        });
+    }
+
+    for route in &new_order {
+        match old_order.iter().find(|x| x.name == route.name) {
+            Some(old_route) => {
+                changes.extend(column_changes(old_route, &old_keys, route, &new_keys));
+                changes.extend(tenant_column_change(&route.name, old, new));
+            }
+            None => changes.push(Change {
+                up: create_table(route, &new_keys, new.multi_tenant),
+                down: format!("DROP TABLE {};\n", route.name),
+                destructive: None,
+            }),
+        }
+    }
+
+    if new.auth && !old.auth {
+        changes.push(Change {
+            up: auth_tables_up(new),
+            down: AUTH_TABLES_DOWN.to_string(),
+            destructive: None,
+        });
+    }
+
+    if new.auth && old.auth {
+        changes.extend(users_column_changes(old, new));
+    }
+
+    let (old_tables, new_tables) = (feature_tables(old), feature_tables(new));
+
+    for (table, create) in &new_tables {
+        if !old_tables.iter().any(|x| x.0 == *table) {
+            changes.push(Change {
+                up: create.clone(),
+                down: format!("DROP TABLE {table};\n"),
+                destructive: None,
+            });
+        }
+    }
+
+    for join in &new_joins {
+        if !old_joins.iter().any(|x| x.name() == join.name()) {
+            changes.push(Change {
+                up: create_join_table(join),
+                down: format!("DROP TABLE {};\n", join.name()),
+                destructive: None,
+            });
+        }
+    }
+
+    for join in &old_joins {
+        if !new_joins.iter().any(|x| x.name() == join.name()) {
+            changes.push(Change {
+                up: format!("DROP TABLE {};\n", join.name()),
+                down: create_join_table(join),
+                destructive: Some(format!("Drops the `{}` join table", join.name())),
+            });
+        }
+    }
+
+    for (table, create) in &old_tables {
+        if !new_tables.iter().any(|x| x.0 == *table) {
+            changes.push(Change {
+                up: format!("DROP TABLE {table};\n"),
+                down: create.clone(),
+                destructive: Some(format!("Drops the `{table}` table")),
+            });
+        }
+    }
+
+    if old.auth && !new.auth {
+        changes.push(Change {
+            up: AUTH_TABLES_DOWN.to_string(),
+            down: auth_tables_up(old),
+            destructive: Some("Drops the `users` and `usersessions` tables".to_string()),
+        });
+    }
+
+    for route in old_order.iter().rev() {
+        if !new_order.iter().any(|x| x.name == route.name) {
+            changes.push(Change {
+                up: format!("DROP TABLE {};\n", route.name),
+                down: create_table(route, &old_keys, old.multi_tenant),
+                destructive: Some(format!("Drops the `{}` table", route.name)),
+            });
+        }
+    }
+
+    if old.multi_tenant && !new.multi_tenant {
+        changes.push(Change {
+            up: format!("DROP TABLE {ORGANIZATIONS};\n"),
+            down: organizations_table(),
+            destructive: Some(format!("Drops the `{ORGANIZATIONS}` table")),
+        });
+    }
+
+    Ok(changes)
+}
+
+/// The column holding the organization a resource's row belongs to, and the index its queries
+/// are filtered by.
+fn tenant_column(table: &str) -> (String, String) {
+    (
+        format!("{TENANT_COLUMN} INT NOT NULL REFERENCES {ORGANIZATIONS}(id) ON DELETE CASCADE"),
+        format!("CREATE INDEX {table}_{TENANT_COLUMN}_idx ON {table} ({TENANT_COLUMN});\n"),
+    )
+}
+
+/// Adds or drops the organization column on a resource that was already there, when
+/// multi-tenancy is turned on or off.
+fn tenant_column_change(table: &str, old: &Config, new: &Config) -> Option<Change> {
+    let (definition, index) = tenant_column(table);
+    let add = format!("ALTER TABLE {table} ADD COLUMN {definition};\n{index}");
+    let drop = format!("ALTER TABLE {table} DROP COLUMN {TENANT_COLUMN};\n");
+
+    match (old.multi_tenant, new.multi_tenant) {
+        (false, true) => Some(Change {
+            up: add,
+            down: drop,
+            destructive: Some(format!(
+                "Adds the NOT NULL `{table}.{TENANT_COLUMN}` column, which fails if `{table}` has any rows"
+            )),
+        }),
+        (true, false) => Some(Change {
+            up: drop,
+            down: add,
+            destructive: Some(format!("Drops the `{table}.{TENANT_COLUMN}` column")),
+        }),
+        _ => None,
+    }
+}
+
+/// Every column on a resource's table that isn't added automatically, including foreign keys.
+fn columns(route: &Route, keys: &[ForeignKey]) -> Vec<(Field, Option<ForeignKey>)> {
+    let mut columns: Vec<(Field, Option<ForeignKey>)> =
+        route.fields.iter().map(|x| (x.clone(), None)).collect();
+
+    for key in keys.iter().filter(|x| x.table == route.name) {
+        columns.push((key.field(), Some(key.clone())));
+    }
+
+    columns
+}
+
+fn column_definition(field: &Field, key: &Option<ForeignKey>) -> String {
+    match key {
+        Some(key) => key.column_definition(),
+        None => field.column_definition(),
+    }
+}
+
+fn create_table(route: &Route, keys: &[ForeignKey], tenant: bool) -> String {
+    let name = &route.name;
+    let columns = columns(route, keys);
+
+    let definitions: String = columns
+        .iter()
+        .map(|(field, key)| format!("    {},\n", column_definition(field, key)))
+        .collect();
+
+    let mut indexes: String = columns
+        .iter()
+        .filter_map(|(field, _)| field.index_definition(name))
+        .map(|(_, definition)| format!("{definition};\n"))
+        .collect();
+
+    // The organization goes last, which is also where adding it later would put it, so the
+    // columns the generated models read keep their positions either way
+    let tenant_definition = if tenant {
+        let (definition, index) = tenant_column(name);
+        indexes.push_str(&index);
+
+        format!(",\n    {definition}")
+    } else {
+        String::new()
+    };
+
+    formatdoc! {"CREATE TABLE IF NOT EXISTS {name} (
+            id SERIAL PRIMARY KEY,
+        {definitions}    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            last_updated TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP{tenant_definition}
+        );
+        {indexes}\n"
+    }
+}
+
+fn create_join_table(join: &JoinTable) -> String {
+    let name = join.name();
+    let (left, right) = (&join.left, &join.right);
+    let on_delete = join.on_delete.sql();
+
+    formatdoc! {"CREATE TABLE IF NOT EXISTS {name} (
+            {left}_id INT NOT NULL REFERENCES {left}(id) ON DELETE {on_delete},
+            {right}_id INT NOT NULL REFERENCES {right}(id) ON DELETE {on_delete},
+            PRIMARY KEY ({left}_id, {right}_id)
+        );\n\n"
+    }
+}
+
+fn add_column(table: &str, field: &Field, key: &Option<ForeignKey>) -> String {
+    let mut sql = format!(
+        "ALTER TABLE {table} ADD COLUMN {};\n",
+        column_definition(field, key)
+    );
+
+    if let Some((_, definition)) = field.index_definition(table) {
+        sql.push_str(&format!("{definition};\n"));
+    }
+
+    sql
+}
+
+fn column_changes(
+    old_route: &Route,
+    old_keys: &[ForeignKey],
+    new_route: &Route,
+    new_keys: &[ForeignKey],
+) -> Vec<Change> {
+    let table = &new_route.name;
+    let old_columns = columns(old_route, old_keys);
+    let new_columns = columns(new_route, new_keys);
+
+    let mut changes = Vec::new();
+    let mut matched: Vec<String> = Vec::new();
+
+    for (field, key) in &new_columns {
+        let name = &field.name;
+
+        let renamed = field.renamed_from.as_ref().filter(|old_name| {
+            !old_columns.iter().any(|(x, _)| &x.name == name)
+                && !new_columns.iter().any(|(x, _)| &x.name == *old_name)
+        });
+
+        let old_name = renamed.unwrap_or(name);
+
+        let Some((old_field, old_key)) = old_columns.iter().find(|(x, _)| &x.name == old_name)
+        else {
+            // There's no default to fill existing rows with
+            changes.push(Change {
+                up: add_column(table, field, key),
+                down: format!("ALTER TABLE {table} DROP COLUMN {name};\n"),
+                destructive: (!field.nullable).then(|| {
+                    format!(
+                        "Adds the NOT NULL `{table}.{name}` column, which fails if `{table}` has any rows"
+                    )
+                }),
+            });
+            continue;
+        };
+
+        matched.push(old_name.clone());
+
+        // Anything after a rename refers to the column by its new name
+        let mut old_field = old_field.clone();
+        old_field.name = name.clone();
+
+        if let Some(old_name) = renamed {
+            let mut up = format!("ALTER TABLE {table} RENAME COLUMN {old_name} TO {name};\n");
+            let mut down = format!("ALTER TABLE {table} RENAME COLUMN {name} TO {old_name};\n");
+
+            let renamed_index = old_columns
+                .iter()
+                .find(|(x, _)| &x.name == old_name)
+                .and_then(|(x, _)| x.index_definition(table));
+
+            if let (Some((old_index, _)), Some((new_index, _))) =
+                (renamed_index, old_field.index_definition(table))
+            {
+                up.push_str(&format!("ALTER INDEX {old_index} RENAME TO {new_index};\n"));
+                down.insert_str(
+                    0,
+                    &format!("ALTER INDEX {new_index} RENAME TO {old_index};\n"),
+                );
+            }
+
+            changes.push(Change {
+                up,
+                down,
+                destructive: None,
+            });
+        }
+
+        if old_field.ty != field.ty {
+            let (old_type, new_type) = (old_field.ty.sql_type(), field.ty.sql_type());
+
+            changes.push(Change {
+                up: format!(
+                    "ALTER TABLE {table} ALTER COLUMN {name} TYPE {new_type} USING {name}::{new_type};\n"
+                ),
+                down: format!(
+                    "ALTER TABLE {table} ALTER COLUMN {name} TYPE {old_type} USING {name}::{old_type};\n"
+                ),
+                destructive: Some(format!(
+                    "Changes the type of `{table}.{name}` from {} to {}",
+                    old_field.ty, field.ty
+                )),
+            });
+        }
+
+        if old_field.nullable != field.nullable {
+            let (up, down) = if field.nullable {
+                ("DROP NOT NULL", "SET NOT NULL")
+            } else {
+                ("SET NOT NULL", "DROP NOT NULL")
+            };
+
+            changes.push(Change {
+                up: format!("ALTER TABLE {table} ALTER COLUMN {name} {up};\n"),
+                down: format!("ALTER TABLE {table} ALTER COLUMN {name} {down};\n"),
+                destructive: (!field.nullable).then(|| {
+                    format!("Makes `{table}.{name}` NOT NULL, which fails if any rows are null")
+                }),
+            });
+        }
+
+        let old_references = old_key.as_ref().map(|x| x.references_definition());
+        let new_references = key.as_ref().map(|x| x.references_definition());
+
+        if old_references != new_references {
+            let drop_old = old_key
+                .as_ref()
+                .map(|x| format!("ALTER TABLE {table} DROP CONSTRAINT {};\n", x.constraint()))
+                .unwrap_or_default();
+            let add_old = old_key
+                .as_ref()
+                .map(|x| {
+                    format!(
+                        "ALTER TABLE {table} ADD CONSTRAINT {} FOREIGN KEY ({name}) {};\n",
+                        x.constraint(),
+                        x.references_definition()
+                    )
+                })
+                .unwrap_or_default();
+            let drop_new = key
+                .as_ref()
+                .map(|x| format!("ALTER TABLE {table} DROP CONSTRAINT {};\n", x.constraint()))
+                .unwrap_or_default();
+            let add_new = key
+                .as_ref()
+                .map(|x| {
+                    format!(
+                        "ALTER TABLE {table} ADD CONSTRAINT {} FOREIGN KEY ({name}) {};\n",
+                        x.constraint(),
+                        x.references_definition()
+                    )
+                })
+                .unwrap_or_default();
+
+            changes.push(Change {
+                up: format!("{drop_old}{add_new}"),
+                down: format!("{drop_new}{add_old}"),
+                destructive: None,
+            });
+        }
+
+        let old_index = old_field.index_definition(table);
+        let new_index = field.index_definition(table);
+
+        if old_index != new_index {
+            let mut up = String::new();
+            let mut down = String::new();
+
+            if let Some((index, definition)) = &old_index {
+                up.push_str(&format!("DROP INDEX {index};\n"));
+                down.push_str(&format!("{definition};\n"));
+            }
+
+            if let Some((index, definition)) = &new_index {
+                up.push_str(&format!("{definition};\n"));
+                down.insert_str(0, &format!("DROP INDEX {index};\n"));
+            }
+
+            changes.push(Change {
+                up,
+                down,
+                destructive: None,
+            });
+        }
+    }
+
+    for (field, key) in &old_columns {
+        if matched.contains(&field.name) {
+            continue;
+        }
+
+        changes.push(Change {
+            up: format!("ALTER TABLE {table} DROP COLUMN {};\n", field.name),
+            down: add_column(table, field, key),
+            destructive: Some(format!("Drops the `{table}.{}` column", field.name)),
         });
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::parse_manifest;
 
-        migrations_down.push_str(indoc! {"DROP TABLE users;
-            DROP TABLE sessions;\n"
-        })
+    fn config(manifest: &str) -> Config {
+        parse_manifest(&format!("name = \"test\"\n{manifest}"))
+            .unwrap()
+            .1
     }
 
-    fs::write(migrations_dir.join(filename_up), migrations_up).unwrap();
-    fs::write(migrations_dir.join(filename_down), migrations_down).unwrap();
+    fn changes(old: &str, new: &str) -> Vec<Change> {
+        schema_changes(&config(old), &config(new)).unwrap()
+    }
+
+    const POST: &str = r#"
+        [[resources]]
+        name = "post"
+
+        [[resources.fields]]
+        name = "title"
+        type = "text"
+    "#;
+
+    #[test]
+    fn creates_and_drops_tables() {
+        let created = changes("", POST);
+        assert_eq!(created.len(), 1);
+        assert!(created[0]
+            .up
+            .starts_with("CREATE TABLE IF NOT EXISTS post (\n    id SERIAL PRIMARY KEY,\n    title VARCHAR NOT NULL,\n"));
+        assert_eq!(created[0].down, "DROP TABLE post;\n");
+        assert!(created[0].destructive.is_none());
+
+        let dropped = changes(POST, "");
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].up, "DROP TABLE post;\n");
+        assert_eq!(dropped[0].down, created[0].up);
+        assert!(dropped[0].destructive.is_some());
+    }
+
+    #[test]
+    fn adds_and_drops_columns() {
+        let new = format!(
+            "{POST}\n[[resources.fields]]\nname = \"views\"\ntype = \"integer\"\nindexed = true\n"
+        );
+
+        let added = changes(POST, &new);
+        assert_eq!(added.len(), 1);
+        assert_eq!(
+            added[0].up,
+            "ALTER TABLE post ADD COLUMN views INT NOT NULL;\nCREATE INDEX post_views_idx ON post (views);\n"
+        );
+        assert_eq!(added[0].down, "ALTER TABLE post DROP COLUMN views;\n");
+        assert!(added[0].destructive.is_some());
+
+        let nullable = changes(POST, &format!("{new}nullable = true\n"));
+        assert_eq!(nullable.len(), 1);
+        assert!(
+            nullable[0]
+                .up
+                .starts_with("ALTER TABLE post ADD COLUMN views INT;\n"),
+            "{}",
+            nullable[0].up
+        );
+        assert!(nullable[0].destructive.is_none());
+
+        let dropped = changes(&new, POST);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].up, "ALTER TABLE post DROP COLUMN views;\n");
+        assert_eq!(dropped[0].down, added[0].up);
+        assert!(dropped[0].destructive.is_some());
+    }
+
+    #[test]
+    fn renames_columns_along_with_their_indexes() {
+        let old = format!("{POST}indexed = true\n");
+        let new = POST.replace(
+            "\"title\"",
+            "\"heading\"\nrenamed_from = \"title\"\nindexed = true",
+        );
+
+        let renamed = changes(&old, &new);
+        assert_eq!(renamed.len(), 1);
+        assert_eq!(
+            renamed[0].up,
+            "ALTER TABLE post RENAME COLUMN title TO heading;\nALTER INDEX post_title_idx RENAME TO post_heading_idx;\n"
+        );
+        assert_eq!(
+            renamed[0].down,
+            "ALTER INDEX post_heading_idx RENAME TO post_title_idx;\nALTER TABLE post RENAME COLUMN heading TO title;\n"
+        );
+        assert!(renamed[0].destructive.is_none());
+    }
+
+    #[test]
+    fn retypes_columns_with_a_cast() {
+        let new = POST.replace("\"text\"", "\"bigint\"");
+
+        let retyped = changes(POST, &new);
+        assert_eq!(retyped.len(), 1);
+        assert_eq!(
+            retyped[0].up,
+            "ALTER TABLE post ALTER COLUMN title TYPE BIGINT USING title::BIGINT;\n"
+        );
+        assert_eq!(
+            retyped[0].down,
+            "ALTER TABLE post ALTER COLUMN title TYPE VARCHAR USING title::VARCHAR;\n"
+        );
+        assert!(retyped[0].destructive.is_some());
+    }
+
+    #[test]
+    fn only_flags_making_columns_not_null() {
+        let nullable = format!("{POST}nullable = true\n");
+
+        let relaxed = changes(POST, &nullable);
+        assert_eq!(relaxed.len(), 1);
+        assert_eq!(
+            relaxed[0].up,
+            "ALTER TABLE post ALTER COLUMN title DROP NOT NULL;\n"
+        );
+        assert_eq!(
+            relaxed[0].down,
+            "ALTER TABLE post ALTER COLUMN title SET NOT NULL;\n"
+        );
+        assert!(relaxed[0].destructive.is_none());
+
+        let tightened = changes(&nullable, POST);
+        assert_eq!(tightened.len(), 1);
+        assert_eq!(tightened[0].up, relaxed[0].down);
+        assert_eq!(tightened[0].down, relaxed[0].up);
+        assert!(tightened[0].destructive.is_some());
+    }
+
+    #[test]
+    fn swaps_indexes() {
+        let indexed = format!("{POST}indexed = true\n");
+        let unique = format!("{POST}unique = true\n");
+
+        let swapped = changes(&indexed, &unique);
+        assert_eq!(swapped.len(), 1);
+        assert_eq!(
+            swapped[0].up,
+            "DROP INDEX post_title_idx;\nCREATE UNIQUE INDEX post_title_key ON post (title);\n"
+        );
+        assert_eq!(
+            swapped[0].down,
+            "DROP INDEX post_title_key;\nCREATE INDEX post_title_idx ON post (title);\n"
+        );
+        assert!(swapped[0].destructive.is_none());
+    }
+
+    #[test]
+    fn swaps_foreign_key_constraints() {
+        let comment = |on_delete: &str| {
+            format!(
+                "{POST}\n[[resources]]\nname = \"comment\"\n\n[[resources.relations]]\nkind = \"belongs_to\"\ntarget = \"post\"\non_delete = \"{on_delete}\"\n"
+            )
+        };
+
+        let swapped = changes(&comment("cascade"), &comment("restrict"));
+        assert_eq!(swapped.len(), 1);
+        assert_eq!(
+            swapped[0].up,
+            "ALTER TABLE comment DROP CONSTRAINT comment_post_id_fkey;\n\
+             ALTER TABLE comment ADD CONSTRAINT comment_post_id_fkey FOREIGN KEY (post_id) REFERENCES post(id) ON DELETE RESTRICT;\n"
+        );
+        assert_eq!(
+            swapped[0].down,
+            "ALTER TABLE comment DROP CONSTRAINT comment_post_id_fkey;\n\
+             ALTER TABLE comment ADD CONSTRAINT comment_post_id_fkey FOREIGN KEY (post_id) REFERENCES post(id) ON DELETE CASCADE;\n"
+        );
+        assert!(swapped[0].destructive.is_none());
+
+        // Setting the key to null on delete also makes the column nullable
+        let set_null = changes(&comment("cascade"), &comment("set_null"));
+        assert_eq!(set_null.len(), 2);
+        assert_eq!(
+            set_null[0].up,
+            "ALTER TABLE comment ALTER COLUMN post_id DROP NOT NULL;\n"
+        );
+        assert!(set_null[1].up.ends_with("ON DELETE SET NULL;\n"));
+    }
+
+    #[test]
+    fn flags_foreign_keys_added_to_existing_tables_unless_nullable() {
+        let comment =
+            |relation: &str| format!("{POST}\n[[resources]]\nname = \"comment\"\n{relation}");
+        let belongs_to = |on_delete: &str| {
+            comment(&format!(
+                "\n[[resources.relations]]\nkind = \"belongs_to\"\ntarget = \"post\"\non_delete = \"{on_delete}\"\n"
+            ))
+        };
+
+        let added = changes(&comment(""), &belongs_to("cascade"));
+        assert_eq!(added.len(), 1);
+        assert_eq!(
+            added[0].up,
+            "ALTER TABLE comment ADD COLUMN post_id INT NOT NULL REFERENCES post(id) ON DELETE CASCADE;\n"
+        );
+        assert!(added[0].destructive.is_some());
+
+        let nullable = changes(&comment(""), &belongs_to("set_null"));
+        assert_eq!(nullable.len(), 1);
+        assert!(nullable[0].destructive.is_none());
+    }
+
+    #[test]
+    fn creates_and_drops_join_tables() {
+        let tag = "\n[[resources]]\nname = \"tag\"\n";
+        let old = format!("{POST}{tag}");
+        let new = format!(
+            "{POST}\n[[resources.relations]]\nkind = \"many_to_many\"\ntarget = \"tag\"\n{tag}"
+        );
+
+        let created = changes(&old, &new);
+        assert_eq!(created.len(), 1);
+        assert!(created[0].up.starts_with(
+            "CREATE TABLE IF NOT EXISTS post_tag (\n    post_id INT NOT NULL REFERENCES post(id) ON DELETE CASCADE,\n"
+        ));
+        assert_eq!(created[0].down, "DROP TABLE post_tag;\n");
+        assert!(created[0].destructive.is_none());
+
+        let dropped = changes(&new, &old);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].up, "DROP TABLE post_tag;\n");
+        assert_eq!(dropped[0].down, created[0].up);
+        assert!(dropped[0].destructive.is_some());
+    }
+
+    #[test]
+    fn creates_and_drops_feature_tables() {
+        let old = "auth = true\n";
+        let new = "auth = true\nrate_limit = true\nrate_limit_store = \"postgres\"\n";
+
+        let created = changes(old, new);
+        let table = created
+            .iter()
+            .find(|x| {
+                x.up.starts_with(&format!("CREATE TABLE IF NOT EXISTS {RATE_LIMITS}"))
+            })
+            .unwrap();
+        assert_eq!(table.down, format!("DROP TABLE {RATE_LIMITS};\n"));
+        assert!(created.iter().all(|x| x.destructive.is_none()));
+
+        let dropped = changes(new, old);
+        let table = dropped
+            .iter()
+            .find(|x| x.up == format!("DROP TABLE {RATE_LIMITS};\n"))
+            .unwrap();
+        assert!(table.destructive.is_some());
+    }
+
+    #[test]
+    fn adds_the_tenant_column_when_multi_tenancy_is_turned_on() {
+        let old = format!(
+            "auth = true\n{}",
+            POST.replace("\"post\"", "\"post\"\nprotected = true")
+        );
+        let new = format!("multi_tenant = true\n{old}");
+
+        let enabled = changes(&old, &new);
+        assert!(enabled[0]
+            .up
+            .starts_with(&format!("CREATE TABLE IF NOT EXISTS {ORGANIZATIONS}")));
+        assert_eq!(
+            enabled[1].up,
+            format!(
+                "ALTER TABLE post ADD COLUMN {TENANT_COLUMN} INT NOT NULL REFERENCES {ORGANIZATIONS}(id) ON DELETE CASCADE;\n\
+                 CREATE INDEX post_{TENANT_COLUMN}_idx ON post ({TENANT_COLUMN});\n"
+            )
+        );
+        assert_eq!(
+            enabled[1].down,
+            format!("ALTER TABLE post DROP COLUMN {TENANT_COLUMN};\n")
+        );
+        assert!(enabled[1].destructive.is_some());
+        assert!(enabled[2]
+            .up
+            .starts_with(&format!("CREATE TABLE IF NOT EXISTS {MEMBERSHIPS}")));
+
+        // Organizations are dropped last, once nothing references them
+        let disabled = changes(&new, &old);
+        assert_eq!(disabled[0].up, enabled[1].down);
+        assert!(disabled[0].destructive.is_some());
+        assert_eq!(
+            disabled.last().unwrap().up,
+            format!("DROP TABLE {ORGANIZATIONS};\n")
+        );
+    }
+
+    #[test]
+    fn creates_tables_with_the_tenant_column() {
+        let new = format!(
+            "auth = true\nmulti_tenant = true\n{}",
+            POST.replace("\"post\"", "\"post\"\nprotected = true")
+        );
+
+        let created = changes("", &new);
+        let post = created
+            .iter()
+            .find(|x| x.up.starts_with("CREATE TABLE IF NOT EXISTS post"))
+            .unwrap();
+        assert!(post.up.contains(&format!(
+            "last_updated TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,\n    {TENANT_COLUMN} INT NOT NULL"
+        )));
+    }
 }
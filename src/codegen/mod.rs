@@ -1,5 +1,22 @@
+pub mod api_keys;
 pub mod axum_auth;
 pub mod axum_snippets;
+pub mod csrf;
+pub mod diesel;
+pub mod health;
+pub mod integration_tests;
+pub mod mailer;
 pub mod main_fn;
+pub mod metrics;
 pub mod migration_file;
+pub mod password;
+pub mod payments;
 pub mod queries;
+pub mod rate_limit;
+pub mod repositories;
+pub mod seaorm;
+pub mod secrets;
+pub mod server_config;
+pub mod sqlx_offline;
+pub mod tenants;
+pub mod workspace;
@@ -0,0 +1,132 @@
+use crate::cli::{Config, PasswordHash};
+use proc_macro2::Literal;
+use quote::quote;
+use syn::File;
+
+/// Hashing and verifying passwords with the chosen algorithm. Both run on a blocking thread,
+/// since they're slow on purpose, and login uses `needs_rehash` to move passwords hashed with
+/// older parameters onto the current ones.
+pub fn password_file(cfg: &Config) -> File {
+    let params = cfg.hash_params;
+
+    let code = match cfg.password_hash {
+        PasswordHash::Argon2id => {
+            let memory_kib = Literal::u32_unsuffixed(params.argon2_memory_kib);
+            let iterations = Literal::u32_unsuffixed(params.argon2_iterations);
+            let parallelism = Literal::u32_unsuffixed(params.argon2_parallelism);
+
+            quote! {
+                use argon2::password_hash::rand_core::OsRng;
+                use argon2::password_hash::{
+                    Error, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+                };
+                use argon2::{Algorithm, Argon2, Params, Version};
+                use tokio::task::spawn_blocking;
+
+                /// How much memory in KiB, how many passes and how many lanes passwords are hashed
+                /// with. Raising them makes hashes harder to crack, and each user's hash is
+                /// upgraded the next time they log in.
+                const MEMORY_KIB: u32 = #memory_kib;
+                const ITERATIONS: u32 = #iterations;
+                const PARALLELISM: u32 = #parallelism;
+
+                fn hasher() -> Argon2<'static> {
+                    let params = Params::new(MEMORY_KIB, ITERATIONS, PARALLELISM, None)
+                        .expect("the argon2 parameters should be valid");
+
+                    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                }
+
+                pub async fn hash_password(password: String) -> Result<String, String> {
+                    spawn_blocking(move || {
+                        let salt = SaltString::generate(&mut OsRng);
+
+                        hasher()
+                            .hash_password(password.as_bytes(), &salt)
+                            .map(|x| x.to_string())
+                            .map_err(|e| e.to_string())
+                    })
+                    .await
+                    .map_err(|e| e.to_string())?
+                }
+
+                /// Passwords stored before argon2id was used were hashed with bcrypt. They're still
+                /// checked with bcrypt, and moved onto argon2id when their users log in.
+                fn is_bcrypt(hash: &str) -> bool {
+                    hash.starts_with("$2")
+                }
+
+                /// Checks a password against its hash, using the parameters stored in the hash.
+                pub async fn verify_password(password: String, hash: String) -> Result<bool, String> {
+                    spawn_blocking(move || {
+                        if is_bcrypt(&hash) {
+                            return bcrypt::verify(password, &hash).map_err(|e| e.to_string());
+                        }
+
+                        let hash = PasswordHash::new(&hash).map_err(|e| e.to_string())?;
+
+                        match hasher().verify_password(password.as_bytes(), &hash) {
+                            Ok(()) => Ok(true),
+                            Err(Error::Password) => Ok(false),
+                            Err(e) => Err(e.to_string()),
+                        }
+                    })
+                    .await
+                    .map_err(|e| e.to_string())?
+                }
+
+                /// Whether a hash was made with anything other than the current parameters.
+                pub fn needs_rehash(hash: &str) -> bool {
+                    if is_bcrypt(hash) {
+                        return true;
+                    }
+
+                    let Ok(hash) = PasswordHash::new(hash) else {
+                        return true;
+                    };
+                    let Ok(params) = Params::try_from(&hash) else {
+                        return true;
+                    };
+
+                    hash.algorithm != Algorithm::Argon2id.ident()
+                        || params.m_cost() != MEMORY_KIB
+                        || params.t_cost() != ITERATIONS
+                        || params.p_cost() != PARALLELISM
+                }
+            }
+        }
+        PasswordHash::Bcrypt => {
+            let cost = Literal::u32_unsuffixed(params.bcrypt_cost);
+
+            quote! {
+                use bcrypt::HashParts;
+                use tokio::task::spawn_blocking;
+
+                /// bcrypt's cost factor. Each step doubles the time hashing takes, and each user's
+                /// hash is upgraded the next time they log in.
+                const COST: u32 = #cost;
+
+                pub async fn hash_password(password: String) -> Result<String, String> {
+                    spawn_blocking(move || bcrypt::hash(password, COST).map_err(|e| e.to_string()))
+                        .await
+                        .map_err(|e| e.to_string())?
+                }
+
+                /// Checks a password against its hash, using the cost stored in the hash.
+                pub async fn verify_password(password: String, hash: String) -> Result<bool, String> {
+                    spawn_blocking(move || bcrypt::verify(password, &hash).map_err(|e| e.to_string()))
+                        .await
+                        .map_err(|e| e.to_string())?
+                }
+
+                /// Whether a hash was made with anything other than the current cost.
+                pub fn needs_rehash(hash: &str) -> bool {
+                    hash.parse::<HashParts>()
+                        .map_or(true, |x| x.get_cost() != COST)
+                }
+            }
+        }
+    };
+
+    syn::parse_file(&code.to_string()).unwrap()
+}
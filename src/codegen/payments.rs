@@ -0,0 +1,480 @@
+use crate::cli::Config;
+use crate::codegen::axum_auth::instrument_secrets;
+use crate::codegen::csrf::csrf_protected;
+use proc_macro2::{Literal, TokenStream};
+use quote::quote;
+use syn::File;
+
+/// Where the client sends its requests, unless it's pointed somewhere else, like a mock server.
+pub const STRIPE_API_BASE: &str = "https://api.stripe.com";
+/// The header Stripe signs its webhooks in.
+pub const STRIPE_SIGNATURE_HEADER: &str = "stripe-signature";
+/// How old a webhook's signature can be before it's turned away, so a captured request can't be
+/// replayed later.
+pub const WEBHOOK_TOLERANCE_SECS: i64 = 300;
+/// The tables that events and the subscriptions they describe are kept in.
+pub const PAYMENTS: &str = "payments";
+pub const SUBSCRIPTIONS: &str = "subscriptions";
+
+/// Whether the app takes payments, which are always made by a logged in user.
+pub fn takes_payments(cfg: &Config) -> bool {
+    cfg.auth && cfg.payments.is_some()
+}
+
+/// The `StripeClient` the payment routes talk to Stripe through, which also checks the
+/// signatures on its webhooks.
+pub fn stripe_file() -> File {
+    let tolerance = Literal::i64_unsuffixed(WEBHOOK_TOLERANCE_SECS);
+
+    let code = quote! {
+        use hmac::{Hmac, Mac};
+        use serde_json::Value;
+        use sha2::Sha256;
+
+        pub const STRIPE_API_BASE: &str = #STRIPE_API_BASE;
+        /// The header Stripe signs its webhooks in, as `t=<timestamp>,v1=<signature>`.
+        pub const SIGNATURE_HEADER: &str = #STRIPE_SIGNATURE_HEADER;
+        /// How many seconds a webhook's signature is good for.
+        const TOLERANCE_SECS: i64 = #tolerance;
+
+        /// Talks to Stripe's API with the app's secret key. `AppState` holds one, which `main`
+        /// sets up with the configured keys and the tests point at a mock server.
+        #[derive(Clone)]
+        pub struct StripeClient {
+            http: reqwest::Client,
+            /// Where requests are sent, which is Stripe's API unless it's been changed.
+            pub api_base: String,
+            secret_key: String,
+            webhook_secret: String,
+            /// The price checkout sessions are for.
+            pub price_id: String,
+            /// The only origin Stripe is allowed to send users back to.
+            redirect_origin: String,
+        }
+
+        impl Default for StripeClient {
+            /// A client without any keys, whose requests fail and which turns away every webhook
+            /// and redirect.
+            fn default() -> Self {
+                Self::new("", "", "", "")
+            }
+        }
+
+        impl StripeClient {
+            pub fn new(secret_key: &str, webhook_secret: &str, price_id: &str, redirect_origin: &str) -> Self {
+                Self {
+                    http: reqwest::Client::new(),
+                    api_base: STRIPE_API_BASE.to_string(),
+                    secret_key: secret_key.to_string(),
+                    webhook_secret: webhook_secret.to_string(),
+                    price_id: price_id.to_string(),
+                    redirect_origin: redirect_origin.to_string(),
+                }
+            }
+
+            /// Checks that a URL Stripe sends the user back to is on the app's own origin, so the
+            /// payment routes can't be used to redirect users anywhere else.
+            pub fn check_redirect(&self, url: &str) -> Result<(), String> {
+                let origin = |x: &str| reqwest::Url::parse(x).map(|x| x.origin());
+
+                match (origin(url), origin(&self.redirect_origin)) {
+                    (Ok(url), Ok(allowed)) if url.is_tuple() && url == allowed => Ok(()),
+                    _ => Err(format!("Users can only be sent back to {}", self.redirect_origin)),
+                }
+            }
+
+            /// Creates the customer a user's payments are made as, returning its id.
+            pub async fn create_customer(&self, user_id: i32, username: &str) -> Result<String, String> {
+                let user_id = user_id.to_string();
+                let customer = self
+                    .post("/v1/customers", &[("name", username), ("metadata[user_id]", &user_id)])
+                    .await?;
+
+                string_field(&customer, "id")
+            }
+
+            /// Starts a subscription to the configured price, returning the URL of the page that
+            /// takes the payment. The user's id comes back in the session's webhook as its
+            /// `client_reference_id`.
+            pub async fn create_checkout_session(
+                &self,
+                customer: &str,
+                user_id: i32,
+                success_url: &str,
+                cancel_url: &str,
+            ) -> Result<String, String> {
+                let user_id = user_id.to_string();
+                let session = self
+                    .post(
+                        "/v1/checkout/sessions",
+                        &[
+                            ("mode", "subscription"),
+                            ("customer", customer),
+                            ("client_reference_id", &user_id),
+                            ("line_items[0][price]", &self.price_id),
+                            ("line_items[0][quantity]", "1"),
+                            ("success_url", success_url),
+                            ("cancel_url", cancel_url),
+                        ],
+                    )
+                    .await?;
+
+                string_field(&session, "url")
+            }
+
+            /// Returns the URL of the customer portal, where a customer can manage their
+            /// subscription and payment details.
+            pub async fn create_portal_session(&self, customer: &str, return_url: &str) -> Result<String, String> {
+                let session = self
+                    .post(
+                        "/v1/billing_portal/sessions",
+                        &[("customer", customer), ("return_url", return_url)],
+                    )
+                    .await?;
+
+                string_field(&session, "url")
+            }
+
+            /// Checks a webhook's `Stripe-Signature` header against its body, returning the
+            /// event it holds.
+            pub fn verify_webhook(&self, payload: &[u8], signature: &str) -> Result<Value, String> {
+                if self.webhook_secret.is_empty() {
+                    return Err("There's no webhook secret to check the signature with".to_string());
+                }
+
+                let mut timestamp = None;
+                let mut signatures = Vec::new();
+
+                for part in signature.split(',') {
+                    match part.split_once('=') {
+                        Some(("t", x)) => timestamp = x.parse::<i64>().ok(),
+                        Some(("v1", x)) => signatures.push(x),
+                        _ => {}
+                    }
+                }
+
+                let Some(timestamp) = timestamp else {
+                    return Err("The signature is missing its timestamp".to_string());
+                };
+
+                if (chrono::Utc::now().timestamp() - timestamp).abs() > TOLERANCE_SECS {
+                    return Err("The signature has expired".to_string());
+                }
+
+                // `verify_slice` compares in constant time
+                let valid = signatures
+                    .iter()
+                    .filter_map(|x| hex::decode(x).ok())
+                    .any(|x| self.mac(timestamp, payload).verify_slice(&x).is_ok());
+
+                if !valid {
+                    return Err("The signature doesn't match".to_string());
+                }
+
+                serde_json::from_slice(payload).map_err(|e| format!("The event isn't valid JSON: {e}"))
+            }
+
+            /// Signs a payload the way Stripe does, for sending webhooks in tests.
+            pub fn sign_webhook(&self, payload: &[u8], timestamp: i64) -> String {
+                let signature = hex::encode(self.mac(timestamp, payload).finalize().into_bytes());
+
+                format!("t={timestamp},v1={signature}")
+            }
+
+            fn mac(&self, timestamp: i64, payload: &[u8]) -> Hmac<Sha256> {
+                let mut mac = Hmac::<Sha256>::new_from_slice(self.webhook_secret.as_bytes())
+                    .expect("HMAC takes keys of any length");
+                mac.update(format!("{timestamp}.").as_bytes());
+                mac.update(payload);
+
+                mac
+            }
+
+            /// Sends a form to the API, which is how Stripe takes its parameters.
+            async fn post(&self, path: &str, form: &[(&str, &str)]) -> Result<Value, String> {
+                let res = self
+                    .http
+                    .post(format!("{}{path}", self.api_base))
+                    .bearer_auth(&self.secret_key)
+                    .form(form)
+                    .send()
+                    .await
+                    .map_err(|e| format!("Couldn't reach Stripe: {e}"))?;
+
+                let status = res.status();
+                let body: Value = res
+                    .json()
+                    .await
+                    .map_err(|e| format!("Stripe's response couldn't be read: {e}"))?;
+
+                if !status.is_success() {
+                    let message = body["error"]["message"].as_str().unwrap_or("no message");
+                    return Err(format!("Stripe responded with {status}: {message}"));
+                }
+
+                Ok(body)
+            }
+        }
+
+        fn string_field(object: &Value, key: &str) -> Result<String, String> {
+            object[key]
+                .as_str()
+                .map(|x| x.to_string())
+                .ok_or_else(|| format!("Stripe's response is missing `{key}`"))
+        }
+    };
+
+    syn::parse_file(&code.to_string()).unwrap()
+}
+
+/// The checkout and portal routes for logged in users, and the webhook Stripe sends its events
+/// to.
+pub fn payment_routes(cfg: &Config) -> File {
+    let instrument = instrument_secrets(cfg);
+
+    let record_event = format!(
+        "INSERT INTO {PAYMENTS} (stripe_event_id, event_type, user_id, amount, currency) \
+         VALUES ($1, $2, $3, $4, $5) ON CONFLICT (stripe_event_id) DO NOTHING"
+    );
+    let upsert_subscription = format!(
+        "INSERT INTO {SUBSCRIPTIONS} (user_id, stripe_subscription_id, status, price_id, current_period_end) \
+         VALUES ($1, $2, $3, $4, to_timestamp($5)) \
+         ON CONFLICT (stripe_subscription_id) DO UPDATE SET status = EXCLUDED.status, \
+         price_id = EXCLUDED.price_id, current_period_end = EXCLUDED.current_period_end, updated_at = now()"
+    );
+
+    let code = quote! {
+        use crate::middleware::auth::UserInfo;
+        use crate::stripe::SIGNATURE_HEADER;
+        use crate::AppState;
+        use axum::body::Bytes;
+        use axum::extract::State;
+        use axum::http::{HeaderMap, StatusCode};
+        use axum::{Extension, Json};
+        use serde::{Deserialize, Serialize};
+        use std::fmt::Display;
+
+        #[derive(Deserialize)]
+        pub struct CheckoutRequest {
+            pub success_url: String,
+            pub cancel_url: String,
+        }
+
+        #[derive(Deserialize)]
+        pub struct PortalRequest {
+            pub return_url: String,
+        }
+
+        /// The Stripe page to send the user to next.
+        #[derive(Serialize)]
+        pub struct PaymentRedirect {
+            pub url: String,
+        }
+
+        /// Starts a checkout for the configured price, creating the user's Stripe customer the
+        /// first time.
+        #instrument
+        pub async fn checkout(
+            State(state): State<AppState>,
+            Extension(user): Extension<UserInfo>,
+            Json(req): Json<CheckoutRequest>,
+        ) -> Result<Json<PaymentRedirect>, (StatusCode, String)> {
+            for url in [&req.success_url, &req.cancel_url] {
+                state.stripe.check_redirect(url).map_err(bad_request)?;
+            }
+
+            let customer = customer_id(&state, user.user_id).await?;
+
+            let url = state
+                .stripe
+                .create_checkout_session(&customer, user.user_id, &req.success_url, &req.cancel_url)
+                .await
+                .map_err(bad_gateway)?;
+
+            Ok(Json(PaymentRedirect { url }))
+        }
+
+        /// Opens the customer portal, for users who've checked out before.
+        #instrument
+        pub async fn portal(
+            State(state): State<AppState>,
+            Extension(user): Extension<UserInfo>,
+            Json(req): Json<PortalRequest>,
+        ) -> Result<Json<PaymentRedirect>, (StatusCode, String)> {
+            state.stripe.check_redirect(&req.return_url).map_err(bad_request)?;
+
+            let (customer,): (Option<String>,) = sqlx::query_as("SELECT stripe_customer_id FROM users WHERE id = $1")
+                .bind(user.user_id)
+                .fetch_one(&state.db)
+                .await
+                .map_err(internal_error)?;
+
+            let Some(customer) = customer else {
+                return Err((StatusCode::NOT_FOUND, "You haven't made any payments yet".to_string()));
+            };
+
+            let url = state
+                .stripe
+                .create_portal_session(&customer, &req.return_url)
+                .await
+                .map_err(bad_gateway)?;
+
+            Ok(Json(PaymentRedirect { url }))
+        }
+
+        /// Receives Stripe's events. Each one is recorded in `payments` by its id in the same
+        /// transaction that applies it, so an event that's delivered more than once is only
+        /// applied once.
+        #instrument
+        pub async fn stripe_webhook(
+            State(state): State<AppState>,
+            headers: HeaderMap,
+            body: Bytes,
+        ) -> Result<StatusCode, (StatusCode, String)> {
+            let Some(signature) = headers.get(SIGNATURE_HEADER).and_then(|x| x.to_str().ok()) else {
+                return Err((StatusCode::BAD_REQUEST, "Missing Stripe-Signature header".to_string()));
+            };
+
+            let event = state
+                .stripe
+                .verify_webhook(&body, signature)
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+            let (Some(event_id), Some(event_type)) = (event["id"].as_str(), event["type"].as_str()) else {
+                return Err((StatusCode::BAD_REQUEST, "The event is missing its id or type".to_string()));
+            };
+            let object = &event["data"]["object"];
+            let customer = object["customer"].as_str();
+
+            let mut tx = state.db.begin().await.map_err(internal_error)?;
+
+            // Checkouts say which user started them, and everything else is matched up by the
+            // customer it's for
+            let user_id = match object["client_reference_id"].as_str().and_then(|x| x.parse::<i32>().ok()) {
+                Some(user_id) => Some(user_id),
+                None => match customer {
+                    Some(customer) => sqlx::query_as::<_, (i32,)>("SELECT id FROM users WHERE stripe_customer_id = $1")
+                        .bind(customer)
+                        .fetch_optional(&mut *tx)
+                        .await
+                        .map_err(internal_error)?
+                        .map(|(id,)| id),
+                    None => None,
+                },
+            };
+
+            // Checkout sessions have an `amount_total`, and invoices an `amount_paid`
+            let amount = object["amount_total"].as_i64().or(object["amount_paid"].as_i64());
+
+            let recorded = sqlx::query(#record_event)
+                .bind(event_id)
+                .bind(event_type)
+                .bind(user_id)
+                .bind(amount)
+                .bind(object["currency"].as_str())
+                .execute(&mut *tx)
+                .await
+                .map_err(internal_error)?
+                .rows_affected();
+
+            if recorded == 0 {
+                return Ok(StatusCode::OK);
+            }
+
+            match event_type {
+                "checkout.session.completed" => {
+                    if let (Some(user_id), Some(customer)) = (user_id, customer) {
+                        sqlx::query("UPDATE users SET stripe_customer_id = $1 WHERE id = $2 AND stripe_customer_id IS NULL")
+                            .bind(customer)
+                            .bind(user_id)
+                            .execute(&mut *tx)
+                            .await
+                            .map_err(internal_error)?;
+                    }
+                }
+                "customer.subscription.created" | "customer.subscription.updated" | "customer.subscription.deleted" => {
+                    if let (Some(user_id), Some(subscription_id), Some(status)) =
+                        (user_id, object["id"].as_str(), object["status"].as_str())
+                    {
+                        sqlx::query(#upsert_subscription)
+                            .bind(user_id)
+                            .bind(subscription_id)
+                            .bind(status)
+                            .bind(object["items"]["data"][0]["price"]["id"].as_str())
+                            .bind(object["current_period_end"].as_f64())
+                            .execute(&mut *tx)
+                            .await
+                            .map_err(internal_error)?;
+                    }
+                }
+                _ => {}
+            }
+
+            tx.commit().await.map_err(internal_error)?;
+
+            Ok(StatusCode::OK)
+        }
+
+        /// The user's Stripe customer, which is created the first time it's needed.
+        async fn customer_id(state: &AppState, user_id: i32) -> Result<String, (StatusCode, String)> {
+            let (customer, username): (Option<String>, String) =
+                sqlx::query_as("SELECT stripe_customer_id, username FROM users WHERE id = $1")
+                    .bind(user_id)
+                    .fetch_one(&state.db)
+                    .await
+                    .map_err(internal_error)?;
+
+            if let Some(customer) = customer {
+                return Ok(customer);
+            }
+
+            let customer = state
+                .stripe
+                .create_customer(user_id, &username)
+                .await
+                .map_err(bad_gateway)?;
+
+            // If two checkouts both created a customer, the one that was saved first is kept
+            let (customer,): (String,) = sqlx::query_as(
+                "UPDATE users SET stripe_customer_id = COALESCE(stripe_customer_id, $1) WHERE id = $2 RETURNING stripe_customer_id",
+            )
+            .bind(customer)
+            .bind(user_id)
+            .fetch_one(&state.db)
+            .await
+            .map_err(internal_error)?;
+
+            Ok(customer)
+        }
+
+        fn bad_request(e: String) -> (StatusCode, String) {
+            (StatusCode::BAD_REQUEST, e)
+        }
+
+        fn bad_gateway(e: String) -> (StatusCode, String) {
+            (StatusCode::BAD_GATEWAY, e)
+        }
+
+        fn internal_error(e: impl Display) -> (StatusCode, String) {
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Something went wrong: {e}"))
+        }
+    };
+
+    syn::parse_file(&code.to_string()).unwrap()
+}
+
+/// The router for `/payments`. Checkout and the portal need a session (and a CSRF token, when
+/// that's turned on), while the webhook is checked by its signature instead.
+pub fn payments_router(cfg: &Config) -> TokenStream {
+    let csrf = csrf_protected(cfg)
+        .then(|| quote! {.route_layer(from_fn_with_state(state.clone(), check_csrf))});
+
+    quote! {
+        let payments_router = Router::new()
+            .route("/checkout", post(checkout))
+            .route("/portal", post(portal))
+            #csrf
+            .route_layer(from_fn_with_state(state.clone(), check_authed_cookies))
+            .route("/webhook", post(stripe_webhook));
+    }
+}
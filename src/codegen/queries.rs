@@ -1,3 +1,9 @@
+use crate::cli::{Config, Orm};
+use crate::codegen::seaorm::camel_ident;
+use crate::codegen::tenants::TENANT_COLUMN;
+use crate::schema::{
+    foreign_keys, join_tables, table_fields, Field, FieldType, ForeignKey, JoinTable,
+};
 use proc_macro2::{Ident, Span, TokenStream};
 use quote::quote;
 
@@ -6,28 +12,53 @@ pub struct QueryGen {
     pub query_fn_name: Ident,
     pub query: String,
     pub path: Option<TokenStream>,
-    pub bind: Option<TokenStream>,
-    pub fetch_mode: TokenStream,
-    pub querytype: TokenStream,
+    pub body: Option<TokenStream>,
+    /// The values bound to the query's parameters, in order.
+    pub args: Vec<TokenStream>,
+    /// The struct each returned row is mapped to. Queries without one don't return rows.
+    pub output: Option<Ident>,
+    /// How sqlx runs the query: `fetch_all`, `fetch_one` or `execute`.
+    pub fetch: Ident,
     pub response: TokenStream,
     pub declaration: TokenStream,
     pub error_handling: TokenStream,
+    /// The types of the query's parameters, in order.
+    pub params: Vec<FieldType>,
+    /// The columns returned by the query.
+    pub columns: Vec<Field>,
+    /// The same query using SeaORM's entities. Resolves to the same kind of result as the sqlx query.
+    pub seaorm: TokenStream,
+    /// The same query using Diesel's DSL, run on a pooled connection by `AppState::interact`.
+    pub diesel: TokenStream,
+    /// The `AppState` field holding the repository this query belongs to.
+    pub repository: Ident,
+    /// The name of the repository method that runs this query.
+    pub method: Ident,
+    /// The repository method's parameters, which are also what the handler passes to it.
+    pub inputs: Vec<(Ident, TokenStream)>,
+    /// What the repository method returns when it succeeds.
+    pub returns: TokenStream,
+    /// The repository method's body for the in-memory repository, which keeps its rows in `self.rows`.
+    pub in_memory: TokenStream,
 }
 
 impl QueryGen {
-    fn get_all(tablename: &str) -> Self {
-        let mut v: Vec<char> = tablename.chars().collect();
-        v[0] = v[0].to_uppercase().next().unwrap();
-        let tablename_titlecase: String = v.into_iter().collect();
+    fn get_all(tablename: &str, fields: &[Field], tenant: bool) -> Self {
+        let entity = module_ident(tablename);
+        let output = struct_ident(tablename);
+
+        let (query, mut args, mut params) =
+            (format!("SELECT * FROM {tablename}"), Vec::new(), Vec::new());
+        let query = scope_to_tenant(tenant, query, &mut args, &mut params, "WHERE", tablename);
 
-        let structname = Ident::new(&tablename_titlecase, Span::call_site());
         Self {
             query_fn_name: Ident::new(&format!("get_all_{tablename}"), Span::call_site()),
-            query: format!("SELECT * FROM {tablename}"),
+            query,
             path: None,
-            bind: None,
-            fetch_mode: quote! {.fetch_all(&state.db)},
-            querytype: quote! {sqlx::query_as::<_, #structname>},
+            body: None,
+            args,
+            output: Some(struct_ident(tablename)),
+            fetch: Ident::new("fetch_all", Span::call_site()),
             response: quote! {Ok((StatusCode::OK, Json(res)))},
             declaration: quote! {let res = match },
             error_handling: quote! {Ok(res) => res,
@@ -35,22 +66,38 @@ impl QueryGen {
                     StatusCode::INTERNAL_SERVER_ERROR,
                     e.to_string()
                 ))},
+            params,
+            columns: table_columns(fields, tenant),
+            seaorm: quote! {#entity::Entity::find().all(&state.orm)},
+            diesel: quote! {#entity::table.select(#output::as_select()).load(conn)},
+            repository: repository_ident(tablename),
+            method: Ident::new("get_all", Span::call_site()),
+            inputs: Vec::new(),
+            returns: quote! {Vec<#output>},
+            in_memory: quote! {Ok(self.rows.lock().unwrap().clone())},
         }
     }
 
-    fn get_one(tablename: &str) -> Self {
-        let mut v: Vec<char> = tablename.chars().collect();
-        v[0] = v[0].to_uppercase().next().unwrap();
-        let tablename_titlecase: String = v.into_iter().collect();
+    fn get_one(tablename: &str, fields: &[Field], tenant: bool) -> Self {
+        let entity = module_ident(tablename);
+        let output = struct_ident(tablename);
+        let not_found = format!("No {tablename} with id {{id}}");
+
+        let (query, mut args, mut params) = (
+            format!("SELECT * FROM {tablename} WHERE id = $1"),
+            vec![quote! {id}],
+            vec![FieldType::Integer],
+        );
+        let query = scope_to_tenant(tenant, query, &mut args, &mut params, "AND", tablename);
 
-        let structname = Ident::new(&tablename_titlecase, Span::call_site());
         Self {
             query_fn_name: Ident::new(&format!("get_{tablename}_by_id"), Span::call_site()),
-            query: format!("SELECT * FROM {tablename} WHERE id = $1"),
-            path: Some(quote! {Path(id): Path<i32>}),
-            bind: Some(quote! {.bind(id)}),
-            fetch_mode: quote! {.fetch_one(&state.db)},
-            querytype: quote! {sqlx::query_as::<_, #structname>},
+            query,
+            path: Some(quote! {Path(id): Path<i32>,}),
+            body: None,
+            args,
+            output: Some(struct_ident(tablename)),
+            fetch: Ident::new("fetch_one", Span::call_site()),
             response: quote! {Ok((StatusCode::OK, Json(res)))},
             declaration: quote! {let res = match },
             error_handling: quote! {Ok(res) => res,
@@ -58,67 +105,803 @@ impl QueryGen {
                     StatusCode::INTERNAL_SERVER_ERROR,
                     e.to_string()
                 ))},
+            params,
+            columns: table_columns(fields, tenant),
+            // Like `fetch_one`, a missing row is an error
+            seaorm: quote! {
+                async {
+                    #entity::Entity::find_by_id(id)
+                        .one(&state.orm)
+                        .await
+                        .and_then(|x| x.ok_or(DbErr::RecordNotFound(format!(#not_found))))
+                }
+            },
+            diesel: quote! {#entity::table.find(id).select(#output::as_select()).first(conn)},
+            repository: repository_ident(tablename),
+            method: Ident::new("get_by_id", Span::call_site()),
+            inputs: vec![id_input()],
+            returns: quote! {#output},
+            in_memory: quote! {
+                self.rows
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .find(|x| x.id == id)
+                    .cloned()
+                    .ok_or(format!(#not_found))
+            },
         }
     }
 
-    fn create(tablename: &str) -> Self {
+    fn create(tablename: &str, fields: &[Field], tenant: bool, parents: &[ForeignKey]) -> Self {
+        let payload = payload_ident(tablename);
+        let output = struct_ident(tablename);
+        let entity = module_ident(tablename);
+        let names = field_idents(fields);
+
+        let insert = if fields.is_empty() {
+            quote! {diesel::insert_into(#entity::table).default_values().execute(conn)}
+        } else {
+            quote! {diesel::insert_into(#entity::table).values(payload).execute(conn)}
+        };
+
+        let mut columns: Vec<&str> = fields.iter().map(|x| x.name.as_str()).collect();
+        let mut args = payload_args(fields);
+        let mut params: Vec<FieldType> = fields.iter().map(|x| x.ty).collect();
+
+        // The organization is written along with the payload, and a row can only refer to rows
+        // in the same organization
+        let checks = if tenant {
+            columns.push(TENANT_COLUMN);
+            args.push(quote! {tenant.id});
+            params.push(FieldType::Integer);
+
+            parent_checks(fields, parents, params.len())
+        } else {
+            Vec::new()
+        };
+
+        let placeholders: Vec<String> = (1..=columns.len()).map(|x| format!("${x}")).collect();
+
+        let query = if columns.is_empty() {
+            format!("INSERT INTO {tablename} DEFAULT VALUES")
+        } else if checks.is_empty() {
+            format!(
+                "INSERT INTO {tablename} ({}) VALUES ({})",
+                columns.join(", "),
+                placeholders.join(", ")
+            )
+        } else {
+            format!(
+                "INSERT INTO {tablename} ({}) SELECT {} WHERE {}",
+                columns.join(", "),
+                placeholders.join(", "),
+                checks.join(" AND ")
+            )
+        };
+
+        // Nothing is written when a check fails
+        let (declaration, error_handling, response) = if checks.is_empty() {
+            (
+                quote! {if let Err(e) = },
+                quote! {return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    e.to_string()
+                ))},
+                quote! {Ok(StatusCode::CREATED)},
+            )
+        } else {
+            (
+                quote! {let res = match },
+                result_handling(),
+                quote! {
+                    if res.rows_affected() == 0 {
+                        return Err((
+                            StatusCode::NOT_FOUND,
+                            "The rows this refers to have to be in the same organization".to_string()
+                        ));
+                    }
+
+                    Ok(StatusCode::CREATED)
+                },
+            )
+        };
+
         Self {
             query_fn_name: Ident::new(&format!("create_{tablename}"), Span::call_site()),
-            query: format!("INSERT INTO {tablename} () VALUES ()"),
+            query,
             path: None,
-            bind: None,
-            fetch_mode: quote! {.execute(&state.db)},
-            querytype: quote! {sqlx::query},
-            response: quote! {Ok(StatusCode::CREATED)},
-            declaration: quote! {if let Err(e) = },
-            error_handling: quote! {return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                e.to_string()
-            ))},
+            body: (!fields.is_empty()).then(|| quote! {Json(payload): Json<#payload>,}),
+            args,
+            output: None,
+            fetch: Ident::new("execute", Span::call_site()),
+            response,
+            declaration,
+            error_handling,
+            params,
+            columns: Vec::new(),
+            // Struct literals need parentheses to be used as an `if let` scrutinee
+            seaorm: quote! {
+                (#entity::ActiveModel {
+                    #(#names: Set(payload.#names),)*
+                    ..Default::default()
+                })
+                .insert(&state.orm)
+            },
+            diesel: quote! {#insert},
+            repository: repository_ident(tablename),
+            method: Ident::new("create", Span::call_site()),
+            inputs: payload_input(tablename, fields).into_iter().collect(),
+            returns: quote! {()},
+            in_memory: quote! {
+                let mut rows = self.rows.lock().unwrap();
+                let id = rows.iter().map(|x| x.id).max().unwrap_or(0) + 1;
+                let now = Utc::now();
+
+                rows.push(#output {
+                    id,
+                    #(#names: payload.#names,)*
+                    created_at: now,
+                    last_updated: now,
+                });
+
+                Ok(())
+            },
         }
     }
 
-    fn update(tablename: &str) -> Self {
+    fn update(tablename: &str, fields: &[Field], tenant: bool, parents: &[ForeignKey]) -> Self {
+        let payload = payload_ident(tablename);
+        let entity = module_ident(tablename);
+        let names = field_idents(fields);
+
+        let changes = if fields.is_empty() {
+            quote! {#entity::last_updated.eq(diesel::dsl::now)}
+        } else {
+            quote! {(payload, #entity::last_updated.eq(diesel::dsl::now))}
+        };
+
+        let mut assignments: Vec<String> = fields
+            .iter()
+            .enumerate()
+            .map(|(idx, x)| format!("{} = ${}", x.name, idx + 1))
+            .collect();
+        assignments.push("last_updated = CURRENT_TIMESTAMP".to_string());
+
+        let mut args = payload_args(fields);
+        args.push(quote! {id});
+
+        let mut inputs = vec![id_input()];
+        inputs.extend(payload_input(tablename, fields));
+
+        let mut params: Vec<FieldType> = fields.iter().map(|x| x.ty).collect();
+        params.push(FieldType::Integer);
+
+        let query = format!(
+            "UPDATE {tablename} SET {} WHERE id = ${}",
+            assignments.join(", "),
+            fields.len() + 1
+        );
+        let mut query = scope_to_tenant(tenant, query, &mut args, &mut params, "AND", tablename);
+
+        if tenant {
+            for check in parent_checks(fields, parents, params.len()) {
+                query.push_str(&format!(" AND {check}"));
+            }
+        }
+
+        let (declaration, error_handling, response) = scoped_write(tenant, tablename);
+
         Self {
             query_fn_name: Ident::new(&format!("update_{tablename}_by_id"), Span::call_site()),
-            query: format!("UPDATE {tablename} set $1 = $2 WHERE id = $3"),
-            path: Some(quote! {Path(id): Path<i32>}),
-            bind: Some(quote! {.bind(id)}),
-            fetch_mode: quote! {.fetch_all(&state.db)},
-            querytype: quote! {sqlx::query},
-            response: quote! {Ok(StatusCode::OK)},
+            query,
+            path: Some(quote! {Path(id): Path<i32>,}),
+            body: (!fields.is_empty()).then(|| quote! {Json(payload): Json<#payload>,}),
+            args,
+            output: None,
+            fetch: Ident::new("execute", Span::call_site()),
+            response,
+            declaration,
+            error_handling,
+            params,
+            columns: Vec::new(),
+            seaorm: quote! {
+                (#entity::ActiveModel {
+                    id: Unchanged(id),
+                    #(#names: Set(payload.#names),)*
+                    last_updated: Set(Utc::now()),
+                    ..Default::default()
+                })
+                .update(&state.orm)
+            },
+            diesel: quote! {diesel::update(#entity::table.find(id)).set(#changes).execute(conn)},
+            repository: repository_ident(tablename),
+            method: Ident::new("update_by_id", Span::call_site()),
+            inputs,
+            returns: quote! {()},
+            in_memory: quote! {
+                if let Some(row) = self.rows.lock().unwrap().iter_mut().find(|x| x.id == id) {
+                    #(row.#names = payload.#names;)*
+                    row.last_updated = Utc::now();
+                }
+
+                Ok(())
+            },
+        }
+    }
+
+    fn delete(tablename: &str, tenant: bool) -> Self {
+        let entity = module_ident(tablename);
+
+        let (query, mut args, mut params) = (
+            format!("DELETE FROM {tablename} WHERE id = $1"),
+            vec![quote! {id}],
+            vec![FieldType::Integer],
+        );
+        let query = scope_to_tenant(tenant, query, &mut args, &mut params, "AND", tablename);
+        let (declaration, error_handling, response) = scoped_write(tenant, tablename);
+
+        Self {
+            query_fn_name: Ident::new(&format!("delete_{tablename}_by_id"), Span::call_site()),
+            query,
+            path: Some(quote! {Path(id): Path<i32>,}),
+            body: None,
+            args,
+            output: None,
+            fetch: Ident::new("execute", Span::call_site()),
+            response,
+            declaration,
+            error_handling,
+            params,
+            columns: Vec::new(),
+            seaorm: quote! {#entity::Entity::delete_by_id(id).exec(&state.orm)},
+            diesel: quote! {diesel::delete(#entity::table.find(id)).execute(conn)},
+            repository: repository_ident(tablename),
+            method: Ident::new("delete_by_id", Span::call_site()),
+            inputs: vec![id_input()],
+            returns: quote! {()},
+            in_memory: quote! {
+                self.rows.lock().unwrap().retain(|x| x.id != id);
+
+                Ok(())
+            },
+        }
+    }
+
+    fn get_by_parent(tablename: &str, parent: &str, fields: &[Field], tenant: bool) -> Self {
+        let entity = module_ident(tablename);
+        let output = struct_ident(tablename);
+        let column = camel_ident(&format!("{parent}_id"));
+        let parent_id = Ident::new(&format!("{parent}_id"), Span::call_site());
+
+        // Foreign keys that are set to null on delete are optional
+        let nullable = fields
+            .iter()
+            .any(|x| x.name == format!("{parent}_id") && x.nullable);
+        let parent_value = if nullable {
+            quote! {Some(id)}
+        } else {
+            quote! {id}
+        };
+
+        let (query, mut args, mut params) = (
+            format!("SELECT * FROM {tablename} WHERE {parent}_id = $1"),
+            vec![quote! {id}],
+            vec![FieldType::Integer],
+        );
+        let query = scope_to_tenant(tenant, query, &mut args, &mut params, "AND", tablename);
+
+        Self {
+            query_fn_name: Ident::new(&format!("get_{tablename}_by_{parent}"), Span::call_site()),
+            query,
+            path: Some(quote! {Path(id): Path<i32>,}),
+            body: None,
+            args,
+            output: Some(struct_ident(tablename)),
+            fetch: Ident::new("fetch_all", Span::call_site()),
+            response: quote! {Ok((StatusCode::OK, Json(res)))},
+            declaration: quote! {let res = match },
+            error_handling: quote! {Ok(res) => res,
+            Err(e) => return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    e.to_string()
+                ))},
+            params,
+            columns: table_columns(fields, tenant),
+            seaorm: quote! {
+                #entity::Entity::find()
+                    .filter(#entity::Column::#column.eq(id))
+                    .all(&state.orm)
+            },
+            diesel: quote! {
+                #entity::table
+                    .filter(#entity::#parent_id.eq(id))
+                    .select(#output::as_select())
+                    .load(conn)
+            },
+            repository: repository_ident(tablename),
+            method: Ident::new(&format!("get_by_{parent}"), Span::call_site()),
+            inputs: vec![id_input()],
+            returns: quote! {Vec<#output>},
+            in_memory: quote! {
+                Ok(self
+                    .rows
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|x| x.#parent_id == #parent_value)
+                    .cloned()
+                    .collect())
+            },
+        }
+    }
+
+    fn get_by_join(
+        tablename: &str,
+        other: &str,
+        join: &JoinTable,
+        fields: &[Field],
+        tenant: bool,
+    ) -> Self {
+        let join_name = join.name();
+        let entity = module_ident(tablename);
+        let join_entity = module_ident(&join_name);
+        let output = struct_ident(tablename);
+        let column = camel_ident(&format!("{other}_id"));
+        let other_id = Ident::new(&format!("{other}_id"), Span::call_site());
+
+        // Links are stored as (left id, right id)
+        let link = if join.left == tablename {
+            quote! {(x.id, id)}
+        } else {
+            quote! {(id, x.id)}
+        };
+
+        let (query, mut args, mut params) = (
+            format!(
+                "SELECT {tablename}.* FROM {tablename} JOIN {join_name} ON {join_name}.{tablename}_id = {tablename}.id WHERE {join_name}.{other}_id = $1"
+            ),
+            vec![quote! {id}],
+            vec![FieldType::Integer],
+        );
+        let query = scope_to_tenant(tenant, query, &mut args, &mut params, "AND", tablename);
+
+        Self {
+            query_fn_name: Ident::new(&format!("get_{tablename}_by_{other}"), Span::call_site()),
+            query,
+            path: Some(quote! {Path(id): Path<i32>,}),
+            body: None,
+            args,
+            output: Some(struct_ident(tablename)),
+            fetch: Ident::new("fetch_all", Span::call_site()),
+            response: quote! {Ok((StatusCode::OK, Json(res)))},
+            declaration: quote! {let res = match },
+            error_handling: quote! {Ok(res) => res,
+            Err(e) => return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    e.to_string()
+                ))},
+            params,
+            columns: table_columns(fields, tenant),
+            seaorm: quote! {
+                #entity::Entity::find()
+                    .inner_join(#join_entity::Entity)
+                    .filter(#join_entity::Column::#column.eq(id))
+                    .all(&state.orm)
+            },
+            diesel: quote! {
+                #entity::table
+                    .inner_join(#join_entity::table)
+                    .filter(#join_entity::#other_id.eq(id))
+                    .select(#output::as_select())
+                    .load(conn)
+            },
+            repository: repository_ident(tablename),
+            method: Ident::new(&format!("get_by_{other}"), Span::call_site()),
+            inputs: vec![id_input()],
+            returns: quote! {Vec<#output>},
+            in_memory: quote! {
+                let links = self.#join_entity.lock().unwrap();
+
+                Ok(self
+                    .rows
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|x| links.contains(&#link))
+                    .cloned()
+                    .collect())
+            },
+        }
+    }
+
+    fn link(join: &JoinTable, tenant: bool) -> Self {
+        let (left, right) = (&join.left, &join.right);
+        let join_entity = module_ident(&join.name());
+        let (left_id, right_id) = (
+            module_ident(&format!("{left}_id")),
+            module_ident(&format!("{right}_id")),
+        );
+
+        let mut args = vec![quote! {id}, quote! {other_id}];
+        let mut params = vec![FieldType::Integer, FieldType::Integer];
+
+        // Rows in different organizations are never linked
+        let query = if tenant {
+            args.push(quote! {tenant.id});
+            params.push(FieldType::Integer);
+
+            format!(
+                "INSERT INTO {} ({left}_id, {right}_id) SELECT $1, $2 WHERE {} AND {} ON CONFLICT DO NOTHING",
+                join.name(),
+                in_tenant(left, 1, 3),
+                in_tenant(right, 2, 3)
+            )
+        } else {
+            format!(
+                "INSERT INTO {} ({left}_id, {right}_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                join.name()
+            )
+        };
+
+        Self {
+            query_fn_name: Ident::new(&format!("add_{right}_to_{left}"), Span::call_site()),
+            query,
+            path: Some(quote! {Path((id, other_id)): Path<(i32, i32)>,}),
+            body: None,
+            args,
+            output: None,
+            fetch: Ident::new("execute", Span::call_site()),
+            response: quote! {Ok(StatusCode::CREATED)},
             declaration: quote! {if let Err(e) = },
             error_handling: quote! {return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 e.to_string()
             ))},
+            params,
+            columns: Vec::new(),
+            seaorm: quote! {
+                #join_entity::Entity::insert(#join_entity::ActiveModel {
+                    #left_id: Set(id),
+                    #right_id: Set(other_id),
+                })
+                .on_conflict(OnConflict::new().do_nothing().to_owned())
+                .exec_without_returning(&state.orm)
+            },
+            diesel: quote! {
+                diesel::insert_into(#join_entity::table)
+                    .values((#join_entity::#left_id.eq(id), #join_entity::#right_id.eq(other_id)))
+                    .on_conflict_do_nothing()
+                    .execute(conn)
+            },
+            repository: repository_ident(left),
+            method: Ident::new(&format!("add_{right}"), Span::call_site()),
+            inputs: vec![id_input(), other_id_input()],
+            returns: quote! {()},
+            in_memory: quote! {
+                let mut links = self.#join_entity.lock().unwrap();
+
+                if !links.contains(&(id, other_id)) {
+                    links.push((id, other_id));
+                }
+
+                Ok(())
+            },
         }
     }
 
-    fn delete(tablename: &str) -> Self {
+    fn unlink(join: &JoinTable, tenant: bool) -> Self {
+        let (left, right) = (&join.left, &join.right);
+        let join_entity = module_ident(&join.name());
+
+        let mut args = vec![quote! {id}, quote! {other_id}];
+        let mut params = vec![FieldType::Integer, FieldType::Integer];
+        let mut query = format!(
+            "DELETE FROM {} WHERE {left}_id = $1 AND {right}_id = $2",
+            join.name()
+        );
+
+        // Both rows are always in the same organization, so checking one of them is enough
+        if tenant {
+            args.push(quote! {tenant.id});
+            params.push(FieldType::Integer);
+            query.push_str(&format!(" AND {}", in_tenant(left, 1, 3)));
+        }
+
         Self {
-            query_fn_name: Ident::new(&format!("delete_{tablename}_by_id"), Span::call_site()),
-            query: format!("SELECT * FROM {tablename}"),
-            path: Some(quote! {Path(id): Path<i32>}),
-            bind: Some(quote! {.bind(id)}),
-            fetch_mode: quote! {.fetch_all(&state.db)},
-            querytype: quote! {sqlx::query},
+            query_fn_name: Ident::new(&format!("remove_{right}_from_{left}"), Span::call_site()),
+            query,
+            path: Some(quote! {Path((id, other_id)): Path<(i32, i32)>,}),
+            body: None,
+            args,
+            output: None,
+            fetch: Ident::new("execute", Span::call_site()),
             response: quote! {Ok(StatusCode::OK)},
             declaration: quote! {if let Err(e) = },
             error_handling: quote! {return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 e.to_string()
             ))},
+            params,
+            columns: Vec::new(),
+            seaorm: quote! {#join_entity::Entity::delete_by_id((id, other_id)).exec(&state.orm)},
+            diesel: quote! {diesel::delete(#join_entity::table.find((id, other_id))).execute(conn)},
+            repository: repository_ident(left),
+            method: Ident::new(&format!("remove_{right}"), Span::call_site()),
+            inputs: vec![id_input(), other_id_input()],
+            returns: quote! {()},
+            in_memory: quote! {
+                self.#join_entity.lock().unwrap().retain(|x| *x != (id, other_id));
+
+                Ok(())
+            },
         }
     }
 
-    pub fn create_query_data(tablename: &str) -> Vec<Self> {
+    /// The future a handler awaits to run this query, using whichever ORM `cfg` asks for.
+    /// With repositories, the handler calls the repository method instead.
+    pub fn call(&self, cfg: &Config) -> TokenStream {
+        if cfg.repositories {
+            let repository = &self.repository;
+            let method = &self.method;
+            let inputs = self.inputs.iter().map(|(name, _)| name);
+
+            return quote! {state.#repository.#method(#(#inputs),*)};
+        }
+
+        match cfg.orm {
+            Orm::Sqlx => self.sqlx_call(cfg.checked_queries, quote! {&state.db}),
+            Orm::SeaOrm => self.seaorm.clone(),
+            Orm::Diesel => {
+                let query = &self.diesel;
+
+                quote! {state.interact(move |conn| #query)}
+            }
+        }
+    }
+
+    /// The sqlx query, run on the pool `db` evaluates to.
+    pub fn sqlx_call(&self, checked: bool, db: TokenStream) -> TokenStream {
+        let query = self.sqlx_query(checked);
+        let fetch = &self.fetch;
+
+        quote! {#query.#fetch(#db)}
+    }
+
+    /// The query itself, minus how it's executed. With `checked` set, this uses sqlx's
+    /// compile-time checked macros instead of binding parameters at runtime.
+    fn sqlx_query(&self, checked: bool) -> TokenStream {
+        let query = &self.query;
+        let args = &self.args;
+
+        match (&self.output, checked) {
+            (Some(output), false) => quote! {
+                sqlx::query_as::<_, #output>(#query)#(.bind(#args))*
+            },
+            (None, false) => quote! {
+                sqlx::query(#query)#(.bind(#args))*
+            },
+            (Some(output), true) => quote! {
+                sqlx::query_as!(#output, #query #(, #args)*)
+            },
+            (None, true) => quote! {
+                sqlx::query!(#query #(, #args)*)
+            },
+        }
+    }
+
+    /// Queries for the nested routes that come from relations involving `tablename`.
+    pub fn relation_query_data(tablename: &str, cfg: &Config) -> Vec<Self> {
+        let (routes, tenant) = (&cfg.routes, cfg.multi_tenant);
+        let mut queries = Vec::new();
+
+        let Some(route) = routes.iter().find(|x| x.name == tablename) else {
+            return queries;
+        };
+        let fields = table_fields(route, routes);
+
+        for key in foreign_keys(routes) {
+            if key.table == tablename {
+                queries.push(QueryGen::get_by_parent(
+                    tablename,
+                    &key.references,
+                    &fields,
+                    tenant,
+                ));
+            }
+        }
+
+        for join in join_tables(routes) {
+            if join.left == tablename {
+                queries.push(QueryGen::get_by_join(
+                    tablename,
+                    &join.right,
+                    &join,
+                    &fields,
+                    tenant,
+                ));
+                queries.push(QueryGen::link(&join, tenant));
+                queries.push(QueryGen::unlink(&join, tenant));
+            } else if join.right == tablename {
+                queries.push(QueryGen::get_by_join(
+                    tablename, &join.left, &join, &fields, tenant,
+                ));
+            }
+        }
+
+        queries
+    }
+
+    /// The CRUD queries for `tablename`. With multi-tenancy, every one of them is scoped to the
+    /// caller's organization.
+    pub fn create_query_data(tablename: &str, fields: &[Field], cfg: &Config) -> Vec<Self> {
+        let tenant = cfg.multi_tenant;
+        let parents: Vec<ForeignKey> = foreign_keys(&cfg.routes)
+            .into_iter()
+            .filter(|x| x.table == tablename)
+            .collect();
+
         vec![
-            QueryGen::get_all(tablename),
-            QueryGen::get_one(tablename),
-            QueryGen::create(tablename),
-            QueryGen::update(tablename),
-            QueryGen::delete(tablename),
+            QueryGen::get_all(tablename, fields, tenant),
+            QueryGen::get_one(tablename, fields, tenant),
+            QueryGen::create(tablename, fields, tenant, &parents),
+            QueryGen::update(tablename, fields, tenant, &parents),
+            QueryGen::delete(tablename, tenant),
         ]
     }
 }
+
+/// With multi-tenancy, adds a condition on the organization to the end of `query`, after
+/// `keyword` (`WHERE` or `AND`), and binds the organization's id as its last parameter.
+fn scope_to_tenant(
+    tenant: bool,
+    mut query: String,
+    args: &mut Vec<TokenStream>,
+    params: &mut Vec<FieldType>,
+    keyword: &str,
+    tablename: &str,
+) -> String {
+    if tenant {
+        args.push(quote! {tenant.id});
+        params.push(FieldType::Integer);
+        query.push_str(&format!(
+            " {keyword} {tablename}.{TENANT_COLUMN} = ${}",
+            params.len()
+        ));
+    }
+
+    query
+}
+
+/// Checks that the row `$id` in `tablename` belongs to the organization `$tenant`.
+fn in_tenant(tablename: &str, id: usize, tenant: usize) -> String {
+    format!("EXISTS (SELECT 1 FROM {tablename} WHERE id = ${id} AND {TENANT_COLUMN} = ${tenant})")
+}
+
+/// Checks that each foreign key in `fields` points at a row in the organization `$tenant`.
+/// Nullable keys can also be left empty.
+fn parent_checks(fields: &[Field], parents: &[ForeignKey], tenant: usize) -> Vec<String> {
+    parents
+        .iter()
+        .filter_map(|key| {
+            let idx = fields.iter().position(|x| x.name == key.column())? + 1;
+            let check = in_tenant(&key.references, idx, tenant);
+
+            Some(if fields[idx - 1].nullable {
+                format!("(${idx}::INT IS NULL OR {check})")
+            } else {
+                check
+            })
+        })
+        .collect()
+}
+
+/// Turns a query's error into a response, while keeping its result around.
+fn result_handling() -> TokenStream {
+    quote! {Ok(res) => res,
+    Err(e) => return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            e.to_string()
+        ))}
+}
+
+/// How an update or delete responds. With multi-tenancy, one that doesn't touch a row is a
+/// 404, since the row is either missing or belongs to another organization.
+fn scoped_write(tenant: bool, tablename: &str) -> (TokenStream, TokenStream, TokenStream) {
+    if !tenant {
+        return (
+            quote! {if let Err(e) = },
+            quote! {return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                e.to_string()
+            ))},
+            quote! {Ok(StatusCode::OK)},
+        );
+    }
+
+    let not_found = format!("No {tablename} with id {{id}} in this organization");
+
+    (
+        quote! {let res = match },
+        result_handling(),
+        quote! {
+            if res.rows_affected() == 0 {
+                return Err((StatusCode::NOT_FOUND, format!(#not_found)));
+            }
+
+            Ok(StatusCode::OK)
+        },
+    )
+}
+
+/// Every column on a resource's table, in the order they're created in. With multi-tenancy, the
+/// organization comes last.
+fn table_columns(fields: &[Field], tenant: bool) -> Vec<Field> {
+    let mut columns = vec![Field::new("id", FieldType::Integer, false)];
+    columns.extend(fields.iter().cloned());
+    columns.push(Field::new("created_at", FieldType::Timestamp, false));
+    columns.push(Field::new("last_updated", FieldType::Timestamp, false));
+
+    if tenant {
+        columns.push(Field::new(TENANT_COLUMN, FieldType::Integer, false));
+    }
+
+    columns
+}
+
+fn field_idents(fields: &[Field]) -> Vec<Ident> {
+    fields
+        .iter()
+        .map(|x| Ident::new(&x.name, Span::call_site()))
+        .collect()
+}
+
+/// The module a table's SeaORM entity or Diesel table lives in.
+fn module_ident(tablename: &str) -> Ident {
+    Ident::new(tablename, Span::call_site())
+}
+
+/// The field on `AppState` that holds a resource's repository.
+pub fn repository_ident(tablename: &str) -> Ident {
+    Ident::new(&format!("{tablename}_repository"), Span::call_site())
+}
+
+fn id_input() -> (Ident, TokenStream) {
+    (Ident::new("id", Span::call_site()), quote! {i32})
+}
+
+fn other_id_input() -> (Ident, TokenStream) {
+    (Ident::new("other_id", Span::call_site()), quote! {i32})
+}
+
+/// Resources without fields don't have a payload to pass along.
+fn payload_input(tablename: &str, fields: &[Field]) -> Option<(Ident, TokenStream)> {
+    let payload = payload_ident(tablename);
+
+    (!fields.is_empty()).then(|| (Ident::new("payload", Span::call_site()), quote! {#payload}))
+}
+
+fn payload_args(fields: &[Field]) -> Vec<TokenStream> {
+    fields
+        .iter()
+        .map(|x| {
+            let name = Ident::new(&x.name, Span::call_site());
+            quote! {payload.#name}
+        })
+        .collect()
+}
+
+pub fn struct_ident(tablename: &str) -> Ident {
+    let mut v: Vec<char> = tablename.chars().collect();
+    v[0] = v[0].to_uppercase().next().unwrap();
+    let tablename_titlecase: String = v.into_iter().collect();
+
+    Ident::new(&tablename_titlecase, Span::call_site())
+}
+
+pub fn payload_ident(tablename: &str) -> Ident {
+    Ident::new(
+        &format!("{}Payload", struct_ident(tablename)),
+        Span::call_site(),
+    )
+}
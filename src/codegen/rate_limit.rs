@@ -0,0 +1,242 @@
+use crate::cli::{Config, Deployment, RateLimitStore};
+use quote::quote;
+use syn::File;
+
+/// How many failed logins in a row lock an account, and for how long.
+pub const MAX_FAILED_LOGINS: i32 = 5;
+pub const LOCKOUT_MINUTES: i32 = 15;
+
+/// Whether the auth router is rate limited.
+pub fn rate_limited(cfg: &Config) -> bool {
+    cfg.auth && cfg.rate_limit
+}
+
+/// A middleware for the auth router that keeps a token bucket per IP address and per username,
+/// along with the `RateLimiter` trait it counts requests through and an in-memory
+/// implementation. With the Postgres store, there's an implementation that keeps the buckets in
+/// the `rate_limits` table too.
+pub fn rate_limit_file(cfg: &Config) -> File {
+    // Shuttle's proxy appends the address it saw to `X-Forwarded-For`, so the last one is the
+    // only one the client can't make up
+    let client_ip = match cfg.deployment {
+        Deployment::Shuttle => quote! {
+            /// The address the request came from, as seen by Shuttle's proxy.
+            fn client_ip(req: &Request<RequestBody>) -> String {
+                req.headers()
+                    .get("x-forwarded-for")
+                    .and_then(|x| x.to_str().ok())
+                    .and_then(|x| x.rsplit(',').next())
+                    .map(|x| x.trim().to_string())
+                    .unwrap_or_else(|| "unknown".to_string())
+            }
+        },
+        Deployment::Standalone => quote! {
+            /// The address the request came from. Behind a reverse proxy, this is the proxy's
+            /// address, so read the one it forwards instead.
+            fn client_ip(req: &Request<RequestBody>) -> String {
+                req.extensions()
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map(|ConnectInfo(addr)| addr.ip().to_string())
+                    .unwrap_or_else(|| "unknown".to_string())
+            }
+        },
+    };
+
+    // The standalone server's body size limit wraps every request body, so the body that's read
+    // to find the username has to be put back the same way
+    let (request_body, rebuild_body) = match cfg.deployment {
+        Deployment::Shuttle => (quote! {Body}, quote! {Body::from(body)}),
+        Deployment::Standalone => (
+            quote! {Limited<Body>},
+            quote! {Limited::new(Body::from(body), usize::MAX)},
+        ),
+    };
+
+    let connect_info = (cfg.deployment == Deployment::Standalone).then(|| {
+        quote! {
+            use axum::extract::ConnectInfo;
+            use http_body::Limited;
+            use std::net::SocketAddr;
+        }
+    });
+
+    let postgres = (cfg.rate_limit_store == RateLimitStore::Postgres).then(|| {
+        // The bucket's refilled, and a token taken if there is one, in a single statement, so
+        // instances racing for the last token can't both get it
+        let refilled = "LEAST($2, rate_limits.tokens + EXTRACT(EPOCH FROM now() - rate_limits.updated_at) * $3 / 60)";
+        let take = format!(
+            "INSERT INTO rate_limits (key, tokens, allowed, updated_at) VALUES ($1, $2 - 1, true, now()) \
+            ON CONFLICT (key) DO UPDATE SET \
+            allowed = {refilled} >= 1, \
+            tokens = {refilled} - CASE WHEN {refilled} >= 1 THEN 1 ELSE 0 END, \
+            updated_at = now() \
+            RETURNING allowed"
+        );
+
+        quote! {
+            /// Keeps the buckets in the `rate_limits` table, so every instance shares them. Rows
+            /// for keys that haven't been seen in a while can be deleted at any time.
+            pub struct PostgresRateLimiter {
+                pub db: PgPool,
+            }
+
+            #[async_trait]
+            impl RateLimiter for PostgresRateLimiter {
+                async fn try_acquire(&self, key: &str, quota: Quota) -> Result<bool, String> {
+                    let (allowed,): (bool,) = sqlx::query_as(#take)
+                        .bind(key)
+                        .bind(quota.capacity)
+                        .bind(quota.per_minute)
+                        .fetch_one(&self.db)
+                        .await
+                        .map_err(|e| e.to_string())?;
+
+                    Ok(allowed)
+                }
+            }
+        }
+    });
+
+    let pool = postgres.as_ref().map(|_| quote! {use sqlx::PgPool;});
+
+    let code = quote! {
+        use crate::AppState;
+        use async_trait::async_trait;
+        use axum::body::{Body, Bytes};
+        use axum::extract::{FromRequest, State};
+        use axum::http::{Request, StatusCode};
+        use axum::middleware::Next;
+        use axum::response::{IntoResponse, Response};
+        use serde::Deserialize;
+        use std::collections::HashMap;
+        use std::sync::Mutex;
+        use std::time::Instant;
+        #connect_info
+        #pool
+
+        /// A token bucket: it holds up to `capacity` requests, and refills at `per_minute`.
+        #[derive(Clone, Copy)]
+        pub struct Quota {
+            pub capacity: f64,
+            pub per_minute: f64,
+        }
+
+        /// Requests to the auth routes from a single IP address.
+        pub const PER_IP: Quota = Quota {
+            capacity: 20.0,
+            per_minute: 20.0,
+        };
+
+        /// Requests for a single username, wherever they come from.
+        pub const PER_USERNAME: Quota = Quota {
+            capacity: 10.0,
+            per_minute: 5.0,
+        };
+
+        /// Counts requests against their buckets. `AppState` holds one behind an `Arc`.
+        #[async_trait]
+        pub trait RateLimiter: Send + Sync {
+            /// Takes a request out of the bucket at `key`, returning `false` if it's empty.
+            async fn try_acquire(&self, key: &str, quota: Quota) -> Result<bool, String>;
+        }
+
+        /// Past this many buckets, full ones are dropped, since they're the same as missing ones.
+        const MAX_BUCKETS: usize = 10_000;
+
+        struct Bucket {
+            tokens: f64,
+            updated: Instant,
+            quota: Quota,
+        }
+
+        impl Bucket {
+            fn tokens_at(&self, now: Instant) -> f64 {
+                let refill = now.duration_since(self.updated).as_secs_f64() * self.quota.per_minute / 60.0;
+
+                (self.tokens + refill).min(self.quota.capacity)
+            }
+        }
+
+        /// Keeps the buckets in memory, so each instance counts on its own.
+        #[derive(Default)]
+        pub struct MemoryRateLimiter {
+            buckets: Mutex<HashMap<String, Bucket>>,
+        }
+
+        #[async_trait]
+        impl RateLimiter for MemoryRateLimiter {
+            async fn try_acquire(&self, key: &str, quota: Quota) -> Result<bool, String> {
+                let now = Instant::now();
+                let mut buckets = self.buckets.lock().unwrap();
+
+                if buckets.len() >= MAX_BUCKETS {
+                    buckets.retain(|_, x| x.tokens_at(now) < x.quota.capacity);
+                }
+
+                let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+                    tokens: quota.capacity,
+                    updated: now,
+                    quota,
+                });
+
+                let tokens = bucket.tokens_at(now);
+                let allowed = tokens >= 1.0;
+
+                bucket.tokens = if allowed { tokens - 1.0 } else { tokens };
+                bucket.updated = now;
+
+                Ok(allowed)
+            }
+        }
+
+        #postgres
+
+        type RequestBody = #request_body;
+
+        #[derive(Deserialize)]
+        struct Credentials {
+            username: Option<String>,
+        }
+
+        /// Limits requests to the auth routes by IP address, and by the username in the body, if
+        /// there is one.
+        pub async fn rate_limit(
+            State(state): State<AppState>,
+            req: Request<RequestBody>,
+            next: Next<RequestBody>,
+        ) -> Response {
+            let mut keys = vec![(format!("ip:{}", client_ip(&req)), PER_IP)];
+
+            let (parts, body) = req.into_parts();
+            let body = match Bytes::from_request(Request::new(body), &()).await {
+                Ok(body) => body,
+                Err(e) => return e.into_response(),
+            };
+
+            if let Some(username) = serde_json::from_slice::<Credentials>(&body)
+                .ok()
+                .and_then(|x| x.username)
+            {
+                keys.push((format!("username:{username}"), PER_USERNAME));
+            }
+
+            for (key, quota) in keys {
+                match state.rate_limiter.try_acquire(&key, quota).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        return (StatusCode::TOO_MANY_REQUESTS, "Too many requests. Try again later").into_response()
+                    }
+                    Err(e) => {
+                        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Something went wrong: {e}")).into_response()
+                    }
+                }
+            }
+
+            next.run(Request::from_parts(parts, #rebuild_body)).await
+        }
+
+        #client_ip
+    };
+
+    syn::parse_file(&code.to_string()).unwrap()
+}
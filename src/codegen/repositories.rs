@@ -0,0 +1,183 @@
+use crate::cli::{Config, Route};
+use crate::codegen::queries::{payload_ident, struct_ident, QueryGen};
+use crate::schema::{join_tables, table_fields};
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use syn::File;
+
+/// A resource's repository module: its model and payload, the repository trait its handlers
+/// depend on, and the Postgres and in-memory implementations of that trait.
+pub fn repository_file(route: &Route, cfg: &Config) -> File {
+    let (domain, postgres) = repository_parts(route, cfg);
+
+    let file = quote! {
+        use sqlx::PgPool;
+        #domain
+        #postgres
+    };
+
+    syn::parse_file(&file.to_string()).unwrap()
+}
+
+/// The workspace's half of a repository module that doesn't depend on Postgres, which goes in
+/// the domain crate: everything but the Postgres implementation.
+pub fn domain_file(route: &Route, cfg: &Config) -> File {
+    let (domain, _) = repository_parts(route, cfg);
+
+    syn::parse_file(&domain.to_string()).unwrap()
+}
+
+/// The Postgres implementation of a resource's repository, which goes in the workspace's db crate.
+pub fn postgres_file(route: &Route, cfg: &Config) -> File {
+    let (_, postgres) = repository_parts(route, cfg);
+
+    let module = Ident::new(&route.name, Span::call_site());
+    let model = struct_ident(&route.name);
+    let trait_name = Ident::new(&format!("{model}Repository"), Span::call_site());
+    let payload = (!table_fields(route, &cfg.routes).is_empty()).then(|| {
+        let payload_name = payload_ident(&route.name);
+        quote! {#payload_name,}
+    });
+
+    let file = quote! {
+        use async_trait::async_trait;
+        use domain::#module::{#model, #payload #trait_name};
+        use sqlx::PgPool;
+        #postgres
+    };
+
+    syn::parse_file(&file.to_string()).unwrap()
+}
+
+/// Splits a repository module into the parts that don't need a database and the Postgres implementation.
+fn repository_parts(route: &Route, cfg: &Config) -> (TokenStream, TokenStream) {
+    let tablename = &route.name;
+    let fields = table_fields(route, &cfg.routes);
+
+    let mut query_data = QueryGen::create_query_data(tablename, &fields, cfg);
+    query_data.extend(QueryGen::relation_query_data(tablename, cfg));
+
+    let model = struct_ident(tablename);
+    let trait_name = Ident::new(&format!("{model}Repository"), Span::call_site());
+    let postgres = Ident::new(&format!("Postgres{model}Repository"), Span::call_site());
+    let in_memory = Ident::new(&format!("InMemory{model}Repository"), Span::call_site());
+
+    let field_names: Vec<Ident> = fields
+        .iter()
+        .map(|x| Ident::new(&x.name, Span::call_site()))
+        .collect();
+    let field_types: Vec<TokenStream> = fields.iter().map(|x| x.rust_type()).collect();
+
+    let payload = (!fields.is_empty()).then(|| {
+        let payload_name = payload_ident(tablename);
+
+        quote! {
+            #[derive(Deserialize)]
+            pub struct #payload_name {
+                #(pub #field_names: #field_types,)*
+            }
+        }
+    });
+    let serde = if payload.is_some() {
+        quote! {use serde::{Deserialize, Serialize};}
+    } else {
+        quote! {use serde::Serialize;}
+    };
+
+    // Both sides of a many-to-many relation can see its links, so the in-memory
+    // repositories share them through an `Arc`
+    let links: Vec<Ident> = join_tables(&cfg.routes)
+        .into_iter()
+        .filter(|x| x.left == *tablename || x.right == *tablename)
+        .map(|x| Ident::new(&x.name(), Span::call_site()))
+        .collect();
+    let sync = if links.is_empty() {
+        quote! {use std::sync::Mutex;}
+    } else {
+        quote! {use std::sync::{Arc, Mutex};}
+    };
+
+    let signatures: Vec<TokenStream> = query_data
+        .iter()
+        .map(|x| {
+            let method = &x.method;
+            let names = x.inputs.iter().map(|(name, _)| name);
+            let types = x.inputs.iter().map(|(_, ty)| ty);
+            let returns = &x.returns;
+
+            quote! {
+                async fn #method(&self, #(#names: #types),*) -> Result<#returns, String>
+            }
+        })
+        .collect();
+
+    let postgres_bodies: Vec<TokenStream> = query_data
+        .iter()
+        .map(|x| {
+            let call = x.sqlx_call(cfg.checked_queries, quote! {&self.db});
+            let discard = x.output.is_none().then(|| quote! {.map(|_| ())});
+
+            quote! {
+                #call.await #discard.map_err(|e| e.to_string())
+            }
+        })
+        .collect();
+
+    let in_memory_bodies: Vec<TokenStream> =
+        query_data.iter().map(|x| x.in_memory.clone()).collect();
+
+    let in_memory_doc = format!(
+        " Keeps every {tablename} in memory, so handlers can be tested without a database."
+    );
+
+    let domain = quote! {
+        use async_trait::async_trait;
+        use chrono::{DateTime, Utc};
+        #serde
+        #sync
+
+        #[derive(Clone, Serialize, sqlx::FromRow)]
+        pub struct #model {
+            pub id: i32,
+            #(pub #field_names: #field_types,)*
+            pub created_at: DateTime<Utc>,
+            pub last_updated: DateTime<Utc>
+        }
+
+        #payload
+
+        #[async_trait]
+        pub trait #trait_name: Send + Sync {
+            #(#signatures;)*
+        }
+
+        #[doc = #in_memory_doc]
+        #[derive(Default)]
+        pub struct #in_memory {
+            pub rows: Mutex<Vec<#model>>,
+            #(pub #links: Arc<Mutex<Vec<(i32, i32)>>>,)*
+        }
+
+        #[async_trait]
+        impl #trait_name for #in_memory {
+            #(#signatures {
+                #in_memory_bodies
+            })*
+        }
+    };
+
+    let postgres = quote! {
+        pub struct #postgres {
+            pub db: PgPool,
+        }
+
+        #[async_trait]
+        impl #trait_name for #postgres {
+            #(#signatures {
+                #postgres_bodies
+            })*
+        }
+    };
+
+    (domain, postgres)
+}
@@ -0,0 +1,340 @@
+use crate::cli::Config;
+use crate::codegen::migration_file::{schema_changes, Change};
+use crate::dependencies::add_migration_crate_dependencies;
+use crate::schema::{foreign_keys, join_tables, table_fields, JoinTable, OnDelete};
+use chrono::Utc;
+use indoc::formatdoc;
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use std::fs;
+use std::path::Path;
+use syn::File;
+
+/// Where the SeaORM migration crate lives, relative to the project root.
+pub const MIGRATION_CRATE: &str = "migration";
+
+/// Turns a snake_case name into the CamelCase identifier SeaORM derives from it, e.g. `post_id` into `PostId`.
+pub fn camel_ident(name: &str) -> Ident {
+    Ident::new(&camel_case(name), Span::call_site())
+}
+
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|x| {
+            let mut chars = x.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// An entity module for every resource and join table, keyed by module name.
+pub fn entity_files(cfg: &Config) -> Vec<(String, File)> {
+    let mut files = Vec::new();
+
+    for route in &cfg.routes {
+        files.push((route.name.clone(), resource_entity(&route.name, cfg)));
+    }
+
+    for join in join_tables(&cfg.routes) {
+        files.push((join.name(), join_entity(&join)));
+    }
+
+    files
+}
+
+fn resource_entity(tablename: &str, cfg: &Config) -> File {
+    let route = cfg.routes.iter().find(|x| x.name == tablename).unwrap();
+    let fields = table_fields(route, &cfg.routes);
+
+    let field_names: Vec<Ident> = fields
+        .iter()
+        .map(|x| Ident::new(&x.name, Span::call_site()))
+        .collect();
+    let field_types: Vec<TokenStream> = fields.iter().map(|x| x.rust_type()).collect();
+
+    let mut variants: Vec<TokenStream> = Vec::new();
+    let mut related: Vec<TokenStream> = Vec::new();
+
+    for key in foreign_keys(&cfg.routes) {
+        if key.table == tablename {
+            let target = &key.references;
+            variants.push(belongs_to(target, &key.column(), key.on_delete));
+            related.push(related_impl(target, &camel_ident(target)));
+        } else if key.references == tablename {
+            let child = &key.table;
+            variants.push(has_many(child));
+            related.push(related_impl(child, &camel_ident(child)));
+        }
+    }
+
+    for join in join_tables(&cfg.routes) {
+        let (this, other) = if join.left == tablename {
+            (&join.left, &join.right)
+        } else if join.right == tablename {
+            (&join.right, &join.left)
+        } else {
+            continue;
+        };
+
+        let join_name = join.name();
+        let join_module = Ident::new(&join_name, Span::call_site());
+        let other_module = Ident::new(other, Span::call_site());
+        let (this_variant, other_variant) = (camel_ident(this), camel_ident(other));
+
+        variants.push(has_many(&join_name));
+        related.push(related_impl(&join_name, &camel_ident(&join_name)));
+        related.push(quote! {
+            impl Related<super::#other_module::Entity> for Entity {
+                fn to() -> RelationDef {
+                    super::#join_module::Relation::#other_variant.def()
+                }
+
+                fn via() -> Option<RelationDef> {
+                    Some(super::#join_module::Relation::#this_variant.def().rev())
+                }
+            }
+        });
+    }
+
+    let file = quote! {
+        use chrono::{DateTime, Utc};
+        use sea_orm::entity::prelude::*;
+        use serde::Serialize;
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+        #[sea_orm(table_name = #tablename)]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub id: i32,
+            #(pub #field_names: #field_types,)*
+            pub created_at: DateTime<Utc>,
+            pub last_updated: DateTime<Utc>,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {
+            #(#variants)*
+        }
+
+        #(#related)*
+
+        impl ActiveModelBehavior for ActiveModel {}
+    };
+
+    syn::parse_file(&file.to_string()).unwrap()
+}
+
+fn join_entity(join: &JoinTable) -> File {
+    let name = join.name();
+    let (left, right) = (&join.left, &join.right);
+    let left_id = Ident::new(&format!("{left}_id"), Span::call_site());
+    let right_id = Ident::new(&format!("{right}_id"), Span::call_site());
+
+    let variants = [
+        belongs_to(left, &left_id.to_string(), join.on_delete),
+        belongs_to(right, &right_id.to_string(), join.on_delete),
+    ];
+    let related = [
+        related_impl(left, &camel_ident(left)),
+        related_impl(right, &camel_ident(right)),
+    ];
+
+    let file = quote! {
+        use sea_orm::entity::prelude::*;
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+        #[sea_orm(table_name = #name)]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub #left_id: i32,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub #right_id: i32,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {
+            #(#variants)*
+        }
+
+        #(#related)*
+
+        impl ActiveModelBehavior for ActiveModel {}
+    };
+
+    syn::parse_file(&file.to_string()).unwrap()
+}
+
+fn belongs_to(target: &str, column: &str, on_delete: OnDelete) -> TokenStream {
+    let variant = camel_ident(target);
+    let entity = format!("super::{target}::Entity");
+    let from = format!("Column::{}", camel_case(column));
+    let to = format!("super::{target}::Column::Id");
+    let on_delete = camel_case(on_delete.name());
+
+    quote! {
+        #[sea_orm(belongs_to = #entity, from = #from, to = #to, on_delete = #on_delete)]
+        #variant,
+    }
+}
+
+fn has_many(target: &str) -> TokenStream {
+    let variant = camel_ident(target);
+    let entity = format!("super::{target}::Entity");
+
+    quote! {
+        #[sea_orm(has_many = #entity)]
+        #variant,
+    }
+}
+
+fn related_impl(target: &str, variant: &Ident) -> TokenStream {
+    let module = Ident::new(target, Span::call_site());
+
+    quote! {
+        impl Related<super::#module::Entity> for Entity {
+            fn to() -> RelationDef {
+                Relation::#variant.def()
+            }
+        }
+    }
+}
+
+/// Creates the migration crate, with a first migration that sets up the whole schema.
+pub fn write_migration_crate(project_path: &Path, cfg: &Config) -> Result<(), String> {
+    let crate_path = project_path.join(MIGRATION_CRATE);
+    fs::create_dir_all(crate_path.join("src"))
+        .map_err(|e| format!("Couldn't create the migration crate: {e}"))?;
+
+    let manifest = formatdoc! {r#"
+        [package]
+        name = "{MIGRATION_CRATE}"
+        version = "0.1.0"
+        edition = "2021"
+        publish = false
+
+        [lib]
+        name = "{MIGRATION_CRATE}"
+        path = "src/lib.rs"
+
+        [dependencies]
+    "#};
+
+    fs::write(crate_path.join("Cargo.toml"), manifest)
+        .map_err(|e| format!("Couldn't write the migration crate's Cargo.toml: {e}"))?;
+    add_migration_crate_dependencies(&crate_path)?;
+
+    let main = formatdoc! {"
+        use sea_orm_migration::prelude::*;
+
+        #[tokio::main]
+        async fn main() {{
+            cli::run_cli({MIGRATION_CRATE}::Migrator).await;
+        }}
+    "};
+
+    fs::write(crate_path.join("src/main.rs"), main)
+        .map_err(|e| format!("Couldn't write the migration crate's main.rs: {e}"))?;
+
+    let changes = schema_changes(&Config::default(), cfg)?;
+
+    write_migration_module(project_path, "schema", &changes)
+}
+
+/// Adds a migration to the migration crate that applies `changes`, then registers it with the `Migrator`.
+/// The statements are the same ones a sqlx project would get, run through SeaORM's connection.
+pub fn write_migration_module(
+    project_path: &Path,
+    description: &str,
+    changes: &[Change],
+) -> Result<(), String> {
+    let src = project_path.join(MIGRATION_CRATE).join("src");
+
+    if !src.exists() {
+        return Err(format!(
+            "Couldn't find the migration crate at {}",
+            src.display()
+        ));
+    }
+
+    let statements = |sql: Vec<&str>| -> String {
+        sql.iter()
+            .map(|x| {
+                format!(
+                    "        db.execute_unprepared(r#\"\n{}\"#)\n            .await?;\n",
+                    x.trim_end()
+                )
+            })
+            .collect()
+    };
+
+    let up = statements(changes.iter().map(|x| x.up.as_str()).collect());
+    let down = statements(changes.iter().rev().map(|x| x.down.as_str()).collect());
+
+    let module = formatdoc! {"
+        use sea_orm_migration::prelude::*;
+
+        #[derive(DeriveMigrationName)]
+        pub struct Migration;
+
+        #[async_trait::async_trait]
+        impl MigrationTrait for Migration {{
+            async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {{
+                let db = manager.get_connection();
+
+        {up}
+                Ok(())
+            }}
+
+            async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {{
+                let db = manager.get_connection();
+
+        {down}
+                Ok(())
+            }}
+        }}
+    "};
+
+    let timestamp = Utc::now().naive_local().format("%Y%m%d_%H%M%S");
+
+    fs::write(src.join(format!("m{timestamp}_{description}.rs")), module)
+        .map_err(|e| format!("Couldn't write the migration: {e}"))?;
+
+    write_migrator(&src)
+}
+
+/// Rewrites the crate's `lib.rs` so the `Migrator` runs every migration module, oldest first.
+fn write_migrator(src: &Path) -> Result<(), String> {
+    let mut modules: Vec<String> = fs::read_dir(src)
+        .map_err(|e| format!("Couldn't read {}: {e}", src.display()))?
+        .filter_map(|x| x.ok())
+        .map(|x| x.file_name().to_string_lossy().replace(".rs", ""))
+        .filter(|x| x.starts_with('m') && x != "main")
+        .collect();
+    modules.sort();
+
+    let declarations: String = modules.iter().map(|x| format!("mod {x};\n")).collect();
+    let migrations: String = modules
+        .iter()
+        .map(|x| format!("            Box::new({x}::Migration),\n"))
+        .collect();
+
+    let lib = formatdoc! {"
+        pub use sea_orm_migration::prelude::*;
+
+        {declarations}
+        pub struct Migrator;
+
+        #[async_trait::async_trait]
+        impl MigratorTrait for Migrator {{
+            fn migrations() -> Vec<Box<dyn MigrationTrait>> {{
+                vec![
+        {migrations}        ]
+            }}
+        }}
+    "};
+
+    fs::write(src.join("lib.rs"), lib).map_err(|e| format!("Couldn't write lib.rs: {e}"))
+}
@@ -0,0 +1,206 @@
+use crate::cli::Config;
+use crate::codegen::payments::takes_payments;
+use crate::codegen::secrets::decode_key_fn;
+use quote::quote;
+use syn::File;
+
+/// The settings a standalone server is run with. Every one of them has a default, which an
+/// optional TOML file and then `APP_*` environment variables can override.
+pub fn config_file(cfg: &Config) -> File {
+    // The cookie key is kept out of `Settings`, so it can't end up in a config file or a log
+    let cookie_key = cfg.auth.then(|| {
+        let decode_key = decode_key_fn();
+
+        quote! {
+            use axum_extra::extract::cookie::Key;
+            use base64::engine::general_purpose::STANDARD;
+            use base64::Engine;
+
+            /// The key that encrypts session cookies, read from the `COOKIE_KEY` environment
+            /// variable so sessions survive restarts and are shared between instances.
+            pub fn cookie_key() -> Result<Key, String> {
+                let encoded = std::env::var("COOKIE_KEY").map_err(|_| {
+                    "`COOKIE_KEY` isn't set. Generate one with `inlet secret gen-key --print`"
+                        .to_string()
+                })?;
+
+                decode_key(&encoded)
+            }
+
+            #decode_key
+        }
+    });
+
+    let stripe_client = takes_payments(cfg).then(|| {
+        quote! {
+            use crate::stripe::StripeClient;
+
+            /// The Stripe client, with the keys, price and redirect origin from the
+            /// `STRIPE_SECRET_KEY`, `STRIPE_WEBHOOK_SECRET`, `STRIPE_PRICE_ID` and
+            /// `STRIPE_REDIRECT_ORIGIN` environment variables.
+            pub fn stripe_client() -> Result<StripeClient, String> {
+                let var = |key: &str| std::env::var(key).map_err(|_| format!("`{key}` isn't set"));
+
+                Ok(StripeClient::new(
+                    &var("STRIPE_SECRET_KEY")?,
+                    &var("STRIPE_WEBHOOK_SECRET")?,
+                    &var("STRIPE_PRICE_ID")?,
+                    &var("STRIPE_REDIRECT_ORIGIN")?,
+                ))
+            }
+        }
+    });
+
+    let code = quote! {
+        use axum::http::HeaderValue;
+        use serde::Deserialize;
+        use std::fmt::Display;
+        use std::io::ErrorKind;
+        use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+        use std::str::FromStr;
+        use std::time::Duration;
+        use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+        /// Where the settings are read from, unless `CONFIG_FILE` says otherwise.
+        const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+        #[derive(Clone, Debug, Deserialize)]
+        #[serde(default)]
+        pub struct Settings {
+            pub host: IpAddr,
+            pub port: u16,
+            /// How long a request can take before it's answered with a 408.
+            pub request_timeout_secs: u64,
+            /// The largest request body that's accepted, in bytes.
+            pub body_limit_bytes: usize,
+            /// How long open connections get to finish once the server is asked to stop.
+            pub shutdown_timeout_secs: u64,
+            /// The origins allowed to make cross-origin requests, or `*` for any of them.
+            pub cors_origins: Vec<String>,
+        }
+
+        impl Default for Settings {
+            fn default() -> Self {
+                Self {
+                    host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    port: 8000,
+                    request_timeout_secs: 30,
+                    body_limit_bytes: 2 * 1024 * 1024,
+                    shutdown_timeout_secs: 30,
+                    cors_origins: Vec::new(),
+                }
+            }
+        }
+
+        impl Settings {
+            /// Reads `config.toml` (or the file at `CONFIG_FILE`) if there is one, then applies
+            /// any `APP_HOST`, `APP_PORT`, `APP_REQUEST_TIMEOUT_SECS`, `APP_BODY_LIMIT_BYTES`,
+            /// `APP_SHUTDOWN_TIMEOUT_SECS` and `APP_CORS_ORIGINS` (comma separated) variables.
+            pub fn load() -> Result<Self, String> {
+                let asked_for = std::env::var("CONFIG_FILE").ok();
+                let path = asked_for.as_deref().unwrap_or(DEFAULT_CONFIG_FILE);
+
+                let mut settings: Settings = match std::fs::read_to_string(path) {
+                    Ok(file) => {
+                        toml::from_str(&file).map_err(|e| format!("Couldn't parse {path}: {e}"))?
+                    }
+                    // The default file is optional, but one that was asked for isn't
+                    Err(e) if e.kind() == ErrorKind::NotFound && asked_for.is_none() => {
+                        Settings::default()
+                    }
+                    Err(e) => return Err(format!("Couldn't read {path}: {e}")),
+                };
+
+                if let Some(host) = env("APP_HOST")? {
+                    settings.host = host;
+                }
+
+                if let Some(port) = env("APP_PORT")? {
+                    settings.port = port;
+                }
+
+                if let Some(secs) = env("APP_REQUEST_TIMEOUT_SECS")? {
+                    settings.request_timeout_secs = secs;
+                }
+
+                if let Some(bytes) = env("APP_BODY_LIMIT_BYTES")? {
+                    settings.body_limit_bytes = bytes;
+                }
+
+                if let Some(secs) = env("APP_SHUTDOWN_TIMEOUT_SECS")? {
+                    settings.shutdown_timeout_secs = secs;
+                }
+
+                if let Some(origins) = env::<String>("APP_CORS_ORIGINS")? {
+                    settings.cors_origins = origins
+                        .split(',')
+                        .map(|x| x.trim().to_string())
+                        .filter(|x| !x.is_empty())
+                        .collect();
+                }
+
+                for origin in settings.cors_origins.iter().filter(|x| *x != "*") {
+                    HeaderValue::from_str(origin)
+                        .map_err(|e| format!("`{origin}` isn't a valid origin: {e}"))?;
+                }
+
+                Ok(settings)
+            }
+
+            pub fn addr(&self) -> SocketAddr {
+                SocketAddr::new(self.host, self.port)
+            }
+
+            pub fn request_timeout(&self) -> Duration {
+                Duration::from_secs(self.request_timeout_secs)
+            }
+
+            pub fn shutdown_timeout(&self) -> Duration {
+                Duration::from_secs(self.shutdown_timeout_secs)
+            }
+
+            /// Allows requests from the configured origins. With none of them, cross-origin
+            /// requests are left to the browser's same-origin policy.
+            pub fn cors_layer(&self) -> CorsLayer {
+                if self.cors_origins.is_empty() {
+                    return CorsLayer::new();
+                }
+
+                let origins = if self.cors_origins.iter().any(|x| x == "*") {
+                    AllowOrigin::any()
+                } else {
+                    AllowOrigin::list(
+                        self.cors_origins
+                            .iter()
+                            .filter_map(|x| HeaderValue::from_str(x).ok()),
+                    )
+                };
+
+                CorsLayer::new()
+                    .allow_origin(origins)
+                    .allow_methods(Any)
+                    .allow_headers(Any)
+            }
+        }
+
+        fn env<T>(key: &str) -> Result<Option<T>, String>
+        where
+            T: FromStr,
+            T::Err: Display,
+        {
+            match std::env::var(key) {
+                Ok(value) => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|e| format!("Couldn't parse {key}: {e}")),
+                Err(_) => Ok(None),
+            }
+        }
+
+        #cookie_key
+
+        #stripe_client
+    };
+
+    syn::parse_file(&code.to_string()).unwrap()
+}
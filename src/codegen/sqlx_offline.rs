@@ -0,0 +1,65 @@
+use crate::cli::Config;
+use crate::codegen::queries::QueryGen;
+use crate::schema::table_fields;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+/// Writes the `.sqlx` directory that `sqlx::query!` and friends read from when there's no
+/// database to check queries against, describing every generated query from the declared schema.
+pub fn write_offline_data(project_path: &Path, cfg: &Config) {
+    let offline_dir = project_path.join(".sqlx");
+    fs::create_dir_all(&offline_dir).unwrap();
+
+    for route in &cfg.routes {
+        let fields = table_fields(route, &cfg.routes);
+
+        let mut query_data = QueryGen::create_query_data(&route.name, &fields, cfg);
+        query_data.extend(QueryGen::relation_query_data(&route.name, cfg));
+
+        for query in query_data {
+            let hash = format!("{:x}", Sha256::digest(query.query.as_bytes()));
+
+            fs::write(
+                offline_dir.join(format!("query-{hash}.json")),
+                describe(&query, &hash),
+            )
+            .unwrap();
+        }
+    }
+}
+
+fn describe(query: &QueryGen, hash: &str) -> String {
+    let columns: Vec<String> = query
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(ordinal, x)| {
+            format!(
+                "      {{\n        \"ordinal\": {ordinal},\n        \"name\": \"{}\",\n        \"type_info\": \"{}\"\n      }}",
+                x.name,
+                x.ty.type_info()
+            )
+        })
+        .collect();
+
+    let parameters: Vec<String> = query
+        .params
+        .iter()
+        .map(|x| format!("\"{}\"", x.type_info()))
+        .collect();
+
+    let nullable: Vec<String> = query
+        .columns
+        .iter()
+        .map(|x| x.nullable.to_string())
+        .collect();
+
+    format!(
+        "{{\n  \"db_name\": \"PostgreSQL\",\n  \"query\": \"{}\",\n  \"describe\": {{\n    \"columns\": [\n{}\n    ],\n    \"parameters\": {{\n      \"Left\": [{}]\n    }},\n    \"nullable\": [{}]\n  }},\n  \"hash\": \"{hash}\"\n}}\n",
+        query.query.replace('\\', "\\\\").replace('"', "\\\""),
+        columns.join(",\n"),
+        parameters.join(", "),
+        nullable.join(", ")
+    )
+}
@@ -0,0 +1,334 @@
+use crate::cli::Config;
+use crate::codegen::axum_auth::instrument_secrets;
+use crate::codegen::csrf::csrf_protected;
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::File;
+
+/// The tables holding organizations and the users who are members of them.
+pub const ORGANIZATIONS: &str = "organizations";
+pub const MEMBERSHIPS: &str = "memberships";
+/// The column on every resource's table that says which organization a row belongs to.
+pub const TENANT_COLUMN: &str = "tenant_id";
+/// The header a request picks one of the user's organizations with.
+pub const TENANT_HEADER: &str = "x-tenant-id";
+/// The roles a member can have. Owners can also manage the organization's members.
+pub const OWNER: &str = "owner";
+pub const MEMBER: &str = "member";
+
+/// The statement that creates a user from `columns`, bound in order. With multi-tenancy, the same
+/// statement gives them an organization of their own, named after them, which they own.
+pub fn insert_user(cfg: &Config, columns: &[&str], returning_id: bool) -> String {
+    let placeholders: Vec<String> = (1..=columns.len()).map(|x| format!("${x}")).collect();
+    let insert = format!(
+        "INSERT INTO users ({}) VALUES ({})",
+        columns.join(", "),
+        placeholders.join(", ")
+    );
+
+    match (cfg.multi_tenant, returning_id) {
+        (false, false) => insert,
+        (false, true) => format!("{insert} RETURNING id"),
+        (true, _) => {
+            let returning = if returning_id {
+                " RETURNING user_id"
+            } else {
+                ""
+            };
+
+            format!(
+                "WITH new_user AS ({insert} RETURNING id, username), \
+                 new_organization AS (INSERT INTO {ORGANIZATIONS} (name) SELECT username FROM new_user RETURNING id) \
+                 INSERT INTO {MEMBERSHIPS} (organization_id, user_id, role) \
+                 SELECT new_organization.id, new_user.id, '{OWNER}' FROM new_organization, new_user{returning}"
+            )
+        }
+    }
+}
+
+/// The `Tenant` extractor, which finds the organization a request acts on from the user's
+/// session and the optional `X-Tenant-Id` header.
+pub fn tenant_extractor() -> File {
+    let membership = format!(
+        "SELECT organization_id, role FROM {MEMBERSHIPS} \
+         WHERE user_id = $1 AND ($2::INT IS NULL OR organization_id = $2) \
+         ORDER BY created_at, id LIMIT 1"
+    );
+
+    let code = quote! {
+        use crate::middleware::auth::UserInfo;
+        use crate::AppState;
+        use axum::async_trait;
+        use axum::extract::FromRequestParts;
+        use axum::http::request::Parts;
+        use axum::http::StatusCode;
+
+        /// The header that picks which of the user's organizations a request acts on.
+        pub const TENANT_HEADER: &str = #TENANT_HEADER;
+
+        /// The organization a request acts on, which every query on a resource is scoped to.
+        /// Only works behind `check_authed_cookies`, which finds the user it's looked up for.
+        #[derive(Clone, Debug)]
+        pub struct Tenant {
+            pub id: i32,
+            /// The user's role in the organization.
+            pub role: String,
+        }
+
+        #[async_trait]
+        impl FromRequestParts<AppState> for Tenant {
+            type Rejection = (StatusCode, String);
+
+            /// Uses the organization in the `X-Tenant-Id` header if there is one, and otherwise
+            /// the first one the user joined. Either way, the user has to be one of its members.
+            async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+                let Some(user) = parts.extensions.get::<UserInfo>() else {
+                    return Err((StatusCode::FORBIDDEN, "Forbidden!".to_string()));
+                };
+
+                let requested = match parts.headers.get(TENANT_HEADER) {
+                    Some(value) => match value.to_str().ok().and_then(|x| x.parse::<i32>().ok()) {
+                        Some(id) => Some(id),
+                        None => {
+                            return Err((
+                                StatusCode::BAD_REQUEST,
+                                "X-Tenant-Id should be an organization's id".to_string(),
+                            ))
+                        }
+                    },
+                    None => None,
+                };
+
+                let membership = sqlx::query_as::<_, (i32, String)>(#membership)
+                    .bind(user.user_id)
+                    .bind(requested)
+                    .fetch_optional(&state.db)
+                    .await
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+                match membership {
+                    Some((id, role)) => Ok(Tenant { id, role }),
+                    None => Err((
+                        StatusCode::FORBIDDEN,
+                        "You aren't a member of that organization".to_string(),
+                    )),
+                }
+            }
+        }
+    };
+
+    syn::parse_file(&code.to_string()).unwrap()
+}
+
+/// The routes for listing and creating the user's organizations, and for their owners to add
+/// and remove members.
+pub fn organization_routes(cfg: &Config) -> File {
+    let instrument = instrument_secrets(cfg);
+
+    let list = format!(
+        "SELECT {ORGANIZATIONS}.id, {ORGANIZATIONS}.name, {MEMBERSHIPS}.role FROM {ORGANIZATIONS} \
+         JOIN {MEMBERSHIPS} ON {MEMBERSHIPS}.organization_id = {ORGANIZATIONS}.id \
+         WHERE {MEMBERSHIPS}.user_id = $1 ORDER BY {MEMBERSHIPS}.created_at, {MEMBERSHIPS}.id"
+    );
+    let create = format!(
+        "WITH new_organization AS (INSERT INTO {ORGANIZATIONS} (name) VALUES ($1) RETURNING id) \
+         INSERT INTO {MEMBERSHIPS} (organization_id, user_id, role) \
+         SELECT id, $2, '{OWNER}' FROM new_organization RETURNING organization_id"
+    );
+    let add = format!(
+        "INSERT INTO {MEMBERSHIPS} (organization_id, user_id, role) VALUES ($1, $2, $3) \
+         ON CONFLICT (organization_id, user_id) DO NOTHING"
+    );
+    // An organization always keeps at least one owner
+    let remove = format!(
+        "DELETE FROM {MEMBERSHIPS} WHERE organization_id = $1 AND user_id = $2 \
+         AND (role <> '{OWNER}' OR (SELECT COUNT(*) FROM {MEMBERSHIPS} WHERE organization_id = $1 AND role = '{OWNER}') > 1)"
+    );
+    let role =
+        format!("SELECT role FROM {MEMBERSHIPS} WHERE organization_id = $1 AND user_id = $2");
+
+    let code = quote! {
+        use crate::middleware::auth::UserInfo;
+        use crate::AppState;
+        use axum::extract::{Path, State};
+        use axum::http::StatusCode;
+        use axum::{Extension, Json};
+        use serde::{Deserialize, Serialize};
+        use std::fmt::Display;
+
+        pub const OWNER: &str = #OWNER;
+        pub const MEMBER: &str = #MEMBER;
+
+        /// An organization the user is a member of, along with their role in it.
+        #[derive(Serialize, sqlx::FromRow)]
+        pub struct Organization {
+            pub id: i32,
+            pub name: String,
+            pub role: String,
+        }
+
+        #[derive(Deserialize)]
+        pub struct NewOrganization {
+            pub name: String,
+        }
+
+        /// The user to add, by username. They're added as a member unless another role is given.
+        #[derive(Deserialize)]
+        pub struct NewMember {
+            pub username: String,
+            pub role: Option<String>,
+        }
+
+        /// The organizations the user is a member of, starting with the one requests act on
+        /// when they don't send an `X-Tenant-Id` header.
+        #instrument
+        pub async fn list_organizations(
+            State(state): State<AppState>,
+            Extension(user): Extension<UserInfo>,
+        ) -> Result<Json<Vec<Organization>>, (StatusCode, String)> {
+            let organizations = sqlx::query_as::<_, Organization>(#list)
+                .bind(user.user_id)
+                .fetch_all(&state.db)
+                .await
+                .map_err(internal_error)?;
+
+            Ok(Json(organizations))
+        }
+
+        /// Creates an organization, which the user owns.
+        #instrument
+        pub async fn create_organization(
+            State(state): State<AppState>,
+            Extension(user): Extension<UserInfo>,
+            Json(req): Json<NewOrganization>,
+        ) -> Result<(StatusCode, Json<Organization>), (StatusCode, String)> {
+            if req.name.trim().is_empty() {
+                return Err((StatusCode::BAD_REQUEST, "Organizations need a name".to_string()));
+            }
+
+            let (id,): (i32,) = sqlx::query_as(#create)
+                .bind(&req.name)
+                .bind(user.user_id)
+                .fetch_one(&state.db)
+                .await
+                .map_err(internal_error)?;
+
+            Ok((
+                StatusCode::CREATED,
+                Json(Organization {
+                    id,
+                    name: req.name,
+                    role: OWNER.to_string(),
+                }),
+            ))
+        }
+
+        /// Adds a user to the organization.
+        #instrument
+        pub async fn add_member(
+            State(state): State<AppState>,
+            Extension(user): Extension<UserInfo>,
+            Path(id): Path<i32>,
+            Json(req): Json<NewMember>,
+        ) -> Result<StatusCode, (StatusCode, String)> {
+            require_owner(&state, id, user.user_id).await?;
+
+            let role = req.role.unwrap_or_else(|| MEMBER.to_string());
+            if role != OWNER && role != MEMBER {
+                return Err((StatusCode::BAD_REQUEST, format!("Members are either an `{OWNER}` or a `{MEMBER}`")));
+            }
+
+            let Some((member_id,)) = sqlx::query_as::<_, (i32,)>("SELECT id FROM users WHERE username = $1")
+                .bind(&req.username)
+                .fetch_optional(&state.db)
+                .await
+                .map_err(internal_error)?
+            else {
+                return Err((StatusCode::NOT_FOUND, format!("No user named {}", req.username)));
+            };
+
+            let added = sqlx::query(#add)
+                .bind(id)
+                .bind(member_id)
+                .bind(role)
+                .execute(&state.db)
+                .await
+                .map_err(internal_error)?
+                .rows_affected();
+
+            if added == 0 {
+                return Err((StatusCode::CONFLICT, format!("{} is already a member", req.username)));
+            }
+
+            Ok(StatusCode::CREATED)
+        }
+
+        /// Removes a member from the organization, as long as it isn't its last owner.
+        #instrument
+        pub async fn remove_member(
+            State(state): State<AppState>,
+            Extension(user): Extension<UserInfo>,
+            Path((id, member_id)): Path<(i32, i32)>,
+        ) -> Result<StatusCode, (StatusCode, String)> {
+            require_owner(&state, id, user.user_id).await?;
+
+            let removed = sqlx::query(#remove)
+                .bind(id)
+                .bind(member_id)
+                .execute(&state.db)
+                .await
+                .map_err(internal_error)?
+                .rows_affected();
+
+            if removed == 0 {
+                return Err((
+                    StatusCode::CONFLICT,
+                    "They aren't a member, or they're the organization's last owner".to_string(),
+                ));
+            }
+
+            Ok(StatusCode::OK)
+        }
+
+        /// Only an organization's owners can manage its members. Anyone else can't tell whether
+        /// it exists.
+        async fn require_owner(state: &AppState, id: i32, user_id: i32) -> Result<(), (StatusCode, String)> {
+            let role = sqlx::query_as::<_, (String,)>(#role)
+                .bind(id)
+                .bind(user_id)
+                .fetch_optional(&state.db)
+                .await
+                .map_err(internal_error)?;
+
+            match role {
+                Some((role,)) if role == OWNER => Ok(()),
+                Some(_) => Err((
+                    StatusCode::FORBIDDEN,
+                    "Only the organization's owners can manage its members".to_string(),
+                )),
+                None => Err((StatusCode::NOT_FOUND, format!("No organization with id {id}"))),
+            }
+        }
+
+        fn internal_error(e: impl Display) -> (StatusCode, String) {
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Something went wrong: {e}"))
+        }
+    };
+
+    syn::parse_file(&code.to_string()).unwrap()
+}
+
+/// The router for `/organizations`, which needs a session (and a CSRF token to change anything,
+/// when that's turned on).
+pub fn organizations_router(cfg: &Config) -> TokenStream {
+    let csrf = csrf_protected(cfg)
+        .then(|| quote! {.route_layer(from_fn_with_state(state.clone(), check_csrf))});
+
+    quote! {
+        let organizations_router = Router::new()
+            .route("/", post(create_organization)#csrf.get(list_organizations))
+            .route("/:id/members", post(add_member)#csrf)
+            .route("/:id/members/:user_id", delete(remove_member)#csrf)
+            .route_layer(from_fn_with_state(state.clone(), check_authed_cookies));
+    }
+}
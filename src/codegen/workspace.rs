@@ -0,0 +1,113 @@
+use crate::cli::Config;
+use crate::codegen::migration_file::MIGRATIONS_DIR;
+use crate::codegen::repositories::{domain_file, postgres_file};
+use crate::commands::write_file;
+use indoc::formatdoc;
+use proc_macro2::{Ident, Span};
+use quote::quote;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The crate with the binary, the handlers and `AppState`.
+pub const API_CRATE: &str = "api";
+/// The crate with the models and repository traits, which doesn't depend on a database.
+pub const DOMAIN_CRATE: &str = "domain";
+/// The crate with the Postgres repositories.
+pub const DB_CRATE: &str = "db";
+/// The crate embedding the migrations. The migrations themselves sit next to its manifest, so
+/// they're still in `migrations/` at the root of the project.
+pub const MIGRATIONS_CRATE: &str = MIGRATIONS_DIR;
+
+pub const MEMBERS: [&str; 4] = [API_CRATE, DOMAIN_CRATE, DB_CRATE, MIGRATIONS_CRATE];
+
+/// Creates the workspace's root manifest along with an empty crate for each member, and returns
+/// the project's path.
+pub fn init_workspace(project_name: &str) -> Result<PathBuf, String> {
+    fs::create_dir(project_name).map_err(|e| format!("Couldn't create {project_name}: {e}"))?;
+    let project_path = fs::canonicalize(project_name).unwrap();
+
+    let members: Vec<String> = MEMBERS.iter().map(|x| format!("\"{x}\"")).collect();
+    let manifest = formatdoc! {r#"
+        [workspace]
+        members = [{}]
+        resolver = "2"
+
+        [workspace.dependencies]
+    "#, members.join(", ")};
+
+    fs::write(project_path.join("Cargo.toml"), manifest)
+        .map_err(|e| format!("Couldn't write the workspace's Cargo.toml: {e}"))?;
+    fs::write(project_path.join(".gitignore"), "/target\n")
+        .map_err(|e| format!("Couldn't write .gitignore: {e}"))?;
+
+    // Same as `cargo init`, which doesn't mind if git isn't installed
+    let _ = Command::new("git")
+        .args(["init", "--quiet"])
+        .current_dir(&project_path)
+        .output();
+
+    for member in MEMBERS {
+        let crate_path = project_path.join(member);
+        fs::create_dir_all(crate_path.join("src"))
+            .map_err(|e| format!("Couldn't create the {member} crate: {e}"))?;
+
+        let manifest = formatdoc! {r#"
+            [package]
+            name = "{member}"
+            version = "0.1.0"
+            edition = "2021"
+            publish = false
+
+            [dependencies]
+        "#};
+
+        fs::write(crate_path.join("Cargo.toml"), manifest)
+            .map_err(|e| format!("Couldn't write the {member} crate's Cargo.toml: {e}"))?;
+    }
+
+    Ok(project_path)
+}
+
+/// Fills in the domain, db and migrations crates. The api crate is generated the same way as a
+/// single-crate project.
+pub fn write_member_crates(project_path: &Path, cfg: &Config) -> Result<(), String> {
+    let domain_src = project_path.join(DOMAIN_CRATE).join("src");
+    let db_src = project_path.join(DB_CRATE).join("src");
+
+    for route in &cfg.routes {
+        let filename = format!("{}.rs", route.name);
+
+        write_file(domain_file(route, cfg), domain_src.join(&filename))?;
+        write_file(postgres_file(route, cfg), db_src.join(&filename))?;
+    }
+
+    let modules: Vec<Ident> = cfg
+        .routes
+        .iter()
+        .map(|x| Ident::new(&x.name, Span::call_site()))
+        .collect();
+    let lib = quote! {
+        #(pub mod #modules;)*
+    };
+    let lib = syn::parse_file(&lib.to_string()).unwrap();
+
+    write_file(lib.clone(), domain_src.join("lib.rs"))?;
+    write_file(lib, db_src.join("lib.rs"))?;
+
+    // sqlx won't take "." as the path, so it goes through the workspace's root instead
+    let path = format!("../{MIGRATIONS_CRATE}");
+    let migrator = quote! {
+        use sqlx::migrate::Migrator;
+
+        /// The migrations next to this crate's manifest, for the api crate to run on startup
+        /// and for `#[sqlx::test]` to set up each test's database with.
+        pub static MIGRATOR: Migrator = sqlx::migrate!(#path);
+    };
+    let migrator = syn::parse_file(&migrator.to_string()).unwrap();
+
+    write_file(
+        migrator,
+        project_path.join(MIGRATIONS_CRATE).join("src/lib.rs"),
+    )
+}
@@ -1,9 +1,18 @@
-use crate::cli::Config;
+use crate::cli::{Config, Deployment, Layout, Orm, PasswordHash};
+use crate::codegen::csrf::csrf_protected;
+use crate::codegen::integration_tests::{has_integration_tests, tests_in_memory_links};
+use crate::codegen::mailer::sends_email;
+use crate::codegen::payments::takes_payments;
+use crate::codegen::rate_limit::rate_limited;
+use crate::codegen::seaorm::MIGRATION_CRATE;
+use crate::codegen::workspace::{API_CRATE, DB_CRATE, DOMAIN_CRATE, MIGRATIONS_CRATE};
 use std::fs;
-use std::path::PathBuf;
-use toml_edit::{value, Array, Document};
+use std::path::{Path, PathBuf};
+use toml_edit::{table, value, Array, Document};
 
 const SHUTTLE_VERSION: &str = "0.24.0";
+const SEA_ORM_VERSION: &str = "0.12.15";
+const DIESEL_VERSION: &str = "2.1.0";
 
 pub fn add_required_dependencies(project_path: PathBuf, cfg: Config) -> Result<(), &'static str> {
     let cargo_toml = fs::read_to_string(project_path.join("Cargo.toml"))
@@ -16,17 +25,154 @@ pub fn add_required_dependencies(project_path: PathBuf, cfg: Config) -> Result<(
         Err(_e) => return Err("Meme!"),
     };
 
+    add_app_dependencies(&mut toml, &cfg);
+
+    if (cfg.crud | cfg.auth) && cfg.orm == Orm::SeaOrm {
+        let mut members = Array::new();
+        members.push(".");
+        members.push(MIGRATION_CRATE);
+        toml["workspace"] = table();
+        toml["workspace"]["members"] = value(members);
+    }
+
+    fs::write(project_path.join("Cargo.toml"), toml.to_string()).unwrap();
+
+    Ok(())
+}
+
+/// Sets up the dependencies of a workspace's crates. Every version is declared once in the root
+/// manifest's `[workspace.dependencies]`, and the crates inherit them from there.
+pub fn add_workspace_dependencies(project_path: &Path, cfg: &Config) -> Result<(), String> {
+    let read = |path: PathBuf| -> Result<Document, String> {
+        fs::read_to_string(&path)
+            .map_err(|e| format!("Couldn't read {}: {e}", path.display()))?
+            .parse::<Document>()
+            .map_err(|e| format!("Couldn't parse {}: {e}", path.display()))
+    };
+    let write = |path: PathBuf, toml: Document| -> Result<(), String> {
+        fs::write(&path, toml.to_string())
+            .map_err(|e| format!("Couldn't write {}: {e}", path.display()))
+    };
+
+    let mut root = read(project_path.join("Cargo.toml"))?;
+    let mut api = read(project_path.join(API_CRATE).join("Cargo.toml"))?;
+
+    let mut member = WorkspaceMember {
+        root: &mut root,
+        member: &mut api,
+    };
+    add_app_dependencies(&mut member, cfg);
+
+    for name in [DOMAIN_CRATE, DB_CRATE, MIGRATIONS_CRATE] {
+        member.add_path_dependency(name, name);
+    }
+
+    write(project_path.join(API_CRATE).join("Cargo.toml"), api)?;
+
+    // Everything the other crates need has already been declared for the api crate
+    let members = [
+        (DOMAIN_CRATE, vec!["async-trait", "chrono", "serde", "sqlx"]),
+        (DB_CRATE, vec!["async-trait", DOMAIN_CRATE, "sqlx"]),
+        (MIGRATIONS_CRATE, vec!["sqlx"]),
+    ];
+
+    for (name, dependencies) in members {
+        let path = project_path.join(name).join("Cargo.toml");
+        let mut toml = read(path.clone())?;
+
+        for dependency in dependencies {
+            toml["dependencies"][dependency]["workspace"] = value(true);
+        }
+
+        write(path, toml)?;
+    }
+
+    write(project_path.join("Cargo.toml"), root)
+}
+
+fn add_app_dependencies(toml: &mut impl ManageDependencies, cfg: &Config) {
     toml.add_dependency("axum", "0.6.18");
-    toml.add_dependency("shuttle-runtime", SHUTTLE_VERSION);
-    toml.add_dependency("shuttle-axum", SHUTTLE_VERSION);
-    toml.add_dependency("tokio", "1.28.2");
+
+    match cfg.deployment {
+        Deployment::Shuttle => {
+            toml.add_dependency("shuttle-runtime", SHUTTLE_VERSION);
+            toml.add_dependency("shuttle-axum", SHUTTLE_VERSION);
+            toml.add_dependency("tokio", "1.28.2");
+        }
+        Deployment::Standalone => {
+            toml.add_dependency_with_features(
+                "tokio",
+                "1.28.2",
+                make_features(vec!["macros", "rt-multi-thread", "signal", "sync", "time"]),
+            );
+            // The server's settings are read from an optional TOML file
+            toml.add_dependency_with_features("serde", "1.0.171", make_features(vec!["derive"]));
+            toml.add_dependency("toml", "0.7.6");
+        }
+    }
 
     if cfg.crud | cfg.auth {
+        let mut sqlx_features = vec!["runtime-tokio-native-tls", "postgres", "chrono"];
+
+        // A workspace's migrations crate always embeds the migrations
+        if (cfg.embed_migrations || cfg.layout == Layout::Workspace) && cfg.orm == Orm::Sqlx {
+            sqlx_features.push("migrate");
+        }
+
+        toml.add_dependency_with_features("sqlx", "0.7.1", make_features(sqlx_features));
         toml.add_dependency_with_features(
-            "sqlx",
-            "0.7.1",
-            make_features(vec!["runtime-tokio-native-tls", "postgres", "chrono"]),
+            "chrono",
+            "0.4.26",
+            make_features(vec!["clock", "serde"]),
         );
+        toml.add_dependency_with_features("serde", "1.0.171", make_features(vec!["derive"]));
+    }
+
+    // The health endpoints respond with JSON, even when there's no database
+    if cfg.health && !(cfg.crud | cfg.auth) {
+        toml.add_dependency_with_features("serde", "1.0.171", make_features(vec!["derive"]));
+    }
+
+    if (cfg.crud | cfg.auth) && cfg.orm == Orm::SeaOrm {
+        toml.add_dependency_with_features(
+            "sea-orm",
+            SEA_ORM_VERSION,
+            make_features(vec![
+                "sqlx-postgres",
+                "runtime-tokio-native-tls",
+                "macros",
+                "with-chrono",
+            ]),
+        );
+
+        if cfg.embed_migrations {
+            toml.add_path_dependency(MIGRATION_CRATE, MIGRATION_CRATE);
+        }
+    }
+
+    if (cfg.crud | cfg.auth) && cfg.orm == Orm::Diesel {
+        toml.add_dependency_with_features(
+            "diesel",
+            DIESEL_VERSION,
+            make_features(vec!["postgres", "chrono"]),
+        );
+        toml.add_dependency_with_features(
+            "deadpool-diesel",
+            "0.6.1",
+            make_features(vec!["postgres"]),
+        );
+
+        if cfg.embed_migrations {
+            toml.add_dependency_with_features(
+                "diesel_migrations",
+                DIESEL_VERSION,
+                make_features(vec!["postgres"]),
+            );
+        }
+    }
+
+    if cfg.repositories {
+        toml.add_dependency("async-trait", "0.1.73");
     }
 
     if cfg.auth {
@@ -35,14 +181,72 @@ pub fn add_required_dependencies(project_path: PathBuf, cfg: Config) -> Result<(
             "0.7.7",
             make_features(vec!["cookie-private"]),
         );
+        toml.add_dependency("time", "0.3.26");
+
+        match cfg.password_hash {
+            // Passwords hashed with bcrypt before argon2id was used can still be checked
+            PasswordHash::Argon2id => {
+                toml.add_dependency_with_features("argon2", "0.5.2", make_features(vec!["std"]));
+                toml.add_dependency("bcrypt", "0.15.0");
+            }
+            PasswordHash::Bcrypt => toml.add_dependency("bcrypt", "0.15.0"),
+        }
+
+        // The cookie key is stored as base64, and session ids are random base64 strings
+        toml.add_dependency("base64", "0.21.2");
+        toml.add_dependency("rand", "0.8.5");
+    }
+
+    if sends_email(cfg) {
+        toml.add_dependency("async-trait", "0.1.73");
         toml.add_dependency_with_features(
-            "chrono",
-            "0.4.26",
-            make_features(vec!["clock", "serde"]),
+            "lettre",
+            "0.11.2",
+            make_features(vec![
+                "builder",
+                "hostname",
+                "pool",
+                "smtp-transport",
+                "tokio1",
+                "tokio1-native-tls",
+            ]),
         );
-        toml.add_dependency_with_features("serde", "1.0.171", make_features(vec!["derive"]));
-        toml.add_dependency("time", "0.3.26");
-        toml.add_dependency("bcrypt", "0.15.0");
+        // Only the tokens' hashes are stored
+        toml.add_dependency("sha2", "0.10.7");
+        toml.add_dependency("rand", "0.8.5");
+    }
+
+    if csrf_protected(cfg) {
+        toml.add_dependency("rand", "0.8.5");
+    }
+
+    // Only the keys' hashes are stored
+    if cfg.api_keys {
+        toml.add_dependency("base64", "0.21.2");
+        toml.add_dependency("sha2", "0.10.7");
+        toml.add_dependency("rand", "0.8.5");
+    }
+
+    if rate_limited(cfg) {
+        toml.add_dependency("async-trait", "0.1.73");
+        // The middleware reads the username out of the request body
+        toml.add_dependency("serde_json", "1.0.104");
+
+        if cfg.deployment == Deployment::Standalone {
+            toml.add_dependency("http-body", "0.4.5");
+        }
+    }
+
+    if takes_payments(cfg) {
+        toml.add_dependency_with_features("reqwest", "0.11.18", make_features(vec!["json"]));
+        toml.add_dependency("serde_json", "1.0.104");
+        // Webhooks are signed with HMAC-SHA256, in hex
+        toml.add_dependency("hmac", "0.12.1");
+        toml.add_dependency("sha2", "0.10.7");
+        toml.add_dependency("hex", "0.4.3");
+    }
+
+    if (cfg.crud | cfg.auth) && cfg.deployment == Deployment::Shuttle {
         toml.add_dependency_with_features(
             "shuttle-shared-db",
             SHUTTLE_VERSION,
@@ -54,14 +258,93 @@ pub fn add_required_dependencies(project_path: PathBuf, cfg: Config) -> Result<(
         toml.add_dependency("shuttle-secrets", SHUTTLE_VERSION);
     }
 
-    fs::write(project_path.join("Cargo.toml"), toml.to_string()).unwrap();
+    if cfg.metrics {
+        toml.add_dependency("metrics", "0.21.1");
+        toml.add_dependency("metrics-exporter-prometheus", "0.12.1");
+    }
 
-    Ok(())
+    let mut tower_http_features = Vec::new();
+
+    if cfg.deployment == Deployment::Standalone {
+        tower_http_features.extend(["cors", "limit", "timeout"]);
+    }
+
+    if cfg.tracing {
+        tower_http_features.extend(["trace", "request-id"]);
+    }
+
+    if !tower_http_features.is_empty() {
+        toml.add_dependency_with_features(
+            "tower-http",
+            "0.4.4",
+            make_features(tower_http_features),
+        );
+    }
+
+    if cfg.tracing {
+        toml.add_dependency("tracing", "0.1.37");
+
+        if cfg.deployment == Deployment::Standalone {
+            toml.add_dependency_with_features(
+                "tracing-subscriber",
+                "0.3.17",
+                make_features(vec!["env-filter", "json"]),
+            );
+        }
+    }
+
+    if has_integration_tests(cfg) {
+        toml.add_dev_dependency_with_features("tower", "0.4.13", make_features(vec!["util"]));
+
+        // The tests read the keys the admin routes create out of their responses, and build the
+        // payloads for the in-memory repositories from JSON
+        if cfg.api_keys || tests_in_memory_links(cfg) {
+            toml.add_dev_dependency("serde_json", "1.0.104");
+        }
+
+        // Tests that don't need a database use `#[tokio::test]` rather than `#[sqlx::test]`.
+        // Standalone servers already depend on tokio's macros
+        let tokio_tests = !(cfg.crud | cfg.auth) || tests_in_memory_links(cfg);
+        if tokio_tests && cfg.deployment == Deployment::Shuttle {
+            toml.add_dev_dependency_with_features(
+                "tokio",
+                "1.28.2",
+                make_features(vec!["macros", "rt-multi-thread"]),
+            );
+        }
+    }
+}
+
+/// Sets up the dependencies of a SeaORM project's migration crate.
+pub fn add_migration_crate_dependencies(crate_path: &Path) -> Result<(), String> {
+    let cargo_toml = fs::read_to_string(crate_path.join("Cargo.toml"))
+        .map_err(|e| format!("Couldn't read the migration crate's Cargo.toml: {e}"))?;
+
+    let mut toml = cargo_toml
+        .parse::<Document>()
+        .map_err(|e| format!("Couldn't parse the migration crate's Cargo.toml: {e}"))?;
+
+    toml.add_dependency_with_features(
+        "sea-orm-migration",
+        SEA_ORM_VERSION,
+        make_features(vec!["runtime-tokio-native-tls", "sqlx-postgres"]),
+    );
+    toml.add_dependency_with_features(
+        "tokio",
+        "1.28.2",
+        make_features(vec!["macros", "rt-multi-thread"]),
+    );
+
+    fs::write(crate_path.join("Cargo.toml"), toml.to_string())
+        .map_err(|e| format!("Couldn't write the migration crate's Cargo.toml: {e}"))
 }
 
 trait ManageDependencies {
     fn add_dependency(&mut self, _name: &str, _version: &str) {}
     fn add_dependency_with_features(&mut self, _name: &str, _version: &str, _features: Array) {}
+    fn add_path_dependency(&mut self, _name: &str, _path: &str) {}
+    fn add_dev_dependency(&mut self, _name: &str, _version: &str) {}
+    fn add_dev_dependency_with_features(&mut self, _name: &str, _version: &str, _features: Array) {}
 }
 
 impl ManageDependencies for Document {
@@ -73,6 +356,78 @@ impl ManageDependencies for Document {
         self["dependencies"][name]["version"] = value(version);
         self["dependencies"][name]["features"] = value(features);
     }
+
+    fn add_path_dependency(&mut self, name: &str, path: &str) {
+        self["dependencies"][name]["path"] = value(path);
+    }
+
+    fn add_dev_dependency(&mut self, name: &str, version: &str) {
+        if !self.contains_key("dev-dependencies") {
+            self["dev-dependencies"] = table();
+        }
+
+        self["dev-dependencies"][name] = value(version);
+    }
+
+    fn add_dev_dependency_with_features(&mut self, name: &str, version: &str, features: Array) {
+        if !self.contains_key("dev-dependencies") {
+            self["dev-dependencies"] = table();
+        }
+
+        self["dev-dependencies"][name]["version"] = value(version);
+        self["dev-dependencies"][name]["features"] = value(features);
+    }
+}
+
+/// A crate in a workspace, whose dependencies are declared in the root manifest and inherited with
+/// `workspace = true`.
+struct WorkspaceMember<'a> {
+    root: &'a mut Document,
+    member: &'a mut Document,
+}
+
+impl ManageDependencies for WorkspaceMember<'_> {
+    fn add_dependency(&mut self, name: &str, version: &str) {
+        self.root["workspace"]["dependencies"][name] = value(version);
+        self.member["dependencies"][name]["workspace"] = value(true);
+    }
+
+    fn add_dependency_with_features(&mut self, name: &str, version: &str, features: Array) {
+        self.root["workspace"]["dependencies"][name]["version"] = value(version);
+        self.root["workspace"]["dependencies"][name]["features"] = value(features);
+        self.member["dependencies"][name]["workspace"] = value(true);
+    }
+
+    fn add_path_dependency(&mut self, name: &str, path: &str) {
+        self.root["workspace"]["dependencies"][name]["path"] = value(path);
+        self.member["dependencies"][name]["workspace"] = value(true);
+    }
+
+    fn add_dev_dependency(&mut self, name: &str, version: &str) {
+        if !self.member.contains_key("dev-dependencies") {
+            self.member["dev-dependencies"] = table();
+        }
+
+        self.root["workspace"]["dependencies"][name] = value(version);
+        self.member["dev-dependencies"][name]["workspace"] = value(true);
+    }
+
+    // The features only go on the member's dev-dependency, since inherited features are added to
+    // the workspace's. An entry the runtime already declared in the root is left as it is
+    fn add_dev_dependency_with_features(&mut self, name: &str, version: &str, features: Array) {
+        if !self.member.contains_key("dev-dependencies") {
+            self.member["dev-dependencies"] = table();
+        }
+
+        if !self.root["workspace"]["dependencies"]
+            .as_table_like()
+            .is_some_and(|x| x.contains_key(name))
+        {
+            self.root["workspace"]["dependencies"][name] = value(version);
+        }
+        self.member["dev-dependencies"][name]["workspace"] = value(true);
+        self.member["dev-dependencies"][name]["features"] = value(features);
+    }
 }
 
 fn make_features(features: Vec<&str>) -> Array {
@@ -84,3 +439,63 @@ fn make_features(features: Vec<&str>) -> Array {
 
     arr
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::parse_manifest;
+
+    #[test]
+    fn keeps_the_workspace_runtime_when_tests_need_more_features() {
+        let (_, cfg) = parse_manifest(
+            r#"
+            name = "test"
+            deployment = "shuttle"
+            layout = "workspace"
+            repositories = true
+
+            [[resources]]
+            name = "post"
+
+            [[resources.relations]]
+            kind = "many_to_many"
+            target = "tag"
+
+            [[resources]]
+            name = "tag"
+            "#,
+        )
+        .unwrap();
+
+        let mut root = "[workspace]\n".parse::<Document>().unwrap();
+        root["workspace"]["dependencies"] = table();
+        let mut api = "[dependencies]\n".parse::<Document>().unwrap();
+
+        add_app_dependencies(
+            &mut WorkspaceMember {
+                root: &mut root,
+                member: &mut api,
+            },
+            &cfg,
+        );
+
+        assert_eq!(
+            root["workspace"]["dependencies"]["tokio"].as_str(),
+            Some("1.28.2")
+        );
+        assert_eq!(
+            api["dependencies"]["tokio"]["workspace"].as_bool(),
+            Some(true)
+        );
+
+        let tokio = &api["dev-dependencies"]["tokio"];
+        assert_eq!(tokio["workspace"].as_bool(), Some(true));
+        let features: Vec<_> = tokio["features"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|x| x.as_str())
+            .collect();
+        assert_eq!(features, ["macros", "rt-multi-thread"]);
+    }
+}
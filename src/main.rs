@@ -1,14 +1,20 @@
 use cli::process_commands;
 
+mod apikey;
 mod cli;
 mod codegen;
 mod commands;
 mod dependencies;
+mod manifest;
+mod migrate;
+mod project;
+mod schema;
+mod secret;
+mod wizard;
 
 fn main() {
     if let Err(e) = process_commands() {
-        panic!("Process panicked: {e}");
+        eprintln!("{e}");
+        std::process::exit(1);
     }
-
-    println!("Bootstrapping complete!");
 }
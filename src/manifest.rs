@@ -0,0 +1,396 @@
+use crate::cli::{
+    Config, Deployment, HashParams, Layout, Orm, PasswordHash, PaymentProvider, RateLimitStore,
+    Route, DEFAULT_HEALTH_PATH, DEFAULT_READY_PATH,
+};
+use crate::schema::{Field, FieldType, OnDelete, Relation, RelationKind};
+use std::fs;
+use std::path::Path;
+use toml_edit::{value, ArrayOfTables, Document, Item, Table};
+
+/// Where a project's manifest lives, relative to the project root.
+pub const MANIFEST: &str = "inlet.toml";
+/// Where the schema that migrations were last generated from is recorded, relative to the project root.
+pub const SCHEMA_SNAPSHOT: &str = ".inlet/schema.toml";
+
+pub const FRAMEWORKS: [&str; 1] = ["axum"];
+pub const DATABASES: [&str; 1] = ["postgres"];
+
+pub fn write_manifest(path: &Path, project_name: &str, cfg: &Config) -> Result<(), String> {
+    let mut doc = Document::new();
+
+    doc["name"] = value(project_name);
+    doc["framework"] = value(FRAMEWORKS[0]);
+    doc["database"] = value(DATABASES[0]);
+    doc["deployment"] = value(cfg.deployment.name());
+    doc["auth"] = value(cfg.auth);
+    doc["api_keys"] = value(cfg.api_keys);
+    doc["email_verification"] = value(cfg.email_verification);
+    doc["password_reset"] = value(cfg.password_reset);
+    doc["rate_limit"] = value(cfg.rate_limit);
+    doc["rate_limit_store"] = value(cfg.rate_limit_store.name());
+    doc["csrf"] = value(cfg.csrf);
+
+    if let Some(payments) = cfg.payments {
+        doc["payments"] = value(payments.name());
+    }
+
+    doc["multi_tenant"] = value(cfg.multi_tenant);
+
+    doc["password_hash"] = value(cfg.password_hash.name());
+
+    // Only the parameters of the algorithm in use are written
+    match cfg.password_hash {
+        PasswordHash::Argon2id => {
+            doc["argon2_memory_kib"] = value(i64::from(cfg.hash_params.argon2_memory_kib));
+            doc["argon2_iterations"] = value(i64::from(cfg.hash_params.argon2_iterations));
+            doc["argon2_parallelism"] = value(i64::from(cfg.hash_params.argon2_parallelism));
+        }
+        PasswordHash::Bcrypt => {
+            doc["bcrypt_cost"] = value(i64::from(cfg.hash_params.bcrypt_cost));
+        }
+    }
+
+    doc["secrets"] = value(cfg.secrets);
+    doc["embed_migrations"] = value(cfg.embed_migrations);
+    doc["checked_queries"] = value(cfg.checked_queries);
+    doc["orm"] = value(cfg.orm.name());
+    doc["repositories"] = value(cfg.repositories);
+    doc["layout"] = value(cfg.layout.name());
+    doc["tracing"] = value(cfg.tracing);
+    doc["metrics"] = value(cfg.metrics);
+    doc["health"] = value(cfg.health);
+    doc["health_path"] = value(&cfg.health_path);
+    doc["ready_path"] = value(&cfg.ready_path);
+
+    let mut secret_keys = ArrayOfTables::new();
+
+    for secret in &cfg.secret_keys {
+        let mut table = Table::new();
+        table["name"] = value(&secret.name);
+        table["type"] = value(secret.ty.name());
+        table["optional"] = value(secret.nullable);
+        secret_keys.push(table);
+    }
+
+    doc["secret_keys"] = Item::ArrayOfTables(secret_keys);
+
+    let mut resources = ArrayOfTables::new();
+
+    for route in &cfg.routes {
+        let mut resource = Table::new();
+        resource["name"] = value(&route.name);
+        resource["protected"] = value(route.auth_required);
+
+        let mut fields = ArrayOfTables::new();
+
+        for field in &route.fields {
+            let mut table = Table::new();
+            table["name"] = value(&field.name);
+            table["type"] = value(field.ty.name());
+            table["nullable"] = value(field.nullable);
+            if field.indexed {
+                table["indexed"] = value(true);
+            }
+            if field.unique {
+                table["unique"] = value(true);
+            }
+            if let Some(renamed_from) = &field.renamed_from {
+                table["renamed_from"] = value(renamed_from);
+            }
+            fields.push(table);
+        }
+
+        resource["fields"] = Item::ArrayOfTables(fields);
+
+        let mut relations = ArrayOfTables::new();
+
+        for relation in &route.relations {
+            let mut table = Table::new();
+            table["kind"] = value(relation.kind.name());
+            table["target"] = value(&relation.target);
+            table["on_delete"] = value(relation.on_delete.name());
+            relations.push(table);
+        }
+
+        resource["relations"] = Item::ArrayOfTables(relations);
+        resources.push(resource);
+    }
+
+    doc["resources"] = Item::ArrayOfTables(resources);
+
+    fs::write(path, doc.to_string()).map_err(|e| format!("Couldn't write the manifest: {e}"))
+}
+
+/// Records `cfg` as the schema the project's migrations are currently at.
+pub fn record_schema(project_path: &Path, project_name: &str, cfg: &Config) -> Result<(), String> {
+    let path = project_path.join(SCHEMA_SNAPSHOT);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Couldn't create {}: {e}", dir.display()))?;
+    }
+
+    // Renames only make sense relative to the previous snapshot
+    let mut cfg = cfg.clone();
+    for route in cfg.routes.iter_mut() {
+        for field in route.fields.iter_mut() {
+            field.renamed_from = None;
+        }
+    }
+
+    write_manifest(&path, project_name, &cfg)
+}
+
+pub fn read_manifest(path: &Path) -> Result<(String, Config), String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read the manifest at {}: {e}", path.display()))?;
+
+    parse_manifest(&contents)
+}
+
+/// Reads a project's name and configuration from the contents of a manifest.
+pub fn parse_manifest(contents: &str) -> Result<(String, Config), String> {
+    let doc = match contents.parse::<Document>() {
+        Ok(res) => res,
+        Err(e) => return Err(format!("Couldn't parse the manifest: {e}")),
+    };
+
+    let Some(name) = doc.get("name").and_then(|x| x.as_str()) else {
+        return Err("The manifest is missing a project name".to_string());
+    };
+
+    let framework = get_str(doc.as_table(), "framework", FRAMEWORKS[0])?;
+    if !FRAMEWORKS.contains(&framework) {
+        return Err(format!("Unsupported framework: {framework}"));
+    }
+
+    let database = get_str(doc.as_table(), "database", DATABASES[0])?;
+    if !DATABASES.contains(&database) {
+        return Err(format!("Unsupported database: {database}"));
+    }
+
+    let deployment = get_str(doc.as_table(), "deployment", Deployment::Shuttle.name())?;
+    let Some(deployment) = Deployment::from_name(deployment) else {
+        return Err(format!("Unsupported deployment target: {deployment}"));
+    };
+
+    let orm = get_str(doc.as_table(), "orm", Orm::Sqlx.name())?;
+    let Some(orm) = Orm::from_name(orm) else {
+        return Err(format!("Unsupported ORM: {orm}"));
+    };
+
+    let layout = get_str(doc.as_table(), "layout", Layout::Single.name())?;
+    let Some(layout) = Layout::from_name(layout) else {
+        return Err(format!("Unsupported layout: {layout}"));
+    };
+
+    let rate_limit_store = get_str(
+        doc.as_table(),
+        "rate_limit_store",
+        RateLimitStore::Memory.name(),
+    )?;
+    let Some(rate_limit_store) = RateLimitStore::from_name(rate_limit_store) else {
+        return Err(format!("Unsupported rate limit store: {rate_limit_store}"));
+    };
+
+    let password_hash = get_str(
+        doc.as_table(),
+        "password_hash",
+        PasswordHash::Argon2id.name(),
+    )?;
+    let Some(password_hash) = PasswordHash::from_name(password_hash) else {
+        return Err(format!("Unsupported password hash: {password_hash}"));
+    };
+
+    let defaults = HashParams::default();
+    let hash_params = HashParams {
+        argon2_memory_kib: get_u32(
+            doc.as_table(),
+            "argon2_memory_kib",
+            defaults.argon2_memory_kib,
+        )?,
+        argon2_iterations: get_u32(
+            doc.as_table(),
+            "argon2_iterations",
+            defaults.argon2_iterations,
+        )?,
+        argon2_parallelism: get_u32(
+            doc.as_table(),
+            "argon2_parallelism",
+            defaults.argon2_parallelism,
+        )?,
+        bcrypt_cost: get_u32(doc.as_table(), "bcrypt_cost", defaults.bcrypt_cost)?,
+    };
+
+    let mut routes = Vec::new();
+
+    if let Some(resources) = doc.get("resources") {
+        let Some(resources) = resources.as_array_of_tables() else {
+            return Err("`resources` should be an array of tables".to_string());
+        };
+
+        for resource in resources {
+            routes.push(read_resource(resource)?);
+        }
+    }
+
+    let mut secret_keys = Vec::new();
+
+    if let Some(tables) = doc.get("secret_keys") {
+        let Some(tables) = tables.as_array_of_tables() else {
+            return Err("`secret_keys` should be an array of tables".to_string());
+        };
+
+        for table in tables {
+            let Some(secret_name) = table.get("name").and_then(|x| x.as_str()) else {
+                return Err("Every secret needs a name".to_string());
+            };
+
+            let ty = get_str(table, "type", FieldType::Text.name())?;
+            let Some(ty) = FieldType::from_name(ty) else {
+                return Err(format!("Unknown type for the `{secret_name}` secret: {ty}"));
+            };
+
+            secret_keys.push(Field::new(secret_name, ty, get_bool(table, "optional")?));
+        }
+    }
+
+    // Payments are left out of the manifest when there aren't any
+    let payments = match get_str(doc.as_table(), "payments", "")? {
+        "" => None,
+        name => match PaymentProvider::from_name(name) {
+            Some(provider) => Some(provider),
+            None => return Err(format!("Unsupported payment provider: {name}")),
+        },
+    };
+
+    let auth = get_bool(doc.as_table(), "auth")?;
+
+    let cfg = Config {
+        crud: !routes.is_empty(),
+        auth,
+        api_keys: get_bool(doc.as_table(), "api_keys")?,
+        email_verification: get_bool(doc.as_table(), "email_verification")?,
+        password_reset: get_bool(doc.as_table(), "password_reset")?,
+        rate_limit: get_bool(doc.as_table(), "rate_limit")?,
+        rate_limit_store,
+        csrf: get_bool(doc.as_table(), "csrf")?,
+        payments,
+        multi_tenant: get_bool(doc.as_table(), "multi_tenant")?,
+        password_hash,
+        hash_params,
+        routes,
+        secrets: get_bool(doc.as_table(), "secrets")?
+            || (auth && deployment == Deployment::Shuttle),
+        secret_keys,
+        deployment,
+        embed_migrations: get_bool(doc.as_table(), "embed_migrations")?,
+        checked_queries: get_bool(doc.as_table(), "checked_queries")?,
+        orm,
+        repositories: get_bool(doc.as_table(), "repositories")?,
+        layout,
+        tracing: get_bool(doc.as_table(), "tracing")?,
+        metrics: get_bool(doc.as_table(), "metrics")?,
+        health: get_bool(doc.as_table(), "health")?,
+        health_path: get_str(doc.as_table(), "health_path", DEFAULT_HEALTH_PATH)?.to_string(),
+        ready_path: get_str(doc.as_table(), "ready_path", DEFAULT_READY_PATH)?.to_string(),
+    };
+
+    Ok((name.to_string(), cfg))
+}
+
+fn read_resource(resource: &Table) -> Result<Route, String> {
+    let Some(name) = resource.get("name").and_then(|x| x.as_str()) else {
+        return Err("Every resource needs a name".to_string());
+    };
+
+    let mut fields = Vec::new();
+
+    if let Some(tables) = resource.get("fields").and_then(|x| x.as_array_of_tables()) {
+        for table in tables {
+            let Some(field_name) = table.get("name").and_then(|x| x.as_str()) else {
+                return Err(format!("A field on `{name}` is missing a name"));
+            };
+
+            let ty = get_str(table, "type", FieldType::Text.name())?;
+            let Some(ty) = FieldType::from_name(ty) else {
+                return Err(format!("Unknown type for `{name}.{field_name}`: {ty}"));
+            };
+
+            let mut field = Field::new(field_name, ty, get_bool(table, "nullable")?);
+            field.indexed = get_bool(table, "indexed")?;
+            field.unique = get_bool(table, "unique")?;
+            field.renamed_from = table
+                .get("renamed_from")
+                .and_then(|x| x.as_str())
+                .map(|x| x.to_string());
+
+            fields.push(field);
+        }
+    }
+
+    let mut relations = Vec::new();
+
+    if let Some(tables) = resource
+        .get("relations")
+        .and_then(|x| x.as_array_of_tables())
+    {
+        for table in tables {
+            let kind = get_str(table, "kind", "")?;
+            let Some(kind) = RelationKind::from_name(kind) else {
+                return Err(format!("Unknown relation kind on `{name}`: {kind}"));
+            };
+
+            let Some(target) = table.get("target").and_then(|x| x.as_str()) else {
+                return Err(format!("A relation on `{name}` is missing a target"));
+            };
+
+            let on_delete = get_str(table, "on_delete", OnDelete::Cascade.name())?;
+            let Some(on_delete) = OnDelete::from_name(on_delete) else {
+                return Err(format!(
+                    "Unknown ON DELETE behaviour on `{name}`: {on_delete}"
+                ));
+            };
+
+            relations.push(Relation {
+                kind,
+                target: target.to_string(),
+                on_delete,
+            });
+        }
+    }
+
+    Ok(Route {
+        name: name.to_string(),
+        auth_required: get_bool(resource, "protected")?,
+        fields,
+        relations,
+    })
+}
+
+fn get_str<'a>(table: &'a Table, key: &str, default: &'a str) -> Result<&'a str, String> {
+    match table.get(key) {
+        Some(item) => item
+            .as_str()
+            .ok_or_else(|| format!("`{key}` should be a string")),
+        None => Ok(default),
+    }
+}
+
+fn get_bool(table: &Table, key: &str) -> Result<bool, String> {
+    match table.get(key) {
+        Some(item) => item
+            .as_bool()
+            .ok_or_else(|| format!("`{key}` should be true or false")),
+        None => Ok(false),
+    }
+}
+
+fn get_u32(table: &Table, key: &str, default: u32) -> Result<u32, String> {
+    match table.get(key) {
+        Some(item) => item
+            .as_integer()
+            .and_then(|x| u32::try_from(x).ok())
+            .ok_or_else(|| format!("`{key}` should be a positive integer")),
+        None => Ok(default),
+    }
+}
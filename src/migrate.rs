@@ -0,0 +1,85 @@
+use crate::cli::Orm;
+use crate::codegen::migration_file::{schema_changes, write_migration, MIGRATIONS_DIR};
+use crate::codegen::seaorm::write_migration_module;
+use crate::manifest::{read_manifest, record_schema, MANIFEST, SCHEMA_SNAPSHOT};
+use inquire::Confirm;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
+
+pub fn migrate_diff(
+    project_path: &Path,
+    description: &str,
+    allow_destructive: bool,
+) -> Result<(), String> {
+    let (project_name, declared) = read_manifest(&project_path.join(MANIFEST))?;
+    let (_, recorded) = read_manifest(&project_path.join(SCHEMA_SNAPSHOT))?;
+
+    declared.validate()?;
+
+    if declared.orm != recorded.orm {
+        return Err(format!(
+            "The project's migrations were generated for {}, so they can't be continued with {}",
+            recorded.orm, declared.orm
+        ));
+    }
+
+    let changes = schema_changes(&recorded, &declared)?;
+
+    if changes.is_empty() {
+        println!("No schema changes found.");
+        return Ok(());
+    }
+
+    let destructive: Vec<&String> = changes
+        .iter()
+        .filter_map(|x| x.destructive.as_ref())
+        .collect();
+
+    if !destructive.is_empty() && !allow_destructive {
+        let warning = format!(
+            "This migration contains changes that could lose data:\n  {}",
+            destructive
+                .iter()
+                .map(|x| x.as_str())
+                .collect::<Vec<&str>>()
+                .join("\n  ")
+        );
+
+        if !std::io::stdin().is_terminal() {
+            return Err(format!(
+                "{warning}\nRe-run with --allow-destructive to generate it anyway."
+            ));
+        }
+
+        println!("{warning}");
+
+        let confirmed = Confirm::new("Do you want to generate it anyway?")
+            .with_default(false)
+            .prompt()
+            .map_err(|_| "Couldn't find your choice :(".to_string())?;
+
+        if !confirmed {
+            return Err("No migration was generated.".to_string());
+        }
+    }
+
+    let description = description.replace(|x: char| !x.is_ascii_alphanumeric(), "_");
+
+    match declared.orm {
+        Orm::SeaOrm => write_migration_module(project_path, &description, &changes)?,
+        orm => {
+            let migrations_dir = project_path.join(MIGRATIONS_DIR);
+            fs::create_dir_all(&migrations_dir)
+                .map_err(|e| format!("Couldn't create the migrations directory: {e}"))?;
+
+            write_migration(&migrations_dir, &description, &changes, orm);
+        }
+    }
+
+    record_schema(project_path, &project_name, &declared)?;
+
+    println!("Generated a migration with {} change(s).", changes.len());
+
+    Ok(())
+}
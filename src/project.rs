@@ -0,0 +1,208 @@
+use crate::cli::{Config, Deployment, Layout, Orm};
+use crate::codegen::api_keys::{api_key_middleware, api_key_routes};
+use crate::codegen::axum_auth::{auth_middleware, auth_routes};
+use crate::codegen::csrf::{csrf_middleware, csrf_protected};
+use crate::codegen::diesel::schema_file;
+use crate::codegen::health::health_file;
+use crate::codegen::integration_tests::{has_integration_tests, integration_tests, TESTS_FILE};
+use crate::codegen::mailer::{mailer_file, sends_email};
+use crate::codegen::main_fn::{axum_crud_fns, lib_function, main_function};
+use crate::codegen::metrics::{metrics_file, METRICS_MODULE};
+use crate::codegen::migration_file::{write_migration_file, MIGRATIONS_DIR};
+use crate::codegen::password::password_file;
+use crate::codegen::payments::{payment_routes, stripe_file, takes_payments};
+use crate::codegen::rate_limit::{rate_limit_file, rate_limited};
+use crate::codegen::repositories::repository_file;
+use crate::codegen::seaorm::{entity_files, write_migration_crate};
+use crate::codegen::secrets::app_config_file;
+use crate::codegen::server_config::config_file;
+use crate::codegen::sqlx_offline::write_offline_data;
+use crate::codegen::tenants::{organization_routes, tenant_extractor};
+use crate::codegen::workspace::{init_workspace, write_member_crates, API_CRATE};
+use crate::commands::{
+    cargo_init, make_dir, write_diesel_config, write_file, write_main_file, write_mod_file,
+    write_readme_file, write_secrets_file,
+};
+use crate::dependencies::{add_required_dependencies, add_workspace_dependencies};
+use crate::manifest::{record_schema, write_manifest, MANIFEST};
+use std::fs;
+
+pub fn create_project(project_name: &str, cfg: Config) -> Result<(), String> {
+    // In a workspace, the generated app is the api crate, while the migrations, the manifest and
+    // the other project-wide files stay at the root
+    let (project_path, app_path, crate_name) = match cfg.layout {
+        Layout::Single => {
+            let project_path = cargo_init(project_name);
+            (
+                project_path.clone(),
+                project_path,
+                project_name.replace('-', "_"),
+            )
+        }
+        Layout::Workspace => {
+            let project_path = init_workspace(project_name)?;
+            (
+                project_path.clone(),
+                project_path.join(API_CRATE),
+                API_CRATE.to_string(),
+            )
+        }
+    };
+    let routes_dir = make_dir(app_path.clone(), "routes");
+
+    if cfg.auth {
+        let middleware_dir = make_dir(app_path.clone(), "middleware");
+        write_file(auth_middleware(&cfg), middleware_dir.join("auth.rs")).unwrap();
+        if csrf_protected(&cfg) {
+            write_file(csrf_middleware(), middleware_dir.join("csrf.rs")).unwrap();
+        }
+        if cfg.multi_tenant {
+            write_file(tenant_extractor(), middleware_dir.join("tenant.rs")).unwrap();
+            write_file(
+                organization_routes(&cfg),
+                routes_dir.join("organizations.rs"),
+            )
+            .unwrap();
+        }
+        write_mod_file(middleware_dir).unwrap();
+        write_file(auth_routes(&cfg), routes_dir.clone().join("auth.rs")).unwrap();
+        write_file(password_file(&cfg), app_path.join("src/password.rs")).unwrap();
+    }
+
+    if cfg.api_keys {
+        let middleware_dir = make_dir(app_path.clone(), "middleware");
+        write_file(api_key_middleware(&cfg), middleware_dir.join("api_key.rs")).unwrap();
+        write_mod_file(middleware_dir).unwrap();
+        write_file(api_key_routes(&cfg), routes_dir.clone().join("api_keys.rs")).unwrap();
+    }
+
+    for route in &cfg.routes {
+        let tablename_as_filename = format!("{}.rs", route.name);
+
+        let (crud_routes, extra_deps) = axum_crud_fns(route.clone(), route.auth_required, &cfg);
+
+        write_main_file(
+            crud_routes,
+            extra_deps,
+            routes_dir.clone().join(tablename_as_filename),
+        )
+        .unwrap();
+    }
+
+    if cfg.crud && cfg.orm == Orm::SeaOrm {
+        let entities_dir = make_dir(project_path.clone(), "entities");
+
+        for (name, entity) in entity_files(&cfg) {
+            write_file(entity, entities_dir.join(format!("{name}.rs"))).unwrap();
+        }
+
+        write_mod_file(entities_dir).unwrap();
+    }
+
+    if cfg.layout == Layout::Workspace {
+        write_member_crates(&project_path, &cfg)?;
+    } else if cfg.repositories {
+        let repositories_dir = make_dir(project_path.clone(), "repositories");
+
+        for route in &cfg.routes {
+            write_file(
+                repository_file(route, &cfg),
+                repositories_dir.join(format!("{}.rs", route.name)),
+            )
+            .unwrap();
+        }
+
+        write_mod_file(repositories_dir).unwrap();
+    }
+
+    if cfg.crud | cfg.auth {
+        match cfg.orm {
+            Orm::SeaOrm => write_migration_crate(&project_path, &cfg)?,
+            _ => {
+                let migrations_dir = project_path.join(MIGRATIONS_DIR);
+                fs::create_dir_all(&migrations_dir).unwrap();
+                write_migration_file(&migrations_dir, cfg.clone());
+            }
+        }
+    }
+
+    if (cfg.crud | cfg.auth) && cfg.orm == Orm::Diesel {
+        write_diesel_config(project_path.clone());
+    }
+
+    if cfg.crud && cfg.orm == Orm::Diesel {
+        fs::write(project_path.join("src/schema.rs"), schema_file(&cfg)).unwrap();
+    }
+
+    if cfg.checked_queries {
+        write_offline_data(&project_path, &cfg);
+    }
+
+    if cfg.secrets {
+        write_secrets_file(&project_path, &cfg)?;
+        write_file(app_config_file(&cfg), app_path.join("src/config.rs")).unwrap();
+    }
+
+    if cfg.deployment == Deployment::Standalone {
+        write_file(config_file(&cfg), app_path.join("src/config.rs")).unwrap();
+    }
+
+    if sends_email(&cfg) {
+        write_file(mailer_file(&cfg), app_path.join("src/mailer.rs")).unwrap();
+    }
+
+    if rate_limited(&cfg) {
+        write_file(rate_limit_file(&cfg), app_path.join("src/rate_limit.rs")).unwrap();
+    }
+
+    if takes_payments(&cfg) {
+        write_file(stripe_file(), app_path.join("src/stripe.rs")).unwrap();
+        write_file(payment_routes(&cfg), routes_dir.join("payments.rs")).unwrap();
+    }
+
+    if cfg.health {
+        write_file(health_file(&cfg), app_path.join("src/health.rs")).unwrap();
+    }
+
+    if cfg.metrics {
+        write_file(
+            metrics_file(&cfg),
+            app_path.join(format!("src/{METRICS_MODULE}.rs")),
+        )
+        .unwrap();
+    }
+
+    write_readme_file(project_path.clone(), project_name, &cfg);
+
+    write_mod_file(routes_dir).unwrap();
+
+    write_manifest(&project_path.join(MANIFEST), project_name, &cfg)?;
+    record_schema(&project_path, project_name, &cfg)?;
+
+    let (lib_file, lib_useitems) = lib_function(cfg.clone());
+    write_main_file(lib_file, lib_useitems, app_path.join("src/lib.rs")).unwrap();
+
+    let (main_fn_file, router_useitems) = main_function(cfg.clone(), &crate_name);
+    write_main_file(main_fn_file, router_useitems, app_path.join("src/main.rs")).unwrap();
+
+    if has_integration_tests(&cfg) {
+        let (tests_file, tests_useitems) = integration_tests(&cfg, &crate_name);
+        let tests_path = app_path.join(TESTS_FILE);
+        fs::create_dir_all(tests_path.parent().unwrap()).unwrap();
+        write_main_file(tests_file, tests_useitems, tests_path).unwrap();
+    }
+
+    match cfg.layout {
+        Layout::Single => {
+            if let Err(e) = add_required_dependencies(project_path, cfg) {
+                return Err(format!("Error while adding dependencies: {e}"));
+            }
+        }
+        Layout::Workspace => add_workspace_dependencies(&project_path, &cfg)
+            .map_err(|e| format!("Error while adding dependencies: {e}"))?,
+    }
+
+    println!("Bootstrapping complete!");
+
+    Ok(())
+}
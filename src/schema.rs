@@ -0,0 +1,503 @@
+use crate::cli::Route;
+use proc_macro2::TokenStream;
+use quote::quote;
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FieldType {
+    Text,
+    Integer,
+    BigInt,
+    Float,
+    Boolean,
+    Timestamp,
+}
+
+impl FieldType {
+    pub const ALL: [FieldType; 6] = [
+        FieldType::Text,
+        FieldType::Integer,
+        FieldType::BigInt,
+        FieldType::Float,
+        FieldType::Boolean,
+        FieldType::Timestamp,
+    ];
+
+    /// The name used for this type in manifests.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FieldType::Text => "text",
+            FieldType::Integer => "integer",
+            FieldType::BigInt => "bigint",
+            FieldType::Float => "float",
+            FieldType::Boolean => "boolean",
+            FieldType::Timestamp => "timestamp",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        FieldType::ALL.into_iter().find(|x| x.name() == name)
+    }
+
+    pub fn sql_type(&self) -> &'static str {
+        match self {
+            FieldType::Text => "VARCHAR",
+            FieldType::Integer => "INT",
+            FieldType::BigInt => "BIGINT",
+            FieldType::Float => "DOUBLE PRECISION",
+            FieldType::Boolean => "BOOLEAN",
+            FieldType::Timestamp => "TIMESTAMP WITH TIME ZONE",
+        }
+    }
+
+    /// The name sqlx gives this type when describing a Postgres query.
+    pub fn type_info(&self) -> &'static str {
+        match self {
+            FieldType::Text => "Varchar",
+            FieldType::Integer => "Int4",
+            FieldType::BigInt => "Int8",
+            FieldType::Float => "Float8",
+            FieldType::Boolean => "Bool",
+            FieldType::Timestamp => "Timestamptz",
+        }
+    }
+
+    /// The type Diesel's `table!` macro uses for this column.
+    pub fn diesel_type(&self) -> &'static str {
+        match self {
+            FieldType::Text => "Varchar",
+            FieldType::Integer => "Int4",
+            FieldType::BigInt => "Int8",
+            FieldType::Float => "Float8",
+            FieldType::Boolean => "Bool",
+            FieldType::Timestamp => "Timestamptz",
+        }
+    }
+
+    pub fn rust_type(&self) -> TokenStream {
+        match self {
+            FieldType::Text => quote! {String},
+            FieldType::Integer => quote! {i32},
+            FieldType::BigInt => quote! {i64},
+            FieldType::Float => quote! {f64},
+            FieldType::Boolean => quote! {bool},
+            FieldType::Timestamp => quote! {DateTime<Utc>},
+        }
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone)]
+pub struct Field {
+    pub name: String,
+    pub ty: FieldType,
+    pub nullable: bool,
+    pub indexed: bool,
+    pub unique: bool,
+    /// The previous name of this field, so that migrations can rename the column instead of replacing it.
+    pub renamed_from: Option<String>,
+}
+
+impl Field {
+    pub fn new(name: &str, ty: FieldType, nullable: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            ty,
+            nullable,
+            indexed: false,
+            unique: false,
+            renamed_from: None,
+        }
+    }
+
+    /// The name and definition of the index for this field, if it has one.
+    pub fn index_definition(&self, table: &str) -> Option<(String, String)> {
+        let name = &self.name;
+
+        if self.unique {
+            let index = format!("{table}_{name}_key");
+            Some((
+                index.clone(),
+                format!("CREATE UNIQUE INDEX {index} ON {table} ({name})"),
+            ))
+        } else if self.indexed {
+            let index = format!("{table}_{name}_idx");
+            Some((
+                index.clone(),
+                format!("CREATE INDEX {index} ON {table} ({name})"),
+            ))
+        } else {
+            None
+        }
+    }
+
+    pub fn rust_type(&self) -> TokenStream {
+        let ty = self.ty.rust_type();
+
+        if self.nullable {
+            quote! {Option<#ty>}
+        } else {
+            ty
+        }
+    }
+
+    pub fn diesel_type(&self) -> String {
+        let ty = self.ty.diesel_type();
+
+        if self.nullable {
+            format!("Nullable<{ty}>")
+        } else {
+            ty.to_string()
+        }
+    }
+
+    pub fn column_definition(&self) -> String {
+        let not_null = if self.nullable { "" } else { " NOT NULL" };
+
+        format!("{} {}{not_null}", self.name, self.ty.sql_type())
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nullable = if self.nullable { "?" } else { "" };
+        let index = if self.unique {
+            " (unique)"
+        } else if self.indexed {
+            " (indexed)"
+        } else {
+            ""
+        };
+
+        write!(f, "{}: {}{nullable}{index}", self.name, self.ty)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RelationKind {
+    BelongsTo,
+    HasMany,
+    ManyToMany,
+}
+
+impl RelationKind {
+    pub const ALL: [RelationKind; 3] = [
+        RelationKind::BelongsTo,
+        RelationKind::HasMany,
+        RelationKind::ManyToMany,
+    ];
+
+    /// The name used for this kind of relation in manifests and on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RelationKind::BelongsTo => "belongs_to",
+            RelationKind::HasMany => "has_many",
+            RelationKind::ManyToMany => "many_to_many",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        RelationKind::ALL.into_iter().find(|x| x.name() == name)
+    }
+}
+
+impl fmt::Display for RelationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OnDelete {
+    Cascade,
+    SetNull,
+    Restrict,
+    NoAction,
+}
+
+impl OnDelete {
+    pub const ALL: [OnDelete; 4] = [
+        OnDelete::Cascade,
+        OnDelete::SetNull,
+        OnDelete::Restrict,
+        OnDelete::NoAction,
+    ];
+
+    /// The name used for this behaviour in manifests and on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OnDelete::Cascade => "cascade",
+            OnDelete::SetNull => "set_null",
+            OnDelete::Restrict => "restrict",
+            OnDelete::NoAction => "no_action",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        OnDelete::ALL.into_iter().find(|x| x.name() == name)
+    }
+
+    pub fn sql(&self) -> &'static str {
+        match self {
+            OnDelete::Cascade => "CASCADE",
+            OnDelete::SetNull => "SET NULL",
+            OnDelete::Restrict => "RESTRICT",
+            OnDelete::NoAction => "NO ACTION",
+        }
+    }
+}
+
+impl fmt::Display for OnDelete {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone)]
+pub struct Relation {
+    pub kind: RelationKind,
+    pub target: String,
+    pub on_delete: OnDelete,
+}
+
+impl Relation {
+    /// Parses a declaration like `comment belongs_to post on_delete=set_null`,
+    /// returning the name of the resource that owns the relation alongside it.
+    pub fn parse(declaration: &str) -> Result<(String, Self), String> {
+        let parts: Vec<&str> = declaration.split_whitespace().collect();
+
+        let (owner, kind, target, on_delete) = match parts[..] {
+            [owner, kind, target] => (owner, kind, target, None),
+            [owner, kind, target, on_delete] => (owner, kind, target, Some(on_delete)),
+            _ => {
+                return Err(format!(
+                    "Couldn't understand the relation `{declaration}`, expected something like `comment belongs_to post`"
+                ))
+            }
+        };
+
+        let Some(kind) = RelationKind::from_name(kind) else {
+            return Err(format!("Unknown relation kind in `{declaration}`: {kind}"));
+        };
+
+        let on_delete = match on_delete {
+            Some(on_delete) => {
+                let Some(action) = on_delete
+                    .strip_prefix("on_delete=")
+                    .and_then(OnDelete::from_name)
+                else {
+                    return Err(format!(
+                        "Unknown ON DELETE behaviour in `{declaration}`: {on_delete}"
+                    ));
+                };
+                action
+            }
+            None => OnDelete::Cascade,
+        };
+
+        let relation = Relation {
+            kind,
+            target: target.to_string(),
+            on_delete,
+        };
+
+        Ok((owner.to_string(), relation))
+    }
+}
+
+impl fmt::Display for Relation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} (on delete: {})",
+            self.kind, self.target, self.on_delete
+        )
+    }
+}
+
+/// A foreign key column on `table` pointing at `references`.
+#[derive(Clone)]
+pub struct ForeignKey {
+    pub table: String,
+    pub references: String,
+    pub on_delete: OnDelete,
+}
+
+impl ForeignKey {
+    pub fn column(&self) -> String {
+        format!("{}_id", self.references)
+    }
+
+    pub fn field(&self) -> Field {
+        Field::new(
+            &self.column(),
+            FieldType::Integer,
+            self.on_delete == OnDelete::SetNull,
+        )
+    }
+
+    /// The name Postgres gives this foreign key's constraint by default.
+    pub fn constraint(&self) -> String {
+        format!("{}_{}_fkey", self.table, self.column())
+    }
+
+    pub fn references_definition(&self) -> String {
+        format!(
+            "REFERENCES {}(id) ON DELETE {}",
+            self.references,
+            self.on_delete.sql()
+        )
+    }
+
+    pub fn column_definition(&self) -> String {
+        format!(
+            "{} {}",
+            self.field().column_definition(),
+            self.references_definition()
+        )
+    }
+}
+
+/// A join table linking `left` and `right` for a many-to-many relation.
+#[derive(Clone)]
+pub struct JoinTable {
+    pub left: String,
+    pub right: String,
+    pub on_delete: OnDelete,
+}
+
+impl JoinTable {
+    pub fn name(&self) -> String {
+        format!("{}_{}", self.left, self.right)
+    }
+}
+
+pub fn foreign_keys(routes: &[Route]) -> Vec<ForeignKey> {
+    let mut keys = Vec::new();
+
+    for route in routes {
+        for relation in &route.relations {
+            let (table, references) = match relation.kind {
+                RelationKind::BelongsTo => (route.name.clone(), relation.target.clone()),
+                RelationKind::HasMany => (relation.target.clone(), route.name.clone()),
+                RelationKind::ManyToMany => continue,
+            };
+
+            keys.push(ForeignKey {
+                table,
+                references,
+                on_delete: relation.on_delete,
+            });
+        }
+    }
+
+    keys
+}
+
+/// The fields of a resource's table, including the foreign keys added by its relations.
+pub fn table_fields(route: &Route, routes: &[Route]) -> Vec<Field> {
+    let mut fields = route.fields.clone();
+
+    for key in foreign_keys(routes) {
+        if key.table == route.name {
+            fields.push(key.field());
+        }
+    }
+
+    fields
+}
+
+pub fn join_tables(routes: &[Route]) -> Vec<JoinTable> {
+    let mut tables = Vec::new();
+
+    for route in routes {
+        for relation in &route.relations {
+            if relation.kind == RelationKind::ManyToMany {
+                tables.push(JoinTable {
+                    left: route.name.clone(),
+                    right: relation.target.clone(),
+                    on_delete: relation.on_delete,
+                });
+            }
+        }
+    }
+
+    tables
+}
+
+/// Orders resources so that every table is created after the tables its foreign keys point at.
+pub fn creation_order(routes: &[Route]) -> Result<Vec<Route>, String> {
+    let keys = foreign_keys(routes);
+    let mut ordered: Vec<Route> = Vec::new();
+    let mut remaining: Vec<Route> = routes.to_vec();
+
+    while !remaining.is_empty() {
+        let Some(idx) = remaining.iter().position(|route| {
+            keys.iter()
+                .filter(|key| key.table == route.name && key.references != route.name)
+                .all(|key| ordered.iter().any(|x| x.name == key.references))
+        }) else {
+            let names: Vec<&str> = remaining.iter().map(|x| x.name.as_str()).collect();
+            return Err(format!(
+                "There's a cycle in the relations between {}",
+                names.join(", ")
+            ));
+        };
+
+        ordered.push(remaining.remove(idx));
+    }
+
+    Ok(ordered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(name: &str, relations: &[&str]) -> Route {
+        Route {
+            name: name.to_string(),
+            auth_required: false,
+            fields: Vec::new(),
+            relations: relations
+                .iter()
+                .map(|x| Relation::parse(&format!("{name} {x}")).unwrap().1)
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn creates_referenced_tables_first() {
+        let routes = [
+            route("comment", &["belongs_to post"]),
+            route("post", &["belongs_to author"]),
+            route("author", &[]),
+        ];
+
+        let order: Vec<String> = creation_order(&routes)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.name)
+            .collect();
+        assert_eq!(order, ["author", "post", "comment"]);
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let routes = [
+            route("post", &["belongs_to comment"]),
+            route("comment", &["belongs_to post"]),
+            route("tag", &[]),
+        ];
+
+        let Err(e) = creation_order(&routes) else {
+            panic!("a cycle should be rejected");
+        };
+        assert_eq!(e, "There's a cycle in the relations between post, comment");
+    }
+}
@@ -0,0 +1,71 @@
+use crate::codegen::secrets::{COOKIE_KEY_SECRET, DEV_SECRETS_FILE, SECRETS_FILE};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::fs;
+use std::path::Path;
+use toml_edit::{value, Document};
+
+/// How many random bytes make up a cookie key. axum-extra's `Key` needs at least 64.
+pub const COOKIE_KEY_BYTES: usize = 64;
+
+/// A fresh cookie key, encoded as base64.
+pub fn generate_key() -> Result<String, String> {
+    let mut bytes = [0u8; COOKIE_KEY_BYTES];
+    getrandom::getrandom(&mut bytes).map_err(|e| format!("Couldn't generate a key: {e}"))?;
+
+    Ok(STANDARD.encode(bytes))
+}
+
+/// Writes a fresh cookie key into `Secrets.toml`, and into `Secrets.dev.toml` if there is one.
+/// Each file gets a key of its own, so sessions from a local run can't be used in production.
+pub fn gen_key(project_path: &Path, print: bool, force: bool) -> Result<(), String> {
+    if print {
+        println!("{}", generate_key()?);
+        return Ok(());
+    }
+
+    for filename in [SECRETS_FILE, DEV_SECRETS_FILE] {
+        let path = project_path.join(filename);
+
+        // Only the main file is created if it's missing
+        if filename == DEV_SECRETS_FILE && !path.exists() {
+            continue;
+        }
+
+        write_cookie_key(&path, force)?;
+        println!("Wrote a new {COOKIE_KEY_SECRET} to {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Sets `COOKIE_KEY` in a secrets file, keeping everything else in it as it was. A key that's
+/// already set is only replaced when `force` is set, since doing so logs everyone out.
+pub fn write_cookie_key(path: &Path, force: bool) -> Result<(), String> {
+    let filename = path.display();
+
+    let contents = if path.exists() {
+        fs::read_to_string(path).map_err(|e| format!("Couldn't read {filename}: {e}"))?
+    } else {
+        String::new()
+    };
+
+    let mut doc = contents
+        .parse::<Document>()
+        .map_err(|e| format!("Couldn't parse {filename}: {e}"))?;
+
+    let already_set = doc
+        .get(COOKIE_KEY_SECRET)
+        .and_then(|x| x.as_str())
+        .is_some_and(|x| !x.is_empty());
+
+    if already_set && !force {
+        return Err(format!(
+            "{COOKIE_KEY_SECRET} is already set in {filename}. Replacing it logs everyone out, so re-run with --force if that's what you want."
+        ));
+    }
+
+    doc[COOKIE_KEY_SECRET] = value(generate_key()?);
+
+    fs::write(path, doc.to_string()).map_err(|e| format!("Couldn't write {filename}: {e}"))
+}
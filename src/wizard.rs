@@ -0,0 +1,496 @@
+use crate::cli::{
+    validate_field_name, validate_resource_name, AuthMode, Config, Deployment, HashParams, Layout,
+    Orm, PasswordHash, PaymentProvider, RateLimitStore, Route, DEFAULT_HEALTH_PATH,
+    DEFAULT_READY_PATH,
+};
+use crate::manifest::{write_manifest, DATABASES, FRAMEWORKS};
+use crate::schema::{Field, FieldType, OnDelete, Relation, RelationKind};
+use inquire::validator::Validation;
+use inquire::{Confirm, CustomUserError, InquireError, MultiSelect, Select, Text};
+use std::path::PathBuf;
+
+const EXTRA_SECRETS: &str = "Secrets.toml file";
+const EXTRA_EMAIL_VERIFICATION: &str = "Email verification on registration";
+const EXTRA_PASSWORD_RESET: &str = "Password reset emails";
+const EXTRA_RATE_LIMIT: &str = "Login rate limiting and lockout";
+const EXTRA_CSRF: &str = "CSRF tokens for protected routes";
+const EXTRA_PAYMENTS: &str = "Stripe payments";
+const EXTRA_MULTI_TENANT: &str = "Multi-tenancy with organizations";
+const EXTRA_EMBED_MIGRATIONS: &str = "Run migrations on startup";
+const EXTRA_CHECKED_QUERIES: &str = "Compile-time checked queries";
+const EXTRA_REPOSITORIES: &str = "Repository layer";
+const EXTRA_TRACING: &str = "Structured logging and tracing";
+const EXTRA_METRICS: &str = "Prometheus metrics";
+const EXTRA_HEALTH: &str = "Health and readiness endpoints";
+
+const ADD_FIELD: &str = "Add a field";
+const REMOVE_FIELD: &str = "Remove a field";
+const MOVE_FIELD_UP: &str = "Move a field up";
+const MOVE_FIELD_DOWN: &str = "Move a field down";
+const DONE: &str = "Done";
+
+const INDEX_CHOICES: [&str; 3] = ["No", "Yes", "Yes, and it should be unique"];
+
+/// Walks the user through every option. Returns `None` if they decide not to generate the project.
+pub fn run_wizard() -> Result<Option<(String, Config)>, String> {
+    let project_name = Text::new("Hey there! What would you like to name your project? > ")
+        .prompt()
+        .map_err(cancelled)?;
+
+    Select::new("Which framework do you want to use?", FRAMEWORKS.to_vec())
+        .prompt()
+        .map_err(cancelled)?;
+
+    Select::new("Which database do you want to use?", DATABASES.to_vec())
+        .prompt()
+        .map_err(cancelled)?;
+
+    let orm = Select::new("How should the database be accessed?", Orm::ALL.to_vec())
+        .prompt()
+        .map_err(cancelled)?;
+
+    let deployment = Select::new("Where will you be deploying to?", Deployment::ALL.to_vec())
+        .prompt()
+        .map_err(cancelled)?;
+
+    let mut routes = Vec::new();
+
+    while Confirm::new("Do you want to add a resource?")
+        .with_default(routes.is_empty())
+        .prompt()
+        .map_err(cancelled)?
+    {
+        let name = Text::new("What's the name of the resource? > ")
+            .with_validator(|x: &str| ask_again(validate_resource_name(x)))
+            .prompt()
+            .map_err(cancelled)?;
+
+        let fields = edit_fields(&name)?;
+
+        routes.push(Route {
+            name,
+            auth_required: false,
+            fields,
+            relations: Vec::new(),
+        });
+    }
+
+    let names: Vec<String> = routes.iter().map(|x| x.name.clone()).collect();
+
+    for route in routes.iter_mut() {
+        while Confirm::new(&format!(
+            "Do you want to relate `{}` to another resource?",
+            route.name
+        ))
+        .with_default(false)
+        .prompt()
+        .map_err(cancelled)?
+        {
+            route.relations.push(pick_relation(&route.name, &names)?);
+        }
+    }
+
+    let auth_mode = if Confirm::new("Do you want auth?")
+        .with_default(false)
+        .prompt()
+        .map_err(cancelled)?
+    {
+        Some(
+            Select::new(
+                "Should callers log in with cookie sessions, or use API keys?",
+                AuthMode::ALL.to_vec(),
+            )
+            .prompt()
+            .map_err(cancelled)?,
+        )
+    } else {
+        None
+    };
+    let auth = auth_mode == Some(AuthMode::Cookie);
+    let api_keys = auth_mode == Some(AuthMode::ApiKey);
+
+    let password_hash = if auth {
+        Select::new(
+            "How should passwords be hashed?",
+            PasswordHash::ALL.to_vec(),
+        )
+        .prompt()
+        .map_err(cancelled)?
+    } else {
+        PasswordHash::default()
+    };
+
+    if auth_mode.is_some() && !routes.is_empty() {
+        let names: Vec<String> = routes.iter().map(|x| x.name.clone()).collect();
+
+        let protected = MultiSelect::new("Which resources should be protected?", names)
+            .prompt()
+            .map_err(cancelled)?;
+
+        for route in routes.iter_mut() {
+            route.auth_required = protected.contains(&route.name);
+        }
+    }
+
+    let mut extras = Vec::new();
+
+    if deployment == Deployment::Shuttle {
+        extras.push(EXTRA_SECRETS);
+    }
+
+    if auth {
+        extras.push(EXTRA_EMAIL_VERIFICATION);
+        extras.push(EXTRA_PASSWORD_RESET);
+        extras.push(EXTRA_RATE_LIMIT);
+        extras.push(EXTRA_CSRF);
+        extras.push(EXTRA_PAYMENTS);
+    }
+
+    if auth || !routes.is_empty() {
+        extras.push(EXTRA_EMBED_MIGRATIONS);
+    }
+
+    if !routes.is_empty() && orm == Orm::Sqlx {
+        extras.push(EXTRA_CHECKED_QUERIES);
+        extras.push(EXTRA_REPOSITORIES);
+    }
+
+    if auth && !routes.is_empty() && orm == Orm::Sqlx {
+        extras.push(EXTRA_MULTI_TENANT);
+    }
+
+    extras.push(EXTRA_TRACING);
+    extras.push(EXTRA_METRICS);
+    extras.push(EXTRA_HEALTH);
+
+    let extras = MultiSelect::new("Any extras?", extras)
+        .prompt()
+        .map_err(cancelled)?;
+
+    // Every row needs a session to find its organization
+    if extras.contains(&EXTRA_MULTI_TENANT) {
+        for route in routes.iter_mut() {
+            route.auth_required = true;
+        }
+    }
+
+    // The workspace layout is built around repositories
+    let layout = if extras.contains(&EXTRA_REPOSITORIES) {
+        Select::new("How should the project be laid out?", Layout::ALL.to_vec())
+            .prompt()
+            .map_err(cancelled)?
+    } else {
+        Layout::Single
+    };
+
+    let (health_path, ready_path) = if extras.contains(&EXTRA_HEALTH) {
+        let health_path = Text::new("Where should the liveness endpoint be served? > ")
+            .with_default(DEFAULT_HEALTH_PATH)
+            .prompt()
+            .map_err(cancelled)?;
+        let ready_path = Text::new("Where should the readiness endpoint be served? > ")
+            .with_default(DEFAULT_READY_PATH)
+            .prompt()
+            .map_err(cancelled)?;
+
+        (health_path, ready_path)
+    } else {
+        (
+            DEFAULT_HEALTH_PATH.to_string(),
+            DEFAULT_READY_PATH.to_string(),
+        )
+    };
+
+    let rate_limit_store = if extras.contains(&EXTRA_RATE_LIMIT) {
+        Select::new(
+            "Where should rate limits be counted?",
+            RateLimitStore::ALL.to_vec(),
+        )
+        .prompt()
+        .map_err(cancelled)?
+    } else {
+        RateLimitStore::default()
+    };
+
+    // Shuttle apps read their cookie key from Secrets.toml
+    let secrets = extras.contains(&EXTRA_SECRETS) || (auth && deployment == Deployment::Shuttle);
+    let mut secret_keys = Vec::new();
+
+    if secrets {
+        while Confirm::new("Do you want to declare a secret?")
+            .with_default(secret_keys.is_empty())
+            .prompt()
+            .map_err(cancelled)?
+        {
+            let name = Text::new("What's the name of the secret? > ")
+                .prompt()
+                .map_err(cancelled)?;
+
+            let types: Vec<FieldType> = FieldType::ALL
+                .into_iter()
+                .filter(|x| *x != FieldType::Timestamp)
+                .collect();
+            let ty = Select::new("What type is it?", types)
+                .prompt()
+                .map_err(cancelled)?;
+
+            let optional = Confirm::new("Is it optional?")
+                .with_default(false)
+                .prompt()
+                .map_err(cancelled)?;
+
+            secret_keys.push(Field::new(&name, ty, optional));
+        }
+    }
+
+    let cfg = Config {
+        crud: !routes.is_empty(),
+        auth,
+        api_keys,
+        email_verification: extras.contains(&EXTRA_EMAIL_VERIFICATION),
+        password_reset: extras.contains(&EXTRA_PASSWORD_RESET),
+        rate_limit: extras.contains(&EXTRA_RATE_LIMIT),
+        rate_limit_store,
+        csrf: extras.contains(&EXTRA_CSRF),
+        payments: extras
+            .contains(&EXTRA_PAYMENTS)
+            .then_some(PaymentProvider::Stripe),
+        multi_tenant: extras.contains(&EXTRA_MULTI_TENANT),
+        password_hash,
+        // The parameters can be tuned in the manifest
+        hash_params: HashParams::default(),
+        routes,
+        secrets,
+        secret_keys,
+        deployment,
+        embed_migrations: extras.contains(&EXTRA_EMBED_MIGRATIONS),
+        checked_queries: extras.contains(&EXTRA_CHECKED_QUERIES),
+        orm,
+        repositories: extras.contains(&EXTRA_REPOSITORIES),
+        layout,
+        tracing: extras.contains(&EXTRA_TRACING),
+        metrics: extras.contains(&EXTRA_METRICS),
+        health: extras.contains(&EXTRA_HEALTH),
+        health_path,
+        ready_path,
+    };
+
+    cfg.validate()?;
+
+    print_summary(&project_name, &cfg);
+
+    if Confirm::new("Do you want to save these answers as a manifest?")
+        .with_default(true)
+        .prompt()
+        .map_err(cancelled)?
+    {
+        let path = Text::new("Where should the manifest be saved? > ")
+            .with_default("inlet.toml")
+            .prompt()
+            .map_err(cancelled)?;
+
+        write_manifest(&PathBuf::from(path), &project_name, &cfg)?;
+    }
+
+    let generate = Confirm::new("Generate the project now?")
+        .with_default(true)
+        .prompt()
+        .map_err(cancelled)?;
+
+    if generate {
+        Ok(Some((project_name, cfg)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn edit_fields(resource: &str) -> Result<Vec<Field>, String> {
+    let mut fields: Vec<Field> = Vec::new();
+
+    loop {
+        if fields.is_empty() {
+            println!("`{resource}` has no fields yet.");
+        } else {
+            println!("`{resource}` fields:");
+            for field in &fields {
+                println!("  {field}");
+            }
+        }
+
+        let mut actions = vec![ADD_FIELD];
+        if !fields.is_empty() {
+            actions.push(REMOVE_FIELD);
+        }
+        if fields.len() > 1 {
+            actions.push(MOVE_FIELD_UP);
+            actions.push(MOVE_FIELD_DOWN);
+        }
+        actions.push(DONE);
+
+        let action = Select::new("What would you like to do?", actions)
+            .prompt()
+            .map_err(cancelled)?;
+
+        match action {
+            ADD_FIELD => {
+                let name = Text::new("What's the name of the field? > ")
+                    .with_validator(|x: &str| ask_again(validate_field_name(x)))
+                    .prompt()
+                    .map_err(cancelled)?;
+
+                let ty = Select::new("What type is it?", FieldType::ALL.to_vec())
+                    .prompt()
+                    .map_err(cancelled)?;
+
+                let nullable = Confirm::new("Can it be null?")
+                    .with_default(false)
+                    .prompt()
+                    .map_err(cancelled)?;
+
+                let index = Select::new("Should it be indexed?", INDEX_CHOICES.to_vec())
+                    .prompt()
+                    .map_err(cancelled)?;
+
+                let mut field = Field::new(&name, ty, nullable);
+                field.indexed = index != INDEX_CHOICES[0];
+                field.unique = index == INDEX_CHOICES[2];
+
+                fields.push(field);
+            }
+            REMOVE_FIELD => {
+                let idx = pick_field("Which field should be removed?", &fields)?;
+                fields.remove(idx);
+            }
+            MOVE_FIELD_UP => {
+                let idx = pick_field("Which field should be moved up?", &fields)?;
+                if idx > 0 {
+                    fields.swap(idx, idx - 1);
+                }
+            }
+            MOVE_FIELD_DOWN => {
+                let idx = pick_field("Which field should be moved down?", &fields)?;
+                if idx + 1 < fields.len() {
+                    fields.swap(idx, idx + 1);
+                }
+            }
+            _ => return Ok(fields),
+        }
+    }
+}
+
+fn pick_relation(resource: &str, names: &[String]) -> Result<Relation, String> {
+    let kind = Select::new(
+        &format!("How is `{resource}` related?"),
+        RelationKind::ALL.to_vec(),
+    )
+    .prompt()
+    .map_err(cancelled)?;
+
+    let target = Select::new(&format!("`{resource}` {kind}..."), names.to_vec())
+        .prompt()
+        .map_err(cancelled)?;
+
+    let on_delete = Select::new("What should happen on delete?", OnDelete::ALL.to_vec())
+        .prompt()
+        .map_err(cancelled)?;
+
+    Ok(Relation {
+        kind,
+        target,
+        on_delete,
+    })
+}
+
+fn pick_field(message: &str, fields: &[Field]) -> Result<usize, String> {
+    let names: Vec<String> = fields.iter().map(|x| x.to_string()).collect();
+
+    Select::new(message, names)
+        .raw_prompt()
+        .map(|x| x.index)
+        .map_err(cancelled)
+}
+
+fn print_summary(project_name: &str, cfg: &Config) {
+    println!("\nHere's what will be generated for `{project_name}`:");
+    println!("  Framework: {}", FRAMEWORKS[0]);
+    println!("  Database: {}", DATABASES[0]);
+    println!("  ORM: {}", cfg.orm);
+    println!("  Deployment: {}", cfg.deployment);
+    if cfg.api_keys {
+        println!("  Auth: {}", AuthMode::ApiKey);
+    } else if cfg.auth {
+        println!("  Auth: {}", AuthMode::Cookie);
+    } else {
+        println!("  Auth: false");
+    }
+
+    if cfg.auth {
+        println!("  Password hashing: {}", cfg.password_hash);
+        println!("  Email verification: {}", cfg.email_verification);
+        println!("  Password reset: {}", cfg.password_reset);
+
+        if cfg.rate_limit {
+            println!("  Rate limiting: {}", cfg.rate_limit_store);
+        } else {
+            println!("  Rate limiting: false");
+        }
+
+        println!("  CSRF tokens: {}", cfg.csrf);
+
+        match cfg.payments {
+            Some(provider) => println!("  Payments: {provider}"),
+            None => println!("  Payments: false"),
+        }
+
+        println!("  Multi-tenancy: {}", cfg.multi_tenant);
+    }
+
+    println!("  Secrets: {}", cfg.secrets);
+
+    for secret in &cfg.secret_keys {
+        println!("    {secret}");
+    }
+
+    println!("  Run migrations on startup: {}", cfg.embed_migrations);
+    println!("  Compile-time checked queries: {}", cfg.checked_queries);
+    println!("  Repository layer: {}", cfg.repositories);
+    println!("  Layout: {}", cfg.layout);
+    println!("  Tracing: {}", cfg.tracing);
+    println!("  Prometheus metrics: {}", cfg.metrics);
+
+    if cfg.health {
+        println!("  Liveness endpoint: {}", cfg.health_path);
+        println!("  Readiness endpoint: {}", cfg.ready_path);
+    }
+
+    for route in &cfg.routes {
+        let protected = if route.auth_required {
+            " (protected)"
+        } else {
+            ""
+        };
+        println!("  Resource `{}`{protected}", route.name);
+
+        for field in &route.fields {
+            println!("    {field}");
+        }
+
+        for relation in &route.relations {
+            println!("    {relation}");
+        }
+    }
+
+    println!();
+}
+
+/// Has a prompt ask again for a name that `Config::validate` would turn down.
+fn ask_again(result: Result<(), String>) -> Result<Validation, CustomUserError> {
+    Ok(match result {
+        Ok(()) => Validation::Valid,
+        Err(e) => Validation::Invalid(e.into()),
+    })
+}
+
+fn cancelled(e: InquireError) -> String {
+    format!("The wizard was cancelled: {e}")
+}